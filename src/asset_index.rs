@@ -0,0 +1,329 @@
+use std::{fs::File, io::{BufRead, BufReader, BufWriter, Cursor, Write}, path::Path};
+
+use crate::{byte_stream::ByteReader, vfs::Vfs, wmb::read_wmb_info, wta_wtp::read_wta_ids};
+
+
+const INDEX_MAGIC: &str = "RPU_ASSET_INDEX 1";
+
+#[derive(Default)]
+pub struct AssetIndex {
+	pub models: Vec<ModelRecord>,
+	pub texture_archives: Vec<TextureArchiveRecord>,
+	/// Files that `build` couldn't read, these aren't saved
+	pub errors: Vec<IndexError>,
+}
+
+pub struct ModelRecord {
+	pub path: String,
+	pub meshes: Vec<String>,
+	pub materials: Vec<MaterialRecord>,
+}
+
+pub struct MaterialRecord {
+	pub name: String,
+	pub shader_name: String,
+	pub texture_ids: Vec<u32>,
+}
+
+/// A WTA or WTB and the texture ids it contains
+pub struct TextureArchiveRecord {
+	pub path: String,
+	pub texture_ids: Vec<u32>,
+}
+
+pub struct IndexError {
+	pub path: String,
+	pub error: String,
+}
+
+impl ModelRecord {
+	pub fn uses_texture(&self, id: u32) -> bool {
+		self.materials.iter().any(|m| m.texture_ids.contains(&id))
+	}
+
+	pub fn uses_shader(&self, shader_name: &str) -> bool {
+		let shader_name = shader_name.to_lowercase();
+		self.materials.iter().any(|m| m.shader_name.to_lowercase().contains(&shader_name))
+	}
+}
+
+impl AssetIndex {
+	/// Indexes every WMB, WTA and WTB below `root`, including those inside of archives.
+	/// Files that can't be read are skipped and listed in `errors`.
+	pub fn build(vfs: &Vfs, root: &str) -> Self {
+		let mut index = AssetIndex::default();
+		let filter = |path: &str| {
+			let path = path.to_lowercase();
+			path.ends_with(".wmb") || path.ends_with(".wta") || path.ends_with(".wtb")
+		};
+		vfs.walk(root, &filter, &mut |path, bytes| {
+			let result = bytes.and_then(|bytes| index.add_file(path, bytes));
+			if let Err(error) = result {
+				index.errors.push(IndexError { path: path.to_string(), error });
+			}
+		});
+		index
+	}
+
+	fn add_file(&mut self, path: &str, bytes: Vec<u8>) -> Result<(), String> {
+		if path.to_lowercase().ends_with(".wmb") {
+			let info = read_wmb_info(&mut ByteReader::new(Cursor::new(bytes)))?;
+			self.models.push(ModelRecord {
+				path: path.to_string(),
+				meshes: info.meshes,
+				materials: info.materials.into_iter().map(|m| MaterialRecord {
+					name: m.name,
+					shader_name: m.shader_name,
+					texture_ids: m.texture_ids,
+				}).collect(),
+			});
+		} else {
+			self.texture_archives.push(TextureArchiveRecord {
+				path: path.to_string(),
+				texture_ids: read_wta_ids(Cursor::new(bytes))?,
+			});
+		}
+		Ok(())
+	}
+
+	pub fn models_using_texture(&self, id: u32) -> Vec<&ModelRecord> {
+		self.models.iter().filter(|m| m.uses_texture(id)).collect()
+	}
+
+	pub fn models_using_shader(&self, shader_name: &str) -> Vec<&ModelRecord> {
+		self.models.iter().filter(|m| m.uses_shader(shader_name)).collect()
+	}
+
+	pub fn texture_archives_containing(&self, id: u32) -> Vec<&TextureArchiveRecord> {
+		self.texture_archives.iter().filter(|t| t.texture_ids.contains(&id)).collect()
+	}
+
+	/// Models whose file name matches `name`, e.g. `pl0000.wmb` or `pl0000`
+	pub fn find_models(&self, name: &str) -> Vec<&ModelRecord> {
+		let name = name.to_lowercase();
+		self.models.iter()
+			.filter(|m| {
+				let file_name = m.path.rsplit('/').next().unwrap_or("").to_lowercase();
+				file_name == name || file_name.strip_suffix(".wmb") == Some(name.as_str())
+			})
+			.collect()
+	}
+
+	pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+		let file = File::create(path).map_err(|e| e.to_string())?;
+		self.write(BufWriter::new(file))
+	}
+
+	pub fn write<W: Write>(&self, mut writer: W) -> Result<(), String> {
+		let mut write_line = |line: String| writeln!(writer, "{}", line).map_err(|e| e.to_string());
+		write_line(INDEX_MAGIC.to_string())?;
+		for model in &self.models {
+			write_line(format!("M\t{}", escape(&model.path)))?;
+			for mesh in &model.meshes {
+				write_line(format!("S\t{}", escape(mesh)))?;
+			}
+			for material in &model.materials {
+				write_line(format!("T\t{}\t{}\t{}", escape(&material.name), escape(&material.shader_name), format_ids(&material.texture_ids)))?;
+			}
+		}
+		for texture_archive in &self.texture_archives {
+			write_line(format!("W\t{}\t{}", escape(&texture_archive.path), format_ids(&texture_archive.texture_ids)))?;
+		}
+		writer.flush().map_err(|e| e.to_string())
+	}
+
+	pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+		let file = File::open(path).map_err(|e| e.to_string())?;
+		Self::read(BufReader::new(file))
+	}
+
+	pub fn read<R: BufRead>(reader: R) -> Result<Self, String> {
+		let mut lines = reader.lines();
+		let magic = lines.next().ok_or("Empty asset index")?.map_err(|e| e.to_string())?;
+		if magic != INDEX_MAGIC {
+			return Err(format!("Unsupported asset index: {}", magic));
+		}
+
+		let mut index = AssetIndex::default();
+		for (line_i, line) in lines.enumerate() {
+			let line = line.map_err(|e| e.to_string())?;
+			let fields = line.split('\t').collect::<Vec<_>>();
+			let invalid_line = || format!("Invalid asset index line {}", line_i + 2);
+			match fields.as_slice() {
+				["M", path] => index.models.push(ModelRecord {
+					path: unescape(path),
+					meshes: Vec::new(),
+					materials: Vec::new(),
+				}),
+				["S", name] => index.models.last_mut()
+					.ok_or_else(invalid_line)?
+					.meshes.push(unescape(name)),
+				["T", name, shader_name, ids] => index.models.last_mut()
+					.ok_or_else(invalid_line)?
+					.materials.push(MaterialRecord {
+						name: unescape(name),
+						shader_name: unescape(shader_name),
+						texture_ids: parse_ids(ids).ok_or_else(invalid_line)?,
+					}),
+				["W", path, ids] => index.texture_archives.push(TextureArchiveRecord {
+					path: unescape(path),
+					texture_ids: parse_ids(ids).ok_or_else(invalid_line)?,
+				}),
+				[""] => {},
+				_ => return Err(invalid_line()),
+			}
+		}
+		Ok(index)
+	}
+}
+
+fn escape(value: &str) -> String {
+	value.replace('\\', "\\\\").replace('\t', "\\t").replace('\n', "\\n")
+}
+
+fn unescape(value: &str) -> String {
+	let mut result = String::with_capacity(value.len());
+	let mut chars = value.chars();
+	while let Some(c) = chars.next() {
+		if c != '\\' {
+			result.push(c);
+			continue;
+		}
+		match chars.next() {
+			Some('t') => result.push('\t'),
+			Some('n') => result.push('\n'),
+			Some(c) => result.push(c),
+			None => {},
+		}
+	}
+	result
+}
+
+fn format_ids(ids: &[u32]) -> String {
+	ids.iter().map(|id| format!("{:08x}", id)).collect::<Vec<_>>().join(",")
+}
+
+fn parse_ids(ids: &str) -> Option<Vec<u32>> {
+	if ids.is_empty() {
+		return Some(Vec::new());
+	}
+	ids.split(',').map(|id| u32::from_str_radix(id, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dat::{write_dat, DatWriteEntry};
+
+	fn test_index() -> AssetIndex {
+		let mut index = AssetIndex::default();
+		index.models.push(ModelRecord {
+			path: "data/pl/pl0000.dat/pl0000.wmb".to_string(),
+			meshes: vec!["body".to_string(), "hair\tback".to_string()],
+			materials: vec![
+				MaterialRecord {
+					name: "mat\tbody".to_string(),
+					shader_name: "SKN03_XXXXX".to_string(),
+					texture_ids: vec![0x1a2b3c4d, 5],
+				},
+				MaterialRecord {
+					name: "hair".to_string(),
+					shader_name: "CLT00_XXXXX".to_string(),
+					texture_ids: Vec::new(),
+				},
+			],
+		});
+		index.models.push(ModelRecord {
+			path: "data/em/em1000.dat/em1000.wmb".to_string(),
+			meshes: Vec::new(),
+			materials: Vec::new(),
+		});
+		index.texture_archives.push(TextureArchiveRecord {
+			path: "data/pl/pl0000.dtt/pl0000.wta".to_string(),
+			texture_ids: vec![5, 6],
+		});
+		index
+	}
+
+	fn test_wta(ids: &[u32]) -> Vec<u8> {
+		let count = ids.len() as u32;
+		let mut wta = b"WTB\0".to_vec();
+		wta.extend(1u32.to_le_bytes());
+		wta.extend(count.to_le_bytes());
+		// offsets, sizes, flags and ids
+		for table in 0..4 {
+			wta.extend((0x20 + table * count * 4).to_le_bytes());
+		}
+		wta.resize(0x20 + 3 * ids.len() * 4, 0);
+		for id in ids {
+			wta.extend(id.to_le_bytes());
+		}
+		wta
+	}
+
+	#[test]
+	fn write_and_read() {
+		let mut bytes = Vec::new();
+		test_index().write(&mut bytes).unwrap();
+		let text = String::from_utf8(bytes.clone()).unwrap();
+		assert!(text.starts_with("RPU_ASSET_INDEX 1\n"));
+		assert!(text.contains("T\tmat\\tbody\tSKN03_XXXXX\t1a2b3c4d,00000005\n"));
+
+		let index = AssetIndex::read(bytes.as_slice()).unwrap();
+		let mut rewritten = Vec::new();
+		index.write(&mut rewritten).unwrap();
+		assert_eq!(rewritten, bytes);
+		assert_eq!(index.models.len(), 2);
+		assert_eq!(index.models[0].meshes[1], "hair\tback");
+		assert_eq!(index.models[0].materials[0].name, "mat\tbody");
+		assert!(index.models[0].materials[1].texture_ids.is_empty());
+	}
+
+	#[test]
+	fn queries() {
+		let index = test_index();
+		let paths = |models: Vec<&ModelRecord>| models.iter().map(|m| m.path.clone()).collect::<Vec<_>>();
+		assert_eq!(paths(index.models_using_texture(5)), vec!["data/pl/pl0000.dat/pl0000.wmb"]);
+		assert!(index.models_using_texture(6).is_empty());
+		assert_eq!(paths(index.models_using_shader("skn03")), vec!["data/pl/pl0000.dat/pl0000.wmb"]);
+		assert!(index.models_using_shader("wat").is_empty());
+		assert_eq!(index.texture_archives_containing(6).len(), 1);
+		assert!(index.texture_archives_containing(0x1a2b3c4d).is_empty());
+		assert_eq!(paths(index.find_models("EM1000")), vec!["data/em/em1000.dat/em1000.wmb"]);
+		assert_eq!(paths(index.find_models("em1000.wmb")), vec!["data/em/em1000.dat/em1000.wmb"]);
+		assert!(index.find_models("em1000.dat").is_empty());
+	}
+
+	#[test]
+	fn invalid_files_are_rejected() {
+		assert!(AssetIndex::read("".as_bytes()).is_err());
+		assert!(AssetIndex::read("RPU_ASSET_INDEX 2\n".as_bytes()).is_err());
+		assert_eq!(
+			AssetIndex::read("RPU_ASSET_INDEX 1\nS\tbody\n".as_bytes()).err(),
+			Some("Invalid asset index line 2".to_string()),
+		);
+		assert!(AssetIndex::read("RPU_ASSET_INDEX 1\nW\ta.wta\txyz\n".as_bytes()).is_err());
+	}
+
+	#[test]
+	fn build_collects_errors() {
+		let entries = [
+			DatWriteEntry { name: "pl0000.wta".to_string(), data: test_wta(&[5, 6]) },
+			DatWriteEntry { name: "pl0000.wmb".to_string(), data: b"WMB3".to_vec() },
+			DatWriteEntry { name: "pl0000.bxm".to_string(), data: Vec::new() },
+		];
+		let mut dat = Cursor::new(Vec::new());
+		write_dat(&mut dat, &entries).unwrap();
+		let mut vfs = Vfs::memory();
+		vfs.add_file("data/pl/pl0000.dat", dat.into_inner()).unwrap();
+		vfs.add_file("data/broken.wta", vec![0; 4]).unwrap();
+
+		let index = AssetIndex::build(&vfs, "data");
+		assert!(index.models.is_empty());
+		assert_eq!(index.texture_archives.len(), 1);
+		assert_eq!(index.texture_archives[0].path, "data/pl/pl0000.dat/pl0000.wta");
+		assert_eq!(index.texture_archives[0].texture_ids, vec![5, 6]);
+		let error_paths = index.errors.iter().map(|e| e.path.as_str()).collect::<Vec<_>>();
+		assert_eq!(error_paths, vec!["data/broken.wta", "data/pl/pl0000.dat/pl0000.wmb"]);
+	}
+}
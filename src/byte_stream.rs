@@ -1,5 +1,5 @@
-use std::io::{Read, Seek};
-use byteorder::{LittleEndian, ReadBytesExt};
+use std::io::{Read, Seek, Write};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use half::f16;
 
 pub struct ByteReader<R: Read + Seek> {
@@ -16,7 +16,7 @@ impl<R: Read + Seek> ByteReader<R> {
     }
 
     pub fn size(&mut self) -> Result<u64, String> {
-        let pos = self.reader.seek(std::io::SeekFrom::Current(0)).map_err(|e| e.to_string())?;
+        let pos = self.reader.stream_position().map_err(|e| e.to_string())?;
         let size = self.reader.seek(std::io::SeekFrom::End(0)).map_err(|e| e.to_string())?;
         self.reader.seek(std::io::SeekFrom::Start(pos)).map_err(|e| e.to_string())?;
         Ok(size)
@@ -29,7 +29,7 @@ impl<R: Read + Seek> ByteReader<R> {
     }
 
     pub fn position(&mut self) -> Result<u64, String> {
-        self.reader.seek(std::io::SeekFrom::Current(0)).map_err(|e| e.to_string())
+        self.reader.stream_position().map_err(|e| e.to_string())
     }
 
     pub fn read_u8(&mut self) -> Result<u8, String> {
@@ -96,3 +96,86 @@ impl<R: Read + Seek> ByteReader<R> {
         Ok(String::from_utf8_lossy(&buffer).to_string())
     }
 }
+
+pub struct ByteWriter<W: Write + Seek> {
+    writer: W,
+}
+
+impl<W: Write + Seek> ByteWriter<W> {
+    pub fn new(writer: W) -> Self {
+        ByteWriter { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    pub fn seek(&mut self, pos: u64) -> Result<u64, String> {
+        self.writer.seek(std::io::SeekFrom::Start(pos)).map_err(|e| e.to_string())
+    }
+
+    pub fn position(&mut self) -> Result<u64, String> {
+        self.writer.stream_position().map_err(|e| e.to_string())
+    }
+
+    pub fn seek_end(&mut self) -> Result<u64, String> {
+        self.writer.seek(std::io::SeekFrom::End(0)).map_err(|e| e.to_string())
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.writer.write_all(bytes).map_err(|e| e.to_string())
+    }
+
+    pub fn write_u8(&mut self, value: u8) -> Result<(), String> {
+        self.writer.write_u8(value).map_err(|e| e.to_string())
+    }
+
+    pub fn write_i8(&mut self, value: i8) -> Result<(), String> {
+        self.writer.write_i8(value).map_err(|e| e.to_string())
+    }
+
+    pub fn write_u16(&mut self, value: u16) -> Result<(), String> {
+        self.writer.write_u16::<LittleEndian>(value).map_err(|e| e.to_string())
+    }
+
+    pub fn write_i16(&mut self, value: i16) -> Result<(), String> {
+        self.writer.write_i16::<LittleEndian>(value).map_err(|e| e.to_string())
+    }
+
+    pub fn write_u32(&mut self, value: u32) -> Result<(), String> {
+        self.writer.write_u32::<LittleEndian>(value).map_err(|e| e.to_string())
+    }
+
+    pub fn write_i32(&mut self, value: i32) -> Result<(), String> {
+        self.writer.write_i32::<LittleEndian>(value).map_err(|e| e.to_string())
+    }
+
+    pub fn write_f32(&mut self, value: f32) -> Result<(), String> {
+        self.writer.write_f32::<LittleEndian>(value).map_err(|e| e.to_string())
+    }
+
+    pub fn write_f16(&mut self, value: f16) -> Result<(), String> {
+        self.writer.write_all(&value.to_le_bytes()).map_err(|e| e.to_string())
+    }
+
+    pub fn write_string(&mut self, value: &str, count: usize) -> Result<(), String> {
+        let mut buffer = value.as_bytes().to_vec();
+        buffer.resize(count, 0);
+        self.write(&buffer)
+    }
+
+    pub fn write_string_zero_term(&mut self, value: &str) -> Result<(), String> {
+        self.write(value.as_bytes())?;
+        self.write_u8(0)
+    }
+
+    pub fn write_zeros(&mut self, count: usize) -> Result<(), String> {
+        self.write(&vec![0; count])
+    }
+
+    pub fn align(&mut self, alignment: u64) -> Result<(), String> {
+        let pos = self.position()?;
+        let padding = (alignment - pos % alignment) % alignment;
+        self.write_zeros(padding as usize)
+    }
+}
@@ -1,18 +1,26 @@
+// every export takes pointers handed out by this module, C callers can't see an `unsafe` marker anyway
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 #[cfg(target_arch = "wasm32")]
 use wasm_bindgen::prelude::*;
 extern crate console_error_panic_hook;
 use std::{alloc::{alloc, dealloc, Layout}, ffi::{c_char, c_void, CStr}, mem, ptr, rc::Rc};
 
-use three_d::WindowedContext;
-
-use crate::{mesh_data::SceneData, mesh_renderer::{new_context, RenderState}, wmb_scr::{read_wmb_scr, read_wmb_scr_from_bytes}};
+use crate::{load_options::{Game, LoadOptions, VisibilityRule}, mesh_data::SceneData, mesh_renderer::{new_context, LodSelection, RenderContext, RenderState}, vfs::Vfs, wmb_scr::{read_wmb_scr, read_wmb_scr_from_bytes, read_wmb_scr_from_dat, read_wmb_scr_from_vfs}};
 
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
 #[no_mangle]
 pub extern "C" fn rpu_load_wmb_from_path(wmb_path: *const c_char) -> *mut SceneData {
+	rpu_load_wmb_from_path_with_options(wmb_path, ptr::null())
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_load_wmb_from_path_with_options(wmb_path: *const c_char, options: *const LoadOptions) -> *mut SceneData {
 	let wmb_path = unsafe { CStr::from_ptr(wmb_path) }.to_string_lossy().into_owned();
-	match read_wmb_scr(wmb_path) {
+	let options = unsafe { options.as_ref() }.cloned().unwrap_or_default();
+	match read_wmb_scr(wmb_path, &options) {
 		Ok(scene_data) => Box::into_raw(Box::new(scene_data)),
 		Err(e) => {
 			eprintln!("{}", e);
@@ -28,6 +36,18 @@ pub extern "C" fn rpu_load_wmb_from_bytes(
 	wmb: *const u8, wmb_size: usize,
 	wta_wtb: *const u8, wta_wtb_size: usize,
 	wtp: *const u8, wtp_size: usize,
+) -> *mut SceneData {
+	rpu_load_wmb_from_bytes_with_options(name, wmb, wmb_size, wta_wtb, wta_wtb_size, wtp, wtp_size, ptr::null())
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_load_wmb_from_bytes_with_options(
+	name: *const c_char,
+	wmb: *const u8, wmb_size: usize,
+	wta_wtb: *const u8, wta_wtb_size: usize,
+	wtp: *const u8, wtp_size: usize,
+	options: *const LoadOptions,
 ) -> *mut SceneData {
 	let name = unsafe { CStr::from_ptr(name) }.to_str().unwrap_or("");
 	let wmb = unsafe { std::slice::from_raw_parts(wmb, wmb_size) };
@@ -41,7 +61,97 @@ pub extern "C" fn rpu_load_wmb_from_bytes(
 	} else {
 		Some(unsafe { std::slice::from_raw_parts(wtp, wtp_size) } )
 	};
-	match read_wmb_scr_from_bytes(name, wmb, wta_wtb, wtp) {
+	let options = unsafe { options.as_ref() }.cloned().unwrap_or_default();
+	match read_wmb_scr_from_bytes(name, wmb, wta_wtb, wtp, &options) {
+		Ok(scene_data) => Box::into_raw(Box::new(scene_data)),
+		Err(e) => {
+			eprintln!("{}", e);
+    		std::ptr::null_mut()
+		},
+	}
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_load_wmb_from_dat(
+	dat_path: *const c_char,
+	dtt_path: *const c_char,
+	entry_name: *const c_char,
+) -> *mut SceneData {
+	rpu_load_wmb_from_dat_with_options(dat_path, dtt_path, entry_name, ptr::null())
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_load_wmb_from_dat_with_options(
+	dat_path: *const c_char,
+	dtt_path: *const c_char,
+	entry_name: *const c_char,
+	options: *const LoadOptions,
+) -> *mut SceneData {
+	let dat_path = unsafe { CStr::from_ptr(dat_path) }.to_string_lossy().into_owned();
+	let dtt_path = if dtt_path.is_null() {
+		None
+	} else {
+		Some(unsafe { CStr::from_ptr(dtt_path) }.to_string_lossy().into_owned())
+	};
+	let entry_name = unsafe { CStr::from_ptr(entry_name) }.to_string_lossy().into_owned();
+	let options = unsafe { options.as_ref() }.cloned().unwrap_or_default();
+	match read_wmb_scr_from_dat(dat_path, dtt_path, &entry_name, &options) {
+		Ok(scene_data) => Box::into_raw(Box::new(scene_data)),
+		Err(e) => {
+			eprintln!("{}", e);
+    		std::ptr::null_mut()
+		},
+	}
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_new_memory_vfs() -> *mut Vfs {
+	Box::into_raw(Box::new(Vfs::memory()))
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_drop_vfs(vfs: *mut Vfs) {
+	unsafe {
+		drop(Box::from_raw(vfs));
+	}
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_vfs_add_file(vfs: *mut Vfs, path: *const c_char, bytes: *const u8, size: usize) -> bool {
+	let vfs = unsafe { &mut *vfs };
+	let path = unsafe { CStr::from_ptr(path) }.to_string_lossy().into_owned();
+	let bytes = if bytes.is_null() || size == 0 {
+		Vec::new()
+	} else {
+		unsafe { std::slice::from_raw_parts(bytes, size) }.to_vec()
+	};
+	match vfs.add_file(&path, bytes) {
+		Ok(_) => true,
+		Err(e) => {
+			eprintln!("{}", e);
+			false
+		},
+	}
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_load_wmb_from_vfs(vfs: *mut Vfs, wmb_path: *const c_char) -> *mut SceneData {
+	rpu_load_wmb_from_vfs_with_options(vfs, wmb_path, ptr::null())
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_load_wmb_from_vfs_with_options(vfs: *mut Vfs, wmb_path: *const c_char, options: *const LoadOptions) -> *mut SceneData {
+	let vfs = unsafe { &*vfs };
+	let wmb_path = unsafe { CStr::from_ptr(wmb_path) }.to_string_lossy().into_owned();
+	let options = unsafe { options.as_ref() }.cloned().unwrap_or_default();
+	match read_wmb_scr_from_vfs(vfs, &wmb_path, &options) {
 		Ok(scene_data) => Box::into_raw(Box::new(scene_data)),
 		Err(e) => {
 			eprintln!("{}", e);
@@ -50,10 +160,116 @@ pub extern "C" fn rpu_load_wmb_from_bytes(
 	}
 }
 
+/// Starts out with the default visibility rules
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_new_load_options() -> *mut LoadOptions {
+	Box::into_raw(Box::new(LoadOptions::default()))
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_drop_load_options(options: *mut LoadOptions) {
+	unsafe {
+		drop(Box::from_raw(options));
+	}
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_load_options_clear_visibility_rules(options: *mut LoadOptions) {
+	unsafe {
+		(*options).visibility_rules.rules.clear();
+	}
+}
+
+/// Null patterns, a negative `lod` and a `game` of 0 match anything. `game` is 1 for NieR:Automata and 2 for MGRR.
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_load_options_add_visibility_rule(
+	options: *mut LoadOptions,
+	game: u32,
+	model_name: *const c_char,
+	mesh_name: *const c_char,
+	lod: i32,
+	material_name: *const c_char,
+	visible: bool,
+) {
+	let pattern = |pattern: *const c_char| if pattern.is_null() {
+		None
+	} else {
+		Some(unsafe { CStr::from_ptr(pattern) }.to_string_lossy().into_owned())
+	};
+	let game = match game {
+		1 => Some(Game::Na),
+		2 => Some(Game::Mgrr),
+		_ => None,
+	};
+	let rule = VisibilityRule {
+		game,
+		model_name: pattern(model_name),
+		mesh_name: pattern(mesh_name),
+		lod: usize::try_from(lod).ok(),
+		material_name: pattern(material_name),
+		visible,
+	};
+	unsafe {
+		(*options).visibility_rules.rules.push(rule);
+	}
+}
+
+/// Meshes are loaded without geometry or textures, only their bounds
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_load_options_set_bounds_only(options: *mut LoadOptions, bounds_only: bool) {
+	unsafe {
+		(*options).bounds_only = bounds_only;
+	}
+}
+
+/// Bit `i` of `groups` selects WMB4 batch group `i`
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_load_options_set_wmb4_batch_groups(options: *mut LoadOptions, groups: u32) {
+	unsafe {
+		for (i, selected) in (*options).wmb4_batch_groups.iter_mut().enumerate() {
+			*selected = groups & (1 << i) != 0;
+		}
+	}
+}
+
+/// Only needed for scenes that aren't passed to `rpu_new_renderer`
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_drop_scene_data(scene_data: *mut SceneData) {
+	unsafe {
+		drop(Box::from_raw(scene_data));
+	}
+}
+
+/// Writes min x, y, z and max x, y, z to `bounds`. Returns false if the scene has no meshes.
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_get_scene_bounds(scene_data: *mut SceneData, bounds: *mut f32) -> bool {
+	let scene_data = unsafe { &*scene_data };
+	match scene_data.bounds() {
+		Some(scene_bounds) => {
+			let values = [
+				scene_bounds.min.x, scene_bounds.min.y, scene_bounds.min.z,
+				scene_bounds.max.x, scene_bounds.max.y, scene_bounds.max.z,
+			];
+			unsafe {
+				ptr::copy_nonoverlapping(values.as_ptr(), bounds, values.len());
+			}
+			true
+		},
+		None => false,
+	}
+}
 
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
 #[no_mangle]
-pub extern "C" fn rpu_new_context() -> *mut Rc<WindowedContext> {
+pub extern "C" fn rpu_new_context() -> *mut Rc<RenderContext> {
 	console_error_panic_hook::set_once();
 
 	match new_context() {
@@ -65,10 +281,24 @@ pub extern "C" fn rpu_new_context() -> *mut Rc<WindowedContext> {
 	}
 }
 
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_set_texture_cache_budget(context: *mut Rc<RenderContext>, budget_bytes: usize) {
+	let context = unsafe { &*context };
+	context.texture_cache.borrow_mut().set_budget(budget_bytes);
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_get_texture_cache_usage(context: *mut Rc<RenderContext>) -> usize {
+	let context = unsafe { &*context };
+	context.texture_cache.borrow().used_bytes()
+}
+
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
 #[no_mangle]
 pub extern "C" fn rpu_new_renderer(
-	context: *mut Rc<WindowedContext>,
+	context: *mut Rc<RenderContext>,
 	width: u32,
 	height: u32,
 	scene_data: *mut SceneData,
@@ -159,6 +389,41 @@ pub extern "C"  fn rpu_get_model_states(state: *mut RenderState) -> *const char
 	}
 }
 
+/// `lod_index` < 0 selects the LOD automatically based on the size on screen
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_set_model_lod(state: *mut RenderState, lod_group_index: u32, lod_index: i32) -> bool {
+	let state = unsafe { &mut *state };
+	let selection = if lod_index < 0 {
+		LodSelection::Auto
+	} else {
+		LodSelection::Fixed(lod_index as usize)
+	};
+	match state.set_lod_selection(lod_group_index as usize, selection) {
+		Ok(_) => true,
+		Err(e) => {
+			eprintln!("{}", e);
+			false
+		},
+	}
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_set_col_tree_visibility(state: *mut RenderState, visible: bool) {
+	unsafe {
+		(*state).set_col_tree_visibility(visible);
+	}
+}
+
+#[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
+#[no_mangle]
+pub extern "C" fn rpu_get_lod_states(state: *mut RenderState) -> *const char {
+	unsafe {
+		(*state).lod_states.as_ptr() as *const char
+	}
+}
+
 #[cfg_attr(target_arch = "wasm32", wasm_bindgen)]
 #[no_mangle]
 pub extern "C" fn malloc(size: usize) -> *mut c_void {
@@ -0,0 +1,680 @@
+use std::{fs::File, io::{BufReader, Cursor, Read, Seek}, path::Path};
+
+use crate::byte_stream::ByteReader;
+
+
+pub struct Cpk<R: Read + Seek> {
+	reader: ByteReader<R>,
+	entries: Vec<CpkEntry>,
+}
+
+#[derive(Clone)]
+pub struct CpkEntry {
+	pub dir_name: String,
+	pub file_name: String,
+	pub id: u32,
+	pub offset: u64,
+	pub file_size: u32,
+	pub extract_size: u32,
+	pub update_date_time: Option<u64>,
+}
+
+impl CpkEntry {
+	pub fn path(&self) -> String {
+		if self.dir_name.is_empty() {
+			self.file_name.clone()
+		} else {
+			format!("{}/{}", self.dir_name, self.file_name)
+		}
+	}
+
+	pub fn is_compressed(&self) -> bool {
+		self.extract_size > self.file_size
+	}
+}
+
+impl Cpk<BufReader<File>> {
+	pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+		let file = File::open(path).map_err(|e| e.to_string())?;
+		Self::read(BufReader::new(file))
+	}
+}
+
+impl<'a> Cpk<Cursor<&'a[u8]>> {
+	pub fn from_bytes(bytes: &'a[u8]) -> Result<Self, String> {
+		Self::read(Cursor::new(bytes))
+	}
+}
+
+impl<R: Read + Seek> Cpk<R> {
+	pub fn read(reader: R) -> Result<Self, String> {
+		let mut reader = ByteReader::new(reader);
+		let header = read_utf_chunk(&mut reader, 0, "CPK ")?;
+		let toc_offset = header.get_u64(0, "TocOffset");
+		let itoc_offset = header.get_u64(0, "ItocOffset");
+		let etoc_offset = header.get_u64(0, "EtocOffset");
+		let content_offset = header.get_u64(0, "ContentOffset");
+		let align = header.get_u64(0, "Align").unwrap_or(0x800).max(1);
+
+		let mut entries = Vec::new();
+		if let Some(toc_offset) = toc_offset.filter(|o| *o != 0) {
+			let toc = read_utf_chunk(&mut reader, toc_offset, "TOC ")?;
+			let base_offset = match content_offset {
+				Some(content_offset) if content_offset < toc_offset => content_offset,
+				_ => toc_offset,
+			};
+			for row in 0..toc.rows.len() {
+				entries.push(CpkEntry {
+					dir_name: toc.get_string(row, "DirName").unwrap_or_default(),
+					file_name: toc.get_string(row, "FileName").unwrap_or_default(),
+					id: toc.get_u64(row, "ID").unwrap_or(row as u64) as u32,
+					offset: base_offset + toc.get_u64(row, "FileOffset").unwrap_or(0),
+					file_size: toc.get_u64(row, "FileSize").unwrap_or(0) as u32,
+					extract_size: toc.get_u64(row, "ExtractSize").unwrap_or(0) as u32,
+					update_date_time: None,
+				});
+			}
+		} else if let Some(itoc_offset) = itoc_offset.filter(|o| *o != 0) {
+			// ID-only archives: files are stored in ID order, starting at the content offset
+			let itoc = read_utf_chunk(&mut reader, itoc_offset, "ITOC")?;
+			let mut files = Vec::new();
+			for column in ["DataL", "DataH"] {
+				let Some(data) = itoc.get_data(0, column) else { continue };
+				let table = UtfTable::read(data)?;
+				for row in 0..table.rows.len() {
+					let id = table.get_u64(row, "ID").unwrap_or(0) as u32;
+					let file_size = table.get_u64(row, "FileSize").unwrap_or(0) as u32;
+					let extract_size = table.get_u64(row, "ExtractSize").unwrap_or(file_size as u64) as u32;
+					files.push((id, file_size, extract_size));
+				}
+			}
+			files.sort_by_key(|(id, _, _)| *id);
+			let mut offset = content_offset.ok_or("CPK has no content offset")?;
+			for (id, file_size, extract_size) in files {
+				entries.push(CpkEntry {
+					dir_name: String::new(),
+					file_name: format!("{:05}", id),
+					id,
+					offset,
+					file_size,
+					extract_size,
+					update_date_time: None,
+				});
+				offset += (file_size as u64).div_ceil(align) * align;
+			}
+		} else {
+			return Err("CPK has neither TOC nor ITOC".to_string());
+		}
+
+		if let Some(etoc_offset) = etoc_offset.filter(|o| *o != 0) {
+			let etoc = read_utf_chunk(&mut reader, etoc_offset, "ETOC")?;
+			for (row, entry) in entries.iter_mut().enumerate() {
+				entry.update_date_time = etoc.get_u64(row, "UpdateDateTime");
+			}
+		}
+
+		Ok(Cpk {
+			reader,
+			entries,
+		})
+	}
+
+	pub fn entries(&self) -> &[CpkEntry] {
+		&self.entries
+	}
+
+	pub fn find(&self, path: &str) -> Option<usize> {
+		let path = path.replace('\\', "/");
+		let path = path.trim_start_matches('/');
+		self.entries.iter().position(|e| e.path().eq_ignore_ascii_case(path))
+	}
+
+	pub fn contains(&self, path: &str) -> bool {
+		self.find(path).is_some()
+	}
+
+	pub fn read_entry_at(&mut self, index: usize) -> Result<Vec<u8>, String> {
+		let entry = self.entries.get(index).ok_or(format!("CPK entry index out of bounds: {}", index))?;
+		let (offset, file_size) = (entry.offset, entry.file_size);
+		self.reader.seek(offset)?;
+		let data = self.reader.read(file_size as usize)?;
+		if data.starts_with(b"CRILAYLA") {
+			decompress_crilayla(&data)
+		} else {
+			Ok(data)
+		}
+	}
+
+	pub fn read_entry(&mut self, path: &str) -> Result<Vec<u8>, String> {
+		let index = self.find(path).ok_or(format!("File not found in CPK: {}", path))?;
+		self.read_entry_at(index)
+	}
+
+	pub fn open_entry(&mut self, path: &str) -> Result<Cursor<Vec<u8>>, String> {
+		Ok(Cursor::new(self.read_entry(path)?))
+	}
+}
+
+fn read_utf_chunk<R: Read + Seek>(reader: &mut ByteReader<R>, offset: u64, id: &str) -> Result<UtfTable, String> {
+	reader.seek(offset)?;
+	let chunk_id = reader.read_string(4)?;
+	if chunk_id != id {
+		return Err(format!("Expected CPK chunk {:?}, found {:?}", id, chunk_id));
+	}
+	let _unknown = reader.read_u32()?;
+	let size = reader.read_u64()?;
+	let mut bytes = reader.read(size as usize)?;
+	if !bytes.starts_with(b"@UTF") {
+		decrypt_utf(&mut bytes);
+	}
+	UtfTable::read(&bytes)
+}
+
+fn decrypt_utf(bytes: &mut [u8]) {
+	let mut m: u32 = 0x0000655f;
+	let t: u32 = 0x00004115;
+	for byte in bytes.iter_mut() {
+		*byte ^= (m & 0xff) as u8;
+		m = m.wrapping_mul(t);
+	}
+}
+
+const COLUMN_STORAGE_MASK: u8 = 0xf0;
+const COLUMN_STORAGE_ZERO: u8 = 0x10;
+const COLUMN_STORAGE_CONSTANT: u8 = 0x30;
+const COLUMN_STORAGE_PER_ROW: u8 = 0x50;
+const COLUMN_STORAGE_CONSTANT2: u8 = 0x70;
+const COLUMN_TYPE_MASK: u8 = 0x0f;
+
+#[derive(Clone)]
+pub enum UtfValue {
+	U8(u8),
+	I8(i8),
+	U16(u16),
+	I16(i16),
+	U32(u32),
+	I32(i32),
+	U64(u64),
+	I64(i64),
+	F32(f32),
+	F64(f64),
+	String(String),
+	Data(Vec<u8>),
+	None,
+}
+
+impl UtfValue {
+	pub fn as_u64(&self) -> Option<u64> {
+		match self {
+			UtfValue::U8(v) => Some(*v as u64),
+			UtfValue::I8(v) => Some(*v as u64),
+			UtfValue::U16(v) => Some(*v as u64),
+			UtfValue::I16(v) => Some(*v as u64),
+			UtfValue::U32(v) => Some(*v as u64),
+			UtfValue::I32(v) => Some(*v as u64),
+			UtfValue::U64(v) => Some(*v),
+			UtfValue::I64(v) => Some(*v as u64),
+			_ => None,
+		}
+	}
+}
+
+pub struct UtfColumn {
+	pub flags: u8,
+	pub name: String,
+	constant: Option<UtfValue>,
+}
+
+/// The `@UTF` table format used by CRI middleware. All values are big endian.
+pub struct UtfTable {
+	pub name: String,
+	pub columns: Vec<UtfColumn>,
+	pub rows: Vec<Vec<UtfValue>>,
+}
+
+impl UtfTable {
+	pub fn read(bytes: &[u8]) -> Result<Self, String> {
+		let mut reader = BeReader { bytes, pos: 0 };
+		if reader.read(4)? != b"@UTF" {
+			return Err("Invalid @UTF table".to_string());
+		}
+		let _table_size = reader.read_u32()?;
+		// offsets are relative to the end of the magic and table size
+		let _version = reader.read_u16()?;
+		let rows_offset = reader.read_u16()? as usize + 8;
+		let strings_offset = reader.read_u32()? as usize + 8;
+		let data_offset = reader.read_u32()? as usize + 8;
+		let name_offset = reader.read_u32()?;
+		let num_columns = reader.read_u16()?;
+		let row_length = reader.read_u16()? as usize;
+		let num_rows = reader.read_u32()?;
+
+		let tables = UtfHeaps { bytes, strings_offset, data_offset };
+		let name = tables.read_string(name_offset)?;
+
+		let mut columns = Vec::with_capacity(num_columns as usize);
+		for _ in 0..num_columns {
+			let mut flags = reader.read_u8()?;
+			if flags == 0 {
+				reader.read(3)?;
+				flags = reader.read_u8()?;
+			}
+			let name = tables.read_string(reader.read_u32()?)?;
+			let constant = match flags & COLUMN_STORAGE_MASK {
+				COLUMN_STORAGE_CONSTANT | COLUMN_STORAGE_CONSTANT2 => Some(read_utf_value(&mut reader, &tables, flags)?),
+				_ => None,
+			};
+			columns.push(UtfColumn { flags, name, constant });
+		}
+
+		let mut rows = Vec::with_capacity(num_rows as usize);
+		for i in 0..num_rows as usize {
+			reader.pos = rows_offset + i * row_length;
+			let mut row = Vec::with_capacity(columns.len());
+			for column in &columns {
+				let value = match column.flags & COLUMN_STORAGE_MASK {
+					COLUMN_STORAGE_PER_ROW => read_utf_value(&mut reader, &tables, column.flags)?,
+					COLUMN_STORAGE_CONSTANT | COLUMN_STORAGE_CONSTANT2 => column.constant.clone().unwrap_or(UtfValue::None),
+					COLUMN_STORAGE_ZERO => UtfValue::None,
+					_ => UtfValue::None,
+				};
+				row.push(value);
+			}
+			rows.push(row);
+		}
+
+		Ok(UtfTable {
+			name,
+			columns,
+			rows,
+		})
+	}
+
+	pub fn get(&self, row: usize, column: &str) -> Option<&UtfValue> {
+		let column = self.columns.iter().position(|c| c.name == column)?;
+		self.rows.get(row)?.get(column)
+	}
+
+	pub fn get_u64(&self, row: usize, column: &str) -> Option<u64> {
+		self.get(row, column)?.as_u64()
+	}
+
+	pub fn get_string(&self, row: usize, column: &str) -> Option<String> {
+		match self.get(row, column)? {
+			UtfValue::String(s) => Some(s.clone()),
+			_ => None,
+		}
+	}
+
+	pub fn get_data(&self, row: usize, column: &str) -> Option<&[u8]> {
+		match self.get(row, column)? {
+			UtfValue::Data(d) => Some(d.as_slice()),
+			_ => None,
+		}
+	}
+}
+
+fn read_utf_value(reader: &mut BeReader, heaps: &UtfHeaps, flags: u8) -> Result<UtfValue, String> {
+	Ok(match flags & COLUMN_TYPE_MASK {
+		0x0 => UtfValue::U8(reader.read_u8()?),
+		0x1 => UtfValue::I8(reader.read_u8()? as i8),
+		0x2 => UtfValue::U16(reader.read_u16()?),
+		0x3 => UtfValue::I16(reader.read_u16()? as i16),
+		0x4 => UtfValue::U32(reader.read_u32()?),
+		0x5 => UtfValue::I32(reader.read_u32()? as i32),
+		0x6 => UtfValue::U64(reader.read_u64()?),
+		0x7 => UtfValue::I64(reader.read_u64()? as i64),
+		0x8 => UtfValue::F32(f32::from_bits(reader.read_u32()?)),
+		0x9 => UtfValue::F64(f64::from_bits(reader.read_u64()?)),
+		0xa => UtfValue::String(heaps.read_string(reader.read_u32()?)?),
+		0xb => {
+			let offset = reader.read_u32()?;
+			let size = reader.read_u32()?;
+			UtfValue::Data(heaps.read_data(offset, size)?.to_vec())
+		}
+		t => return Err(format!("Unknown @UTF column type: {}", t)),
+	})
+}
+
+struct UtfHeaps<'a> {
+	bytes: &'a [u8],
+	strings_offset: usize,
+	data_offset: usize,
+}
+
+impl UtfHeaps<'_> {
+	fn read_string(&self, offset: u32) -> Result<String, String> {
+		let start = self.strings_offset + offset as usize;
+		let bytes = self.bytes.get(start..).ok_or("@UTF string offset out of bounds")?;
+		let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+		Ok(String::from_utf8_lossy(&bytes[..end]).to_string())
+	}
+
+	fn read_data(&self, offset: u32, size: u32) -> Result<&[u8], String> {
+		let start = self.data_offset + offset as usize;
+		self.bytes.get(start..start + size as usize).ok_or("@UTF data out of bounds".to_string())
+	}
+}
+
+struct BeReader<'a> {
+	bytes: &'a [u8],
+	pos: usize,
+}
+
+impl BeReader<'_> {
+	fn read(&mut self, size: usize) -> Result<&[u8], String> {
+		let bytes = self.bytes.get(self.pos..self.pos + size).ok_or("Unexpected end of @UTF table")?;
+		self.pos += size;
+		Ok(bytes)
+	}
+
+	fn read_u8(&mut self) -> Result<u8, String> {
+		Ok(self.read(1)?[0])
+	}
+
+	fn read_u16(&mut self) -> Result<u16, String> {
+		Ok(u16::from_be_bytes(self.read(2)?.try_into().unwrap()))
+	}
+
+	fn read_u32(&mut self) -> Result<u32, String> {
+		Ok(u32::from_be_bytes(self.read(4)?.try_into().unwrap()))
+	}
+
+	fn read_u64(&mut self) -> Result<u64, String> {
+		Ok(u64::from_be_bytes(self.read(8)?.try_into().unwrap()))
+	}
+}
+
+/// Decompresses CRILAYLA data. The compressed stream is read backwards from the end,
+/// the last 0x100 bytes are the uncompressed file header.
+pub fn decompress_crilayla(data: &[u8]) -> Result<Vec<u8>, String> {
+	if data.len() < 0x110 || !data.starts_with(b"CRILAYLA") {
+		return Err("Invalid CRILAYLA data".to_string());
+	}
+	let uncompressed_size = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+	let header_offset = u32::from_le_bytes(data[12..16].try_into().unwrap()) as usize + 0x10;
+	let header = data.get(header_offset..header_offset + 0x100).ok_or("Invalid CRILAYLA header offset")?;
+
+	let mut result = vec![0u8; uncompressed_size + 0x100];
+	result[..0x100].copy_from_slice(header);
+
+	let mut bits = CrilaylaBits {
+		data,
+		offset: data.len() as isize - 0x100 - 1,
+		bit_pool: 0,
+		bits_left: 0,
+	};
+	let output_end = 0x100 + uncompressed_size - 1;
+	let mut bytes_output = 0;
+	const VLE_LENS: [u32; 4] = [2, 3, 5, 8];
+
+	while bytes_output < uncompressed_size {
+		if bits.next(1)? > 0 {
+			let mut backreference_offset = output_end - bytes_output + bits.next(13)? as usize + 3;
+			let mut backreference_length = 3;
+			let mut vle_level = 0;
+			while vle_level < VLE_LENS.len() {
+				let this_level = bits.next(VLE_LENS[vle_level])? as usize;
+				backreference_length += this_level;
+				if this_level != (1 << VLE_LENS[vle_level]) - 1 {
+					break;
+				}
+				vle_level += 1;
+			}
+			if vle_level == VLE_LENS.len() {
+				loop {
+					let this_level = bits.next(8)? as usize;
+					backreference_length += this_level;
+					if this_level != 255 {
+						break;
+					}
+				}
+			}
+			for _ in 0..backreference_length {
+				if bytes_output >= uncompressed_size {
+					break;
+				}
+				let value = *result.get(backreference_offset).ok_or("Invalid CRILAYLA back reference")?;
+				result[output_end - bytes_output] = value;
+				backreference_offset -= 1;
+				bytes_output += 1;
+			}
+		} else {
+			result[output_end - bytes_output] = bits.next(8)? as u8;
+			bytes_output += 1;
+		}
+	}
+
+	Ok(result)
+}
+
+struct CrilaylaBits<'a> {
+	data: &'a [u8],
+	offset: isize,
+	bit_pool: u8,
+	bits_left: u32,
+}
+
+impl CrilaylaBits<'_> {
+	fn next(&mut self, bit_count: u32) -> Result<u16, String> {
+		let mut out_bits: u16 = 0;
+		let mut num_bits_produced = 0;
+		while num_bits_produced < bit_count {
+			if self.bits_left == 0 {
+				// the stream starts after the magic and the two sizes
+				if self.offset < 0x10 {
+					return Err("Unexpected end of CRILAYLA data".to_string());
+				}
+				self.bit_pool = self.data[self.offset as usize];
+				self.bits_left = 8;
+				self.offset -= 1;
+			}
+			let bits_this_round = self.bits_left.min(bit_count - num_bits_produced);
+			out_bits <<= bits_this_round;
+			out_bits |= ((self.bit_pool as u16) >> (self.bits_left - bits_this_round)) & ((1 << bits_this_round) - 1);
+			self.bits_left -= bits_this_round;
+			num_bits_produced += bits_this_round;
+		}
+		Ok(out_bits)
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+
+	fn add_string(strings: &mut Vec<u8>, string: &str) -> u32 {
+		let offset = strings.len() as u32;
+		strings.extend(string.as_bytes());
+		strings.push(0);
+		offset
+	}
+
+	/// A `@UTF` table with one per row column for each name, typed by the values of the first row
+	pub(crate) fn utf_table(name: &str, columns: &[&str], rows: &[Vec<UtfValue>]) -> Vec<u8> {
+		let mut strings = Vec::new();
+		let name_offset = add_string(&mut strings, name);
+		let mut column_bytes = Vec::new();
+		for (i, column) in columns.iter().enumerate() {
+			let column_type = match rows.first().map(|row| &row[i]) {
+				Some(UtfValue::String(_)) => 0xa,
+				_ => 0x6,
+			};
+			column_bytes.push(COLUMN_STORAGE_PER_ROW | column_type);
+			column_bytes.extend(add_string(&mut strings, column).to_be_bytes());
+		}
+		let mut row_bytes = Vec::new();
+		for row in rows {
+			for value in row {
+				match value {
+					UtfValue::String(string) => row_bytes.extend(add_string(&mut strings, string).to_be_bytes()),
+					value => row_bytes.extend(value.as_u64().unwrap().to_be_bytes()),
+				}
+			}
+		}
+		let row_length = row_bytes.len().checked_div(rows.len()).unwrap_or(0);
+		let rows_offset = 24 + column_bytes.len();
+		let strings_offset = rows_offset + row_bytes.len();
+
+		let mut body = Vec::new();
+		body.extend(1u16.to_be_bytes());
+		body.extend((rows_offset as u16).to_be_bytes());
+		body.extend((strings_offset as u32).to_be_bytes());
+		body.extend(((strings_offset + strings.len()) as u32).to_be_bytes());
+		body.extend(name_offset.to_be_bytes());
+		body.extend((columns.len() as u16).to_be_bytes());
+		body.extend((row_length as u16).to_be_bytes());
+		body.extend((rows.len() as u32).to_be_bytes());
+		body.extend(column_bytes);
+		body.extend(row_bytes);
+		body.extend(strings);
+		let mut table = b"@UTF".to_vec();
+		table.extend((body.len() as u32).to_be_bytes());
+		table.extend(body);
+		table
+	}
+
+	fn chunk(id: &[u8; 4], table: Vec<u8>) -> Vec<u8> {
+		let mut chunk = id.to_vec();
+		chunk.extend(0xffu32.to_le_bytes());
+		chunk.extend((table.len() as u64).to_le_bytes());
+		chunk.extend(table);
+		chunk
+	}
+
+	/// A CPK with a TOC at 0x800 and the files from 0x1000 on, CRILAYLA data is stored as is
+	pub(crate) fn test_cpk(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+		const TOC_OFFSET: usize = 0x800;
+		let mut content = Vec::new();
+		let mut rows = Vec::new();
+		for (path, data) in files {
+			let (dir_name, file_name) = path.rsplit_once('/').unwrap_or(("", path));
+			let extract_size = if data.starts_with(b"CRILAYLA") {
+				u32::from_le_bytes(data[8..12].try_into().unwrap()) + 0x100
+			} else {
+				data.len() as u32
+			};
+			rows.push(vec![
+				UtfValue::String(dir_name.to_string()),
+				UtfValue::String(file_name.to_string()),
+				UtfValue::U32(data.len() as u32),
+				UtfValue::U32(extract_size),
+				UtfValue::U64((TOC_OFFSET + content.len()) as u64),
+			]);
+			content.extend(data);
+			content.resize(content.len().next_multiple_of(0x800), 0);
+		}
+		let header = chunk(b"CPK ", utf_table("CpkHeader", &["TocOffset"], &[vec![UtfValue::U64(TOC_OFFSET as u64)]]));
+		let toc = chunk(b"TOC ", utf_table("CpkTocInfo", &["DirName", "FileName", "FileSize", "ExtractSize", "FileOffset"], &rows));
+		assert!(header.len() <= TOC_OFFSET && toc.len() <= 0x800);
+
+		let mut cpk = header;
+		cpk.resize(TOC_OFFSET, 0);
+		cpk.extend(toc);
+		cpk.resize(TOC_OFFSET * 2, 0);
+		cpk.extend(content);
+		cpk
+	}
+
+	/// Packs bits in the order `CrilaylaBits` reads them: MSB first, from the end of the stream
+	fn crilayla_blob(header: &[u8; 0x100], uncompressed_size: u32, fields: &[(u16, u32)]) -> Vec<u8> {
+		let mut bits = Vec::new();
+		for (value, bit_count) in fields {
+			bits.extend((0..*bit_count).rev().map(|i| (value >> i) & 1 != 0));
+		}
+		let mut stream = bits.chunks(8)
+			.map(|byte| byte.iter().enumerate().fold(0u8, |acc, (i, bit)| acc | ((*bit as u8) << (7 - i))))
+			.collect::<Vec<_>>();
+		stream.reverse();
+
+		let mut blob = b"CRILAYLA".to_vec();
+		blob.extend(uncompressed_size.to_le_bytes());
+		blob.extend((stream.len() as u32).to_le_bytes());
+		blob.extend(stream);
+		blob.extend(header);
+		blob
+	}
+
+	#[test]
+	fn decompress_literals_and_back_reference() {
+		let header = [0x42; 0x100];
+		// output is written back to front: "zyx" as literals, then 6 bytes copied from 3 bytes ahead
+		let blob = crilayla_blob(&header, 9, &[
+			(0, 1), (b'z' as u16, 8),
+			(0, 1), (b'y' as u16, 8),
+			(0, 1), (b'x' as u16, 8),
+			(1, 1), (0, 13), (3, 2), (0, 3),
+		]);
+		let decompressed = decompress_crilayla(&blob).unwrap();
+		assert_eq!(&decompressed[..0x100], &header);
+		assert_eq!(&decompressed[0x100..], b"xyzxyzxyz");
+	}
+
+	#[test]
+	fn back_reference_past_the_output_is_an_error() {
+		let blob = crilayla_blob(&[0; 0x100], 4, &[(1, 1), (0x1fff, 13), (0, 2)]);
+		assert_eq!(decompress_crilayla(&blob), Err("Invalid CRILAYLA back reference".to_string()));
+	}
+
+	#[test]
+	fn read_entries() {
+		let compressed = crilayla_blob(&[0x42; 0x100], 3, &[
+			(0, 1), (b'c' as u16, 8),
+			(0, 1), (b'b' as u16, 8),
+			(0, 1), (b'a' as u16, 8),
+		]);
+		let bytes = test_cpk(&[
+			("root.bin", vec![1, 2, 3]),
+			("dir/sub/packed.bin", compressed),
+		]);
+		let mut cpk = Cpk::from_bytes(&bytes).unwrap();
+		assert_eq!(cpk.entries().len(), 2);
+		assert_eq!(cpk.entries()[1].dir_name, "dir/sub");
+		assert_eq!(cpk.entries()[1].extract_size, 0x103);
+		assert_eq!(cpk.read_entry("ROOT.BIN").unwrap(), vec![1, 2, 3]);
+		let packed = cpk.read_entry("\\dir\\sub\\packed.bin").unwrap();
+		assert_eq!(packed.len(), 0x103);
+		assert_eq!(&packed[0x100..], b"abc");
+		assert!(cpk.read_entry("missing.bin").is_err());
+	}
+
+	#[test]
+	fn truncated_stream_is_an_error() {
+		let blob = crilayla_blob(&[0; 0x100], 4, &[(0, 1), (b'a' as u16, 8)]);
+		assert!(decompress_crilayla(&blob).is_err());
+	}
+
+	#[test]
+	fn read_utf_table() {
+		let strings = b"tbl\0TocOffset\0Name\0hello\0";
+		let mut body = Vec::new();
+		body.extend(1u16.to_be_bytes());
+		// rows, strings and data offsets, relative to the start of the body
+		body.extend(34u16.to_be_bytes());
+		body.extend(46u32.to_be_bytes());
+		body.extend((46 + strings.len() as u32).to_be_bytes());
+		// table name, column count, row length, row count
+		body.extend(0u32.to_be_bytes());
+		body.extend(2u16.to_be_bytes());
+		body.extend(12u16.to_be_bytes());
+		body.extend(1u32.to_be_bytes());
+		body.push(COLUMN_STORAGE_PER_ROW | 0x6);
+		body.extend(4u32.to_be_bytes());
+		body.push(COLUMN_STORAGE_PER_ROW | 0xa);
+		body.extend(14u32.to_be_bytes());
+		body.extend(0x1234u64.to_be_bytes());
+		body.extend(19u32.to_be_bytes());
+		body.extend(strings);
+		let mut bytes = b"@UTF".to_vec();
+		bytes.extend((body.len() as u32).to_be_bytes());
+		bytes.extend(body);
+
+		let table = UtfTable::read(&bytes).unwrap();
+		assert_eq!(table.name, "tbl");
+		assert_eq!(table.get_u64(0, "TocOffset"), Some(0x1234));
+		assert_eq!(table.get_string(0, "Name").as_deref(), Some("hello"));
+		assert!(table.get(1, "Name").is_none());
+
+		assert!(UtfTable::read(&bytes[..bytes.len() - strings.len() - 4]).is_err());
+	}
+}
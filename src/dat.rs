@@ -0,0 +1,428 @@
+use std::{fs::File, io::{BufReader, BufWriter, Cursor, Read, Seek, Write}, path::Path};
+
+use crate::byte_stream::{ByteReader, ByteWriter};
+
+const DAT_HEADER_SIZE: u32 = 0x20;
+const DAT_FILE_ALIGNMENT: u64 = 16;
+
+
+pub struct Dat<R: Read + Seek> {
+	reader: ByteReader<R>,
+	entries: Vec<DatEntry>,
+	hash_data: Option<DatHashData>,
+}
+
+pub struct DatWriteEntry {
+	pub name: String,
+	pub data: Vec<u8>,
+}
+
+#[derive(Clone)]
+pub struct DatEntry {
+	pub name: String,
+	pub extension: String,
+	pub offset: u32,
+	pub size: u32,
+}
+
+pub struct DatHashData {
+	pub pre_hash_shift: u32,
+	pub bucket_offsets: Vec<i16>,
+	pub hashes: Vec<u32>,
+	pub indices: Vec<u16>,
+}
+
+struct DatHeader {
+	id: String,
+	file_count: u32,
+	offset_file_offsets: u32,
+	offset_extensions: u32,
+	offset_names: u32,
+	offset_sizes: u32,
+	offset_hash_data: u32,
+}
+
+impl DatHeader {
+	fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
+		Ok(DatHeader {
+			id: reader.read_string(4)?,
+			file_count: reader.read_u32()?,
+			offset_file_offsets: reader.read_u32()?,
+			offset_extensions: reader.read_u32()?,
+			offset_names: reader.read_u32()?,
+			offset_sizes: reader.read_u32()?,
+			offset_hash_data: reader.read_u32()?,
+		})
+	}
+}
+
+impl DatHashData {
+	fn read<R: Read + Seek>(reader: &mut ByteReader<R>, file_count: u32) -> Result<Self, String> {
+		let start = reader.position()?;
+		let pre_hash_shift = reader.read_u32()?;
+		let offset_bucket_offsets = reader.read_u32()? as u64;
+		let offset_hashes = reader.read_u32()? as u64;
+		let offset_indices = reader.read_u32()? as u64;
+		if pre_hash_shift > 31 {
+			return Err(format!("Invalid DAT hash shift: {}", pre_hash_shift));
+		}
+
+		reader.seek(start + offset_bucket_offsets)?;
+		let num_buckets = 1u32 << (31 - pre_hash_shift);
+		let mut bucket_offsets = Vec::with_capacity(num_buckets as usize);
+		for _ in 0..num_buckets {
+			bucket_offsets.push(reader.read_i16()?);
+		}
+
+		reader.seek(start + offset_hashes)?;
+		let mut hashes = Vec::with_capacity(file_count as usize);
+		for _ in 0..file_count {
+			hashes.push(reader.read_u32()?);
+		}
+
+		reader.seek(start + offset_indices)?;
+		let mut indices = Vec::with_capacity(file_count as usize);
+		for _ in 0..file_count {
+			indices.push(reader.read_u16()?);
+		}
+
+		Ok(DatHashData {
+			pre_hash_shift,
+			bucket_offsets,
+			hashes,
+			indices,
+		})
+	}
+
+	pub fn generate(names: &[&str]) -> Result<Self, String> {
+		let mut pre_hash_shift = 0;
+		for i in 0..31 {
+			if 1usize << i >= names.len() {
+				pre_hash_shift = 31 - i;
+				break;
+			}
+		}
+		let mut hashes = names.iter()
+			.enumerate()
+			.map(|(i, name)| (dat_name_hash(name), i as u16))
+			.collect::<Vec<_>>();
+		hashes.sort_by_key(|(hash, _)| hash >> pre_hash_shift);
+
+		let mut bucket_offsets = vec![-1i16; 1 << (31 - pre_hash_shift)];
+		for (i, (hash, _)) in hashes.iter().enumerate() {
+			let bucket = (hash >> pre_hash_shift) as usize;
+			if bucket_offsets[bucket] == -1 {
+				bucket_offsets[bucket] = i16::try_from(i)
+					.map_err(|_| format!("Too many files for a DAT hash table: {}", names.len()))?;
+			}
+		}
+
+		Ok(DatHashData {
+			pre_hash_shift,
+			bucket_offsets,
+			hashes: hashes.iter().map(|(hash, _)| *hash).collect(),
+			indices: hashes.iter().map(|(_, index)| *index).collect(),
+		})
+	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		let offset_bucket_offsets = 16;
+		let offset_hashes = offset_bucket_offsets + self.bucket_offsets.len() as u32 * 2;
+		let offset_indices = offset_hashes + self.hashes.len() as u32 * 4;
+		writer.write_u32(self.pre_hash_shift)?;
+		writer.write_u32(offset_bucket_offsets)?;
+		writer.write_u32(offset_hashes)?;
+		writer.write_u32(offset_indices)?;
+		for bucket_offset in &self.bucket_offsets {
+			writer.write_i16(*bucket_offset)?;
+		}
+		for hash in &self.hashes {
+			writer.write_u32(*hash)?;
+		}
+		for index in &self.indices {
+			writer.write_u16(*index)?;
+		}
+		Ok(())
+	}
+
+	fn find(&self, name: &str) -> Option<usize> {
+		let hash = dat_name_hash(name);
+		let bucket = (hash >> self.pre_hash_shift) as usize;
+		let start = *self.bucket_offsets.get(bucket)?;
+		if start < 0 {
+			return None;
+		}
+		for i in start as usize..self.hashes.len() {
+			if self.hashes[i] >> self.pre_hash_shift != hash >> self.pre_hash_shift {
+				break;
+			}
+			if self.hashes[i] == hash {
+				return Some(self.indices[i] as usize);
+			}
+		}
+		None
+	}
+}
+
+impl Dat<BufReader<File>> {
+	pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+		let file = File::open(path).map_err(|e| e.to_string())?;
+		Self::read(BufReader::new(file))
+	}
+}
+
+impl<'a> Dat<Cursor<&'a[u8]>> {
+	pub fn from_bytes(bytes: &'a[u8]) -> Result<Self, String> {
+		Self::read(Cursor::new(bytes))
+	}
+}
+
+impl<R: Read + Seek> Dat<R> {
+	pub fn read(reader: R) -> Result<Self, String> {
+		let mut reader = ByteReader::new(reader);
+		let header = DatHeader::read(&mut reader)?;
+		if header.id != "DAT\0" {
+			return Err(format!("Not a DAT file: {}", header.id.replace('\0', "")));
+		}
+		let file_count = header.file_count as usize;
+
+		reader.seek(header.offset_file_offsets as u64)?;
+		let mut offsets = Vec::with_capacity(file_count);
+		for _ in 0..file_count {
+			offsets.push(reader.read_u32()?);
+		}
+
+		reader.seek(header.offset_extensions as u64)?;
+		let mut extensions = Vec::with_capacity(file_count);
+		for _ in 0..file_count {
+			extensions.push(reader.read_string(4)?.replace('\0', ""));
+		}
+
+		reader.seek(header.offset_names as u64)?;
+		let name_length = reader.read_u32()? as usize;
+		let mut names = Vec::with_capacity(file_count);
+		for _ in 0..file_count {
+			let name = reader.read_string(name_length)?;
+			let name = name.split('\0').next().unwrap_or("").to_string();
+			names.push(name);
+		}
+
+		reader.seek(header.offset_sizes as u64)?;
+		let mut sizes = Vec::with_capacity(file_count);
+		for _ in 0..file_count {
+			sizes.push(reader.read_u32()?);
+		}
+
+		let hash_data = if header.offset_hash_data != 0 {
+			reader.seek(header.offset_hash_data as u64)?;
+			Some(DatHashData::read(&mut reader, header.file_count)?)
+		} else {
+			None
+		};
+
+		let entries = names.into_iter()
+			.zip(extensions)
+			.zip(offsets.into_iter().zip(sizes))
+			.map(|((name, extension), (offset, size))| DatEntry { name, extension, offset, size })
+			.collect();
+
+		Ok(Dat {
+			reader,
+			entries,
+			hash_data,
+		})
+	}
+
+	pub fn entries(&self) -> &[DatEntry] {
+		&self.entries
+	}
+
+	pub fn hash_data(&self) -> Option<&DatHashData> {
+		self.hash_data.as_ref()
+	}
+
+	pub fn find(&self, name: &str) -> Option<usize> {
+		let index = self.hash_data.as_ref()
+			.and_then(|hash_data| hash_data.find(name))
+			.filter(|i| self.entries.get(*i).is_some_and(|e| e.name.eq_ignore_ascii_case(name)));
+		index.or_else(|| self.entries.iter().position(|e| e.name.eq_ignore_ascii_case(name)))
+	}
+
+	pub fn contains(&self, name: &str) -> bool {
+		self.find(name).is_some()
+	}
+
+	pub fn read_entry_at(&mut self, index: usize) -> Result<Vec<u8>, String> {
+		let entry = self.entries.get(index).ok_or(format!("DAT entry index out of bounds: {}", index))?;
+		let (offset, size) = (entry.offset, entry.size);
+		self.reader.seek(offset as u64)?;
+		self.reader.read(size as usize)
+	}
+
+	pub fn read_entry(&mut self, name: &str) -> Result<Vec<u8>, String> {
+		let index = self.find(name).ok_or(format!("File not found in DAT: {}", name))?;
+		self.read_entry_at(index)
+	}
+
+	pub fn open_entry(&mut self, name: &str) -> Result<Cursor<Vec<u8>>, String> {
+		Ok(Cursor::new(self.read_entry(name)?))
+	}
+
+	/// Writes a copy of this archive with some files replaced.
+	///
+	/// The original entry order is kept. Replacements that don't match an existing
+	/// entry are appended at the end.
+	pub fn repack<W: Write + Seek>(&mut self, writer: W, mut replacements: Vec<DatWriteEntry>) -> Result<(), String> {
+		let mut entries = Vec::with_capacity(self.entries.len() + replacements.len());
+		for i in 0..self.entries.len() {
+			let name = self.entries[i].name.clone();
+			let replacement = replacements.iter().position(|r| r.name.eq_ignore_ascii_case(&name));
+			let data = match replacement {
+				Some(replacement) => replacements.remove(replacement).data,
+				None => self.read_entry_at(i)?,
+			};
+			entries.push(DatWriteEntry { name, data });
+		}
+		entries.append(&mut replacements);
+		write_dat(writer, &entries)
+	}
+}
+
+pub fn write_dat_file<P: AsRef<Path>>(path: P, entries: &[DatWriteEntry]) -> Result<(), String> {
+	let file = File::create(path).map_err(|e| e.to_string())?;
+	write_dat(BufWriter::new(file), entries)
+}
+
+pub fn write_dat<W: Write + Seek>(writer: W, entries: &[DatWriteEntry]) -> Result<(), String> {
+	let mut writer = ByteWriter::new(writer);
+	let file_count = entries.len() as u32;
+	let names = entries.iter().map(|e| e.name.as_str()).collect::<Vec<_>>();
+	let name_length = names.iter().map(|n| n.len()).max().unwrap_or(0) + 1;
+	let hash_data = DatHashData::generate(&names)?;
+
+	let offset_file_offsets = DAT_HEADER_SIZE;
+	let offset_extensions = offset_file_offsets + file_count * 4;
+	let offset_names = offset_extensions + file_count * 4;
+	let offset_sizes = align(offset_names + 4 + file_count * name_length as u32, 4);
+	let offset_hash_data = offset_sizes + file_count * 4;
+
+	writer.write_string("DAT", 4)?;
+	writer.write_u32(file_count)?;
+	writer.write_u32(offset_file_offsets)?;
+	writer.write_u32(offset_extensions)?;
+	writer.write_u32(offset_names)?;
+	writer.write_u32(offset_sizes)?;
+	writer.write_u32(offset_hash_data)?;
+	writer.write_u32(0)?;
+
+	// file offsets are filled in after the data is written
+	writer.write_zeros(file_count as usize * 4)?;
+
+	for name in &names {
+		let extension = Path::new(name).extension()
+			.and_then(|e| e.to_str())
+			.unwrap_or("");
+		writer.write_string(extension, 4)?;
+	}
+
+	writer.write_u32(name_length as u32)?;
+	for name in &names {
+		writer.write_string(name, name_length)?;
+	}
+	writer.align(4)?;
+
+	for entry in entries {
+		writer.write_u32(entry.data.len() as u32)?;
+	}
+
+	hash_data.write(&mut writer)?;
+
+	let mut file_offsets = Vec::with_capacity(entries.len());
+	for entry in entries {
+		writer.align(DAT_FILE_ALIGNMENT)?;
+		file_offsets.push(writer.position()? as u32);
+		writer.write(&entry.data)?;
+	}
+	writer.align(DAT_FILE_ALIGNMENT)?;
+
+	writer.seek(offset_file_offsets as u64)?;
+	for offset in file_offsets {
+		writer.write_u32(offset)?;
+	}
+
+	writer.into_inner().flush().map_err(|e| e.to_string())
+}
+
+fn align(value: u32, alignment: u32) -> u32 {
+	value.div_ceil(alignment) * alignment
+}
+
+pub fn dat_name_hash(name: &str) -> u32 {
+	crc32(name.to_lowercase().as_bytes()) & 0x7FFFFFFF
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+	let mut crc = 0xFFFFFFFFu32;
+	for byte in bytes {
+		crc ^= *byte as u32;
+		for _ in 0..8 {
+			let mask = (crc & 1).wrapping_neg();
+			crc = (crc >> 1) ^ (0xEDB88320 & mask);
+		}
+	}
+	!crc
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn test_entries() -> Vec<DatWriteEntry> {
+		(0..37).map(|i| DatWriteEntry {
+			name: format!("file{i}.wmb"),
+			data: vec![i as u8; i * 3 + 1],
+		}).collect()
+	}
+
+	#[test]
+	fn repack_without_replacements_is_identical() {
+		let mut original = Cursor::new(Vec::new());
+		write_dat(&mut original, &test_entries()).unwrap();
+		let original = original.into_inner();
+
+		let mut repacked = Cursor::new(Vec::new());
+		Dat::from_bytes(&original).unwrap().repack(&mut repacked, Vec::new()).unwrap();
+		assert_eq!(repacked.into_inner(), original);
+	}
+
+	#[test]
+	fn repack_with_replacements() {
+		let mut original = Cursor::new(Vec::new());
+		write_dat(&mut original, &test_entries()).unwrap();
+		let original = original.into_inner();
+
+		let replacements = vec![
+			DatWriteEntry { name: "FILE3.wmb".to_string(), data: vec![9; 100] },
+			DatWriteEntry { name: "new.wta".to_string(), data: vec![1] },
+		];
+		let mut repacked = Cursor::new(Vec::new());
+		Dat::from_bytes(&original).unwrap().repack(&mut repacked, replacements).unwrap();
+		let repacked = repacked.into_inner();
+
+		let mut dat = Dat::from_bytes(&repacked).unwrap();
+		assert_eq!(dat.entries().len(), 38);
+		assert_eq!(dat.entries()[3].name, "file3.wmb");
+		assert_eq!(dat.read_entry("file3.wmb").unwrap(), vec![9; 100]);
+		assert_eq!(dat.read_entry("file36.wmb").unwrap(), vec![36; 36 * 3 + 1]);
+		assert_eq!(dat.entries()[37].name, "new.wta");
+		assert_eq!(dat.read_entry("new.wta").unwrap(), vec![1]);
+	}
+
+	#[test]
+	fn too_many_files_for_hash_table() {
+		let names = (0..40000).map(|i| format!("{i}.bin")).collect::<Vec<_>>();
+		let names = names.iter().map(String::as_str).collect::<Vec<_>>();
+		assert!(DatHashData::generate(&names).is_err());
+		assert!(DatHashData::generate(&names[..1000]).is_ok());
+	}
+}
@@ -1,5 +1,9 @@
+pub mod asset_index;
 pub mod byte_stream;
 pub mod c_exports;
+pub mod cpk;
+pub mod dat;
+pub mod load_options;
 pub mod mesh_data;
 pub mod mesh_renderer;
 pub mod wmb;
@@ -7,4 +11,7 @@ pub mod wmb_na;
 pub mod wmb_mgrr;
 pub mod wmb_scr;
 pub mod scr_mgrr;
+pub mod tangent_space;
+pub mod texture_cache;
+pub mod vfs;
 pub mod wta_wtp;
\ No newline at end of file
@@ -0,0 +1,135 @@
+/// Settings that affect how models are turned into scene data
+#[derive(Clone)]
+pub struct LoadOptions {
+	pub visibility_rules: VisibilityRules,
+	/// Only reads the bounds of each mesh, without decoding vertices or textures
+	pub bounds_only: bool,
+	/// Which of the four batch groups of WMB4 meshes to load. Group 0 holds the regular geometry,
+	/// the others LOD and shadow or special pass variants.
+	pub wmb4_batch_groups: [bool; 4],
+}
+
+impl Default for LoadOptions {
+	fn default() -> Self {
+		LoadOptions {
+			visibility_rules: VisibilityRules::default(),
+			bounds_only: false,
+			wmb4_batch_groups: [true; 4],
+		}
+	}
+}
+
+/// Decides which meshes are visible by default. Meshes start out visible and the last matching rule wins.
+#[derive(Clone)]
+pub struct VisibilityRules {
+	pub rules: Vec<VisibilityRule>,
+}
+
+/// Game a model belongs to, told apart by the WMB version
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Game {
+	/// NieR:Automata, WMB3
+	Na,
+	/// Metal Gear Rising: Revengeance, WMB4
+	Mgrr,
+}
+
+/// Name patterns support `*` and `?` wildcards. `None` matches anything.
+/// WMB4 materials have no name, their shader name is matched instead.
+#[derive(Clone)]
+pub struct VisibilityRule {
+	pub game: Option<Game>,
+	pub model_name: Option<String>,
+	pub mesh_name: Option<String>,
+	pub lod: Option<usize>,
+	pub material_name: Option<String>,
+	pub visible: bool,
+}
+
+impl VisibilityRules {
+	/// Shows every mesh
+	pub fn show_all() -> Self {
+		VisibilityRules { rules: Vec::new() }
+	}
+
+	pub fn is_visible(&self, game: Game, model_name: &str, mesh_name: &str, lod: Option<usize>, material_name: &str) -> bool {
+		self.rules.iter()
+			.rev()
+			.find(|rule| rule.matches(game, model_name, mesh_name, lod, material_name))
+			.is_none_or(|rule| rule.visible)
+	}
+}
+
+impl Default for VisibilityRules {
+	/// Hides alternative costume and damage state meshes of the NA player models and MGRR decal meshes
+	fn default() -> Self {
+		let mut rules = Vec::new();
+		for model_name in ["*pl000*", "*pl010*", "*pl020*"] {
+			for mesh_name in ["*Armor*", "*serious*", "*Broken*", "*DLC*"] {
+				rules.push(VisibilityRule::hide(Game::Na, Some(model_name), Some(mesh_name)));
+			}
+		}
+		rules.push(VisibilityRule::hide(Game::Mgrr, None, Some("*_DEC")));
+		VisibilityRules { rules }
+	}
+}
+
+impl VisibilityRule {
+	fn hide(game: Game, model_name: Option<&str>, mesh_name: Option<&str>) -> Self {
+		VisibilityRule {
+			game: Some(game),
+			model_name: model_name.map(str::to_string),
+			mesh_name: mesh_name.map(str::to_string),
+			lod: None,
+			material_name: None,
+			visible: false,
+		}
+	}
+
+	pub fn matches(&self, game: Game, model_name: &str, mesh_name: &str, lod: Option<usize>, material_name: &str) -> bool {
+		let matches_pattern = |pattern: &Option<String>, value: &str| pattern.as_ref().is_none_or(|p| glob_match(p, value));
+		self.game.is_none_or(|g| g == game)
+			&& matches_pattern(&self.model_name, model_name)
+			&& matches_pattern(&self.mesh_name, mesh_name)
+			&& self.lod.is_none_or(|l| lod == Some(l))
+			&& matches_pattern(&self.material_name, material_name)
+	}
+}
+
+fn glob_match(pattern: &str, value: &str) -> bool {
+	let pattern = pattern.chars().collect::<Vec<_>>();
+	let value = value.chars().collect::<Vec<_>>();
+	let (mut p, mut v) = (0, 0);
+	// position of the last `*` and the value position it was matched at
+	let mut backtrack: Option<(usize, usize)> = None;
+	while v < value.len() {
+		if p < pattern.len() && (pattern[p] == '?' || pattern[p] == value[v]) {
+			p += 1;
+			v += 1;
+		} else if p < pattern.len() && pattern[p] == '*' {
+			backtrack = Some((p, v));
+			p += 1;
+		} else if let Some((star_p, star_v)) = backtrack {
+			p = star_p + 1;
+			v = star_v + 1;
+			backtrack = Some((star_p, star_v + 1));
+		} else {
+			return false;
+		}
+	}
+	pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn default_rules_only_apply_to_their_game() {
+		let rules = VisibilityRules::default();
+		assert!(!rules.is_visible(Game::Na, "pl0000.wmb", "Armor_1", Some(0), "mat"));
+		assert!(rules.is_visible(Game::Mgrr, "pl0010.wmb", "Armor_1", None, "mat"));
+		assert!(!rules.is_visible(Game::Mgrr, "em0010.wmb", "body_DEC", None, ""));
+		assert!(rules.is_visible(Game::Na, "em0010.wmb", "body_DEC", Some(0), "mat"));
+	}
+}
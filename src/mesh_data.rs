@@ -1,12 +1,29 @@
-use std::collections::HashMap;
+use std::{collections::{hash_map::DefaultHasher, BTreeSet, HashMap}, hash::{Hash, Hasher}, io::Cursor};
 
-use three_d::{Matrix4, Vector2, Vector3, Vector4};
+use image::{codecs::dds::DdsDecoder, ImageDecoder, ImageReader};
+use three_d::{Matrix4, SquareMatrix, Vector2, Vector3, Vector4};
+
+use crate::tangent_space::{fallback_tangent, generate_corner_normals, generate_corner_tangents, is_degenerate_tangent, split_vertices, DEFAULT_SMOOTHING_ANGLE};
 
 
 #[derive(Default)]
 pub struct SceneData {
 	pub meshes: Vec<MeshData>,
-	pub textures: HashMap<u32, TextureData>,
+	pub textures: HashMap<u32, EncodedTextureData>,
+	pub texture_source: String,
+	pub skeletons: Vec<SkeletonData>,
+	pub lod_groups: Vec<LodGroupData>,
+	pub materials: Vec<MaterialData>,
+	pub col_trees: Vec<ColTreeData>,
+	/// Problems that didn't stop loading, like skin weights of bones that couldn't be resolved
+	pub warnings: Vec<String>,
+}
+
+impl SceneData {
+	/// Bounds of all meshes after applying their transforms
+	pub fn bounds(&self) -> Option<BoundsData> {
+		BoundsData::union(self.meshes.iter().filter_map(|m| m.world_bounds()))
+	}
 }
 
 pub struct MeshData {
@@ -16,12 +33,395 @@ pub struct MeshData {
 	pub tangents: Option<Vec<Vector4<f32>>>,
 	pub normals: Option<Vec<Vector3<f32>>>,
 	pub uv: Vec<Vector2<f32>>,
+	pub uv2: Option<Vec<Vector2<f32>>>,
+	pub uv3: Option<Vec<Vector2<f32>>>,
+	pub uv4: Option<Vec<Vector2<f32>>>,
+	pub uv5: Option<Vec<Vector2<f32>>>,
+	pub colors: Option<Vec<[u8; 4]>>,
 	pub albedo_texture_id: Option<u32>,
 	pub normal_texture_id: Option<u32>,
 	pub mask_texture_id: Option<u32>,
+	/// Sampled with `uv2`
+	pub lightmap_texture_id: Option<u32>,
 	pub transform: Matrix4<f32>,
 	pub uses_transparency: bool,
 	pub should_be_visible: bool,
+	/// Index into `SceneData::skeletons`
+	pub skeleton_index: Option<usize>,
+	/// Per vertex indices into the bones of the skeleton
+	pub bone_indices: Option<Vec<[u16; 4]>>,
+	pub bone_weights: Option<Vec<[f32; 4]>>,
+	pub lod: Option<MeshLod>,
+	/// Index into `SceneData::materials`
+	pub material_index: Option<usize>,
+	/// Bounds stored in the model file, before applying `transform`
+	pub bounds: Option<BoundsData>,
+	/// WMB4 batch groups that list the mesh
+	pub batch_groups: Vec<usize>,
+}
+
+impl MeshData {
+	/// A visible mesh without any geometry
+	pub fn new(name: String) -> Self {
+		MeshData {
+			name,
+			vertices: Vec::new(),
+			indexes: Vec::new(),
+			tangents: None,
+			normals: None,
+			uv: Vec::new(),
+			uv2: None,
+			uv3: None,
+			uv4: None,
+			uv5: None,
+			colors: None,
+			albedo_texture_id: None,
+			normal_texture_id: None,
+			mask_texture_id: None,
+			lightmap_texture_id: None,
+			transform: Matrix4::identity(),
+			uses_transparency: false,
+			should_be_visible: true,
+			skeleton_index: None,
+			bone_indices: None,
+			bone_weights: None,
+			lod: None,
+			material_index: None,
+			bounds: None,
+			batch_groups: Vec::new(),
+		}
+	}
+
+	/// Generates normals if there are none and replaces missing or degenerate tangents.
+	/// Vertices on hard edges or UV mirror seams are split so that each side gets its own normal and tangent.
+	pub fn generate_missing_normals_and_tangents(&mut self) {
+		if self.vertices.is_empty() {
+			return;
+		}
+		if self.normals.is_none() {
+			let corner_normals = generate_corner_normals(&self.vertices, &self.indexes, DEFAULT_SMOOTHING_ANGLE);
+			let mut normals = vec![Vector3::unit_z(); self.vertices.len()];
+			let sources = split_vertices(&mut normals, &mut self.indexes, &corner_normals);
+			self.duplicate_vertices(&sources);
+			self.normals = Some(normals);
+		}
+		let Some(normals) = &self.normals else {
+			return;
+		};
+		let needs_tangents = match &self.tangents {
+			Some(tangents) => tangents.iter().zip(normals.iter()).any(|(t, n)| is_degenerate_tangent(*t, *n)),
+			None => true,
+		};
+		if !needs_tangents || self.uv.len() != self.vertices.len() || normals.len() != self.vertices.len() {
+			return;
+		}
+
+		let existing = self.tangents.take().unwrap_or_default();
+		let is_valid = |vertex_i: usize| existing.get(vertex_i).is_some_and(|t| !is_degenerate_tangent(*t, normals[vertex_i]));
+		// if MikkTSpace fails the fallback tangents are good enough for a preview
+		let generated = generate_corner_tangents(&self.vertices, normals, &self.uv, &self.indexes)
+			.unwrap_or_else(|_| self.indexes.iter().map(|&i| fallback_tangent(normals[i as usize])).collect());
+		// tangents from the file are kept, only the corners of vertices without a usable one get a generated tangent
+		let corner_tangents = generated.into_iter()
+			.zip(&self.indexes)
+			.map(|(generated, &i)| if is_valid(i as usize) { existing[i as usize] } else { generated })
+			.collect::<Vec<_>>();
+		let mut tangents = (0..self.vertices.len())
+			.map(|i| if is_valid(i) { existing[i] } else { fallback_tangent(normals[i]) })
+			.collect();
+		let sources = split_vertices(&mut tangents, &mut self.indexes, &corner_tangents);
+		self.duplicate_vertices(&sources);
+		self.tangents = Some(tangents);
+	}
+
+	/// Appends copies of the vertices at `sources` with all their attributes
+	fn duplicate_vertices(&mut self, sources: &[usize]) {
+		fn duplicate<T: Copy>(values: &mut Vec<T>, sources: &[usize]) {
+			let copies = sources.iter().filter_map(|&i| values.get(i).copied()).collect::<Vec<_>>();
+			values.extend(copies);
+		}
+
+		if sources.is_empty() {
+			return;
+		}
+		duplicate(&mut self.vertices, sources);
+		duplicate(&mut self.uv, sources);
+		for values in [&mut self.uv2, &mut self.uv3, &mut self.uv4, &mut self.uv5].into_iter().flatten() {
+			duplicate(values, sources);
+		}
+		if let Some(normals) = &mut self.normals {
+			duplicate(normals, sources);
+		}
+		if let Some(tangents) = &mut self.tangents {
+			duplicate(tangents, sources);
+		}
+		if let Some(colors) = &mut self.colors {
+			duplicate(colors, sources);
+		}
+		if let Some(bone_indices) = &mut self.bone_indices {
+			duplicate(bone_indices, sources);
+		}
+		if let Some(bone_weights) = &mut self.bone_weights {
+			duplicate(bone_weights, sources);
+		}
+	}
+
+	/// Bounds after applying `transform`. Falls back to the vertices when the file has no bounds.
+	pub fn world_bounds(&self) -> Option<BoundsData> {
+		let bounds = self.bounds.or_else(|| BoundsData::from_positions(&self.vertices))?;
+		Some(bounds.transformed(&self.transform))
+	}
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BoundsData {
+	pub min: Vector3<f32>,
+	pub max: Vector3<f32>,
+}
+
+impl BoundsData {
+	pub fn from_center_extents(center: Vector3<f32>, extents: Vector3<f32>) -> Self {
+		BoundsData {
+			min: center - extents,
+			max: center + extents,
+		}
+	}
+
+	pub fn from_positions(positions: &[Vector3<f32>]) -> Option<Self> {
+		let (first, rest) = positions.split_first()?;
+		let mut bounds = BoundsData { min: *first, max: *first };
+		for position in rest {
+			bounds.expand(*position);
+		}
+		Some(bounds)
+	}
+
+	pub fn union(bounds: impl IntoIterator<Item = BoundsData>) -> Option<Self> {
+		bounds.into_iter().reduce(|mut a, b| {
+			a.expand(b.min);
+			a.expand(b.max);
+			a
+		})
+	}
+
+	pub fn expand(&mut self, position: Vector3<f32>) {
+		for i in 0..3 {
+			self.min[i] = self.min[i].min(position[i]);
+			self.max[i] = self.max[i].max(position[i]);
+		}
+	}
+
+	pub fn center(&self) -> Vector3<f32> {
+		(self.min + self.max) / 2.0
+	}
+
+	pub fn extents(&self) -> Vector3<f32> {
+		(self.max - self.min) / 2.0
+	}
+
+	/// Axis aligned bounds of the transformed corners
+	pub fn transformed(&self, transform: &Matrix4<f32>) -> Self {
+		let corners = (0..8).map(|i| {
+			let corner = Vector3::new(
+				if i & 1 == 0 { self.min.x } else { self.max.x },
+				if i & 2 == 0 { self.min.y } else { self.max.y },
+				if i & 4 == 0 { self.min.z } else { self.max.z },
+			);
+			(transform * corner.extend(1.0)).truncate()
+		}).collect::<Vec<_>>();
+		BoundsData::from_positions(&corners).unwrap()
+	}
+}
+
+pub struct SkeletonData {
+	pub bones: Vec<BoneData>,
+}
+
+pub struct BoneData {
+	/// Bone id used by the game, e.g. in motion files
+	pub id: i16,
+	pub parent_index: Option<usize>,
+	/// Bind pose relative to the parent bone
+	pub local_transform: Matrix4<f32>,
+	/// Bind pose in model space
+	pub world_transform: Matrix4<f32>,
+	pub inverse_bind_matrix: Matrix4<f32>,
+}
+
+impl SkeletonData {
+	pub fn find_bone(&self, id: i16) -> Option<usize> {
+		self.bones.iter().position(|b| b.id == id)
+	}
+
+	pub fn children(&self, bone_index: usize) -> impl Iterator<Item = usize> + '_ {
+		self.bones.iter()
+			.enumerate()
+			.filter(move |(_, b)| b.parent_index == Some(bone_index))
+			.map(|(i, _)| i)
+	}
+}
+
+pub struct MaterialData {
+	pub name: String,
+	pub shader_name: String,
+	pub technique_name: String,
+	pub textures: Vec<MaterialTextureData>,
+	pub parameter_groups: Vec<MaterialParameterGroupData>,
+	pub variables: Vec<MaterialVariableData>,
+	pub shader_name_parts: ShaderNameData,
+}
+
+/// A shader name like `skn03_xxx` split into family (`skn`), variant (`03`) and suffix (`xxx`)
+pub struct ShaderNameData {
+	pub family: String,
+	pub variant: String,
+	pub suffix: String,
+}
+
+impl ShaderNameData {
+	pub fn parse(shader_name: &str) -> Self {
+		let shader_name = shader_name.trim_end_matches('\0');
+		let family_len = shader_name.find(|c: char| c.is_ascii_digit()).unwrap_or(shader_name.len());
+		let (family, rest) = shader_name.split_at(family_len);
+		let variant_len = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+		let (variant, suffix) = rest.split_at(variant_len);
+		ShaderNameData {
+			family: family.to_string(),
+			variant: variant.to_string(),
+			suffix: suffix.trim_start_matches('_').to_string(),
+		}
+	}
+}
+
+pub struct MaterialTextureData {
+	/// Shader sampler name, e.g. `g_AlbedoMap`, or the usage of WMB4 textures, e.g. `albedo`
+	pub slot: String,
+	pub id: u32,
+	/// WMB4 texture flags, which decide how the texture is used
+	pub flags: Option<u32>,
+}
+
+pub struct MaterialParameterGroupData {
+	pub index: i32,
+	pub parameters: Vec<f32>,
+}
+
+pub struct MaterialVariableData {
+	pub name: String,
+	pub value: f32,
+}
+
+impl MaterialData {
+	pub fn texture(&self, slot: &str) -> Option<u32> {
+		self.textures.iter().find(|t| t.slot == slot).map(|t| t.id)
+	}
+
+	pub fn variable(&self, name: &str) -> Option<f32> {
+		self.variables.iter().find(|v| v.name == name).map(|v| v.value)
+	}
+}
+
+/// Bounding volume hierarchy the game uses to cull the batches of a model
+pub struct ColTreeData {
+	pub nodes: Vec<ColTreeNodeData>,
+}
+
+pub struct ColTreeNodeData {
+	pub min: Vector3<f32>,
+	pub max: Vector3<f32>,
+	pub left: Option<usize>,
+	pub right: Option<usize>,
+	/// Indices into `SceneData::meshes` of the batches assigned to this node
+	pub mesh_indices: Vec<usize>,
+}
+
+impl ColTreeNodeData {
+	pub fn contains_point(&self, point: Vector3<f32>) -> bool {
+		(0..3).all(|i| point[i] >= self.min[i] && point[i] <= self.max[i])
+	}
+
+	pub fn intersects_box(&self, min: Vector3<f32>, max: Vector3<f32>) -> bool {
+		(0..3).all(|i| min[i] <= self.max[i] && max[i] >= self.min[i])
+	}
+
+	pub fn intersects_ray(&self, origin: Vector3<f32>, direction: Vector3<f32>) -> bool {
+		let mut t_min = 0.0_f32;
+		let mut t_max = f32::INFINITY;
+		for i in 0..3 {
+			if direction[i] == 0.0 {
+				if origin[i] < self.min[i] || origin[i] > self.max[i] {
+					return false;
+				}
+				continue;
+			}
+			let t1 = (self.min[i] - origin[i]) / direction[i];
+			let t2 = (self.max[i] - origin[i]) / direction[i];
+			t_min = t_min.max(t1.min(t2));
+			t_max = t_max.min(t1.max(t2));
+		}
+		t_min <= t_max
+	}
+}
+
+impl ColTreeData {
+	/// Nodes that aren't the child of any other node
+	pub fn roots(&self) -> Vec<usize> {
+		let children = self.nodes.iter()
+			.flat_map(|n| n.left.into_iter().chain(n.right))
+			.collect::<BTreeSet<_>>();
+		(0..self.nodes.len()).filter(|i| !children.contains(i)).collect()
+	}
+
+	pub fn query_point(&self, point: Vector3<f32>) -> Vec<usize> {
+		self.query(|node| node.contains_point(point))
+	}
+
+	pub fn query_box(&self, min: Vector3<f32>, max: Vector3<f32>) -> Vec<usize> {
+		self.query(|node| node.intersects_box(min, max))
+	}
+
+	pub fn query_ray(&self, origin: Vector3<f32>, direction: Vector3<f32>) -> Vec<usize> {
+		self.query(|node| node.intersects_ray(origin, direction))
+	}
+
+	/// Mesh indices of all nodes that pass `test` and whose parents pass it as well
+	fn query(&self, test: impl Fn(&ColTreeNodeData) -> bool) -> Vec<usize> {
+		let mut mesh_indices = BTreeSet::new();
+		let mut visited = vec![false; self.nodes.len()];
+		let mut stack = self.roots();
+		while let Some(node_i) = stack.pop() {
+			if node_i >= self.nodes.len() || visited[node_i] {
+				continue;
+			}
+			visited[node_i] = true;
+			let node = &self.nodes[node_i];
+			if !test(node) {
+				continue;
+			}
+			mesh_indices.extend(node.mesh_indices.iter().copied());
+			stack.extend(node.left.into_iter().chain(node.right));
+		}
+		mesh_indices.into_iter().collect()
+	}
+}
+
+/// The LODs of one model
+pub struct LodGroupData {
+	pub name: String,
+	pub lods: Vec<LodData>,
+}
+
+pub struct LodData {
+	pub name: String,
+	pub level: i32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct MeshLod {
+	/// Index into `SceneData::lod_groups`
+	pub group_index: usize,
+	/// Index into `LodGroupData::lods`
+	pub lod_index: usize,
 }
 
 #[derive(Clone)]
@@ -30,3 +430,40 @@ pub struct TextureData {
 	pub width: u32,
 	pub height: u32,
 }
+
+/// A DDS or PNG file as stored in the WTP. It's only decoded when it isn't in the GPU texture cache yet.
+#[derive(Clone)]
+pub struct EncodedTextureData {
+	pub bytes: Vec<u8>,
+	/// Hash of `bytes`, so that swapped textures with the same id don't hit the cache
+	pub hash: u64,
+}
+
+impl EncodedTextureData {
+	pub fn new(bytes: Vec<u8>) -> Self {
+		let mut hasher = DefaultHasher::new();
+		bytes.hash(&mut hasher);
+		EncodedTextureData { hash: hasher.finish(), bytes }
+	}
+
+	/// Decodes to RGBA8
+	pub fn decode(&self) -> Result<TextureData, String> {
+		const DDS_MAGIC: [u8; 4] = [0x44, 0x44, 0x53, 0x20];
+		const PNG_MAGIC: [u8; 4] = [0x89, 0x50, 0x4E, 0x47];
+		let bytes = self.bytes.as_slice();
+		if bytes.len() > 4 && bytes[0..4] == DDS_MAGIC {
+			let dds_decoder = DdsDecoder::new(bytes).map_err(|e| e.to_string())?;
+			let (width, height) = dds_decoder.dimensions();
+			let mut buffer = vec![0; dds_decoder.total_bytes() as usize];
+			dds_decoder.read_image(&mut buffer).map_err(|e| e.to_string())?;
+			Ok(TextureData { bytes: buffer, width, height })
+		} else if bytes.len() > 4 && bytes[0..4] == PNG_MAGIC {
+			let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format().map_err(|e| e.to_string())?;
+			let image = reader.decode().map_err(|e| e.to_string())?.to_rgba8();
+			let (width, height) = image.dimensions();
+			Ok(TextureData { bytes: image.into_raw(), width, height })
+		} else {
+			Err("Unknown texture format".to_string())
+		}
+	}
+}
@@ -1,14 +1,26 @@
-use std::{collections::HashMap, rc::Rc, sync::Arc, time::{Duration, Instant}};
+use std::{cell::RefCell, collections::HashMap, ops::Deref, rc::Rc, sync::Arc, time::{Duration, Instant}};
 
 // use rand::Rng;
 use winit::{event_loop::EventLoop, window::WindowBuilder};
-use crate::mesh_data::SceneData;
+use crate::{mesh_data::{EncodedTextureData, MeshData, MeshLod, SceneData}, texture_cache::{TextureCache, TextureKey}};
 use three_d::*;
 use three_d::window::WindowedContext;
 
 
+pub struct RenderContext {
+	context: WindowedContext,
+	pub texture_cache: RefCell<TextureCache>,
+}
+
+impl Deref for RenderContext {
+	type Target = WindowedContext;
+
+	fn deref(&self) -> &Self::Target {
+		&self.context
+	}
+}
 
-pub fn new_context() -> Result<WindowedContext, String> {
+pub fn new_context() -> Result<RenderContext, String> {
 	let event_loop = EventLoop::new();
 	let window = WindowBuilder::new()
 		.with_visible(false)
@@ -22,7 +34,10 @@ pub fn new_context() -> Result<WindowedContext, String> {
 		},
 	)
 		.map_err(|e| e.to_string())?;
-	Ok(context)
+	Ok(RenderContext {
+		context,
+		texture_cache: RefCell::new(TextureCache::default()),
+	})
 }
 
 type Mat = DeferredPhysicalMaterial;
@@ -30,13 +45,39 @@ type Mat = DeferredPhysicalMaterial;
 struct ModelInfo {
 	model: Gm<Mesh, Mat>,
 	transform: Matrix4<f32>,
+	visible: bool,
+	lod: Option<MeshLod>,
+	/// Bounds after applying `transform`, used for culling
+	bounding_box: AxisAlignedBoundingBox,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LodSelection {
+	Fixed(usize),
+	/// Picks a LOD based on the size of the model on screen
+	Auto,
+}
+
+struct LodGroupState {
+	lod_count: usize,
+	selection: LodSelection,
+	active_lod: usize,
+	bounding_box: AxisAlignedBoundingBox,
 }
 
 pub struct RenderState {
-	context: Rc<WindowedContext>,
+	context: Rc<RenderContext>,
 	camera: Camera,
 	models: HashMap<u32, ModelInfo>,
+	texture_keys: Vec<TextureKey>,
+	lod_groups: Vec<LodGroupState>,
+	col_tree_boxes: Vec<Gm<BoundingBox, ColorMaterial>>,
+	show_col_tree: bool,
+	/// Mesh names by model id, including meshes without geometry
+	model_names: Vec<String>,
+	/// `id,name,shown` per line, where shown also takes the active LOD into account
 	pub model_states: String,
+	pub lod_states: String,
 	ambient_light: AmbientLight,
 	directional_light: DirectionalLight,
 	pitch: f32,
@@ -44,29 +85,55 @@ pub struct RenderState {
 }
 
 impl RenderState {
-	pub fn new(context: Rc<WindowedContext>, width: u32, height: u32, scene_data: SceneData) -> Result<Self, String> {
+	pub fn new(context: Rc<RenderContext>, width: u32, height: u32, scene_data: SceneData) -> Result<Self, String> {
 		// let t1 = Instant::now();
 		// let mut tex_times: Vec<Duration> = Vec::new();
 		// let mut mat_times: Vec<Duration> = Vec::new();
 		// let mut gm_times: Vec<Duration> = Vec::new();
 		let mut models = HashMap::new();
-		let mut model_states = String::new();
+		let mut model_names = Vec::with_capacity(scene_data.meshes.len());
 		let mut bounding_box = AxisAlignedBoundingBox::EMPTY;
-		let mut textures: HashMap<u32, Arc<Texture2D>> = HashMap::new();
+		let mut visible_bounding_box = AxisAlignedBoundingBox::EMPTY;
+		let mut texture_keys: Vec<TextureKey> = Vec::new();
+		let mut lod_groups = scene_data.lod_groups.iter()
+			.map(|group| LodGroupState {
+				lod_count: group.lods.len(),
+				selection: LodSelection::Fixed(0),
+				active_lod: 0,
+				bounding_box: AxisAlignedBoundingBox::EMPTY,
+			})
+			.collect::<Vec<_>>();
 		for (i, mesh_data) in scene_data.meshes.into_iter().enumerate() {
-			bounding_box.expand(mesh_data.vertices.as_slice());
+			let mesh_bounding_box = mesh_data.world_bounds()
+				.map_or(AxisAlignedBoundingBox::EMPTY, |b| AxisAlignedBoundingBox::new_with_positions(&[b.min, b.max]));
+			bounding_box.expand_with_aabb(mesh_bounding_box);
+			if mesh_data.should_be_visible {
+				visible_bounding_box.expand_with_aabb(mesh_bounding_box);
+			}
+			if let Some(group) = mesh_data.lod.and_then(|lod| lod_groups.get_mut(lod.group_index)) {
+				group.bounding_box.expand_with_aabb(mesh_bounding_box);
+			}
+			model_names.push(mesh_data.name.clone());
+			// loaded with `LoadOptions::bounds_only`
+			if mesh_data.vertices.is_empty() {
+				continue;
+			}
+			let lightmap = mesh_data.lightmap_texture_id
+				.and_then(|id| lookup_texture_data(&context, id, &mut texture_keys, &scene_data.texture_source, &scene_data.textures));
+			let colors = vertex_colors(&mesh_data, lightmap.as_deref());
 			let cpu_mesh = CpuMesh {
 				positions: Positions::F32(mesh_data.vertices),
 				indices: Indices::U32(mesh_data.indexes),
 				normals: mesh_data.normals,
 				tangents: mesh_data.tangents,
 				uvs: Some(mesh_data.uv.iter().map(|v| Vec2::new(v.x, v.y)).collect()),
+				colors,
 				..Default::default()
 			};
 		
 			// let sub_t1 = Instant::now();
-			let albedo_texture = lookup_texture(&context, mesh_data.albedo_texture_id, &mut textures, &scene_data.textures);
-			let normal_texture = lookup_texture(&context, mesh_data.normal_texture_id, &mut textures, &scene_data.textures);
+			let albedo_texture = lookup_texture(&context, mesh_data.albedo_texture_id, &mut texture_keys, &scene_data.texture_source, &scene_data.textures);
+			let normal_texture = lookup_texture(&context, mesh_data.normal_texture_id, &mut texture_keys, &scene_data.texture_source, &scene_data.textures);
 			// tex_times.push(sub_t1.elapsed());
 			// let sub_t1 = Instant::now();
 			let material = Mat {
@@ -79,23 +146,38 @@ impl RenderState {
 			// mat_times.push(sub_t1.elapsed());
 		
 			// let sub_t1 = Instant::now();
-			let mut model = Gm::new(Mesh::new(&context, &cpu_mesh), material);
-			if mesh_data.should_be_visible {
-				model.set_transformation(Matrix4::from(mesh_data.transform));
-			}
-			else {
-				model.set_transformation(Matrix4::from_scale(0.0));
-			}
-			let model_info = ModelInfo {
+			let model = Gm::new(Mesh::new(&context, &cpu_mesh), material);
+			let mut model_info = ModelInfo {
 				model,
-				transform: Matrix4::from(mesh_data.transform),
+				transform: mesh_data.transform,
+				visible: mesh_data.should_be_visible,
+				lod: mesh_data.lod,
+				bounding_box: mesh_bounding_box,
 			};
+			model_info.update_transformation(&lod_groups);
 			models.insert(i as u32, model_info);
 			// gm_times.push(sub_t1.elapsed());
-
-			model_states.push_str(&format!("{},{},{}\n", i, mesh_data.name, mesh_data.should_be_visible));
 		}
-		model_states.push('\0');
+		let mut lod_states = String::new();
+		for (group_i, group) in scene_data.lod_groups.iter().enumerate() {
+			for (lod_i, lod) in group.lods.iter().enumerate() {
+				lod_states.push_str(&format!("{},{},{},{},{}\n", group_i, group.name, lod_i, lod.name, lod.level));
+			}
+		}
+		lod_states.push('\0');
+		let col_tree_boxes = scene_data.col_trees.iter()
+			.flat_map(|col_tree| col_tree.nodes.iter())
+			.map(|node| Gm::new(
+				BoundingBox::new(&context, AxisAlignedBoundingBox::new_with_positions(&[node.min, node.max])),
+				ColorMaterial {
+					color: Srgba::new(255, 160, 0, 255),
+					..Default::default()
+				},
+			))
+			.collect();
+		if !visible_bounding_box.is_empty() {
+			bounding_box = visible_bounding_box;
+		}
 		if bounding_box.is_empty() {
 			bounding_box = AxisAlignedBoundingBox::new_with_positions(&[vec3(-1.0, -1.0, -1.0), vec3(1.0, 1.0, 1.0)]);
 		}
@@ -125,13 +207,20 @@ impl RenderState {
 			context,
 			camera,
 			models,
-			model_states,
+			texture_keys,
+			lod_groups,
+			col_tree_boxes,
+			show_col_tree: false,
+			model_names,
+			model_states: String::new(),
+			lod_states,
 			ambient_light,
 			directional_light,
 			pitch: 0.0,
 			yaw: 0.0,
 		};
 		render_state.add_camera_rotation((-20.0_f32).to_radians(), (30.0_f32).to_radians());
+		render_state.update_model_states();
 
 		Ok(render_state)
 	}
@@ -162,9 +251,20 @@ impl RenderState {
 		);
 		self.camera.set_viewport(Viewport::new_at_origo(width, height));
 		render_target.clear(ClearState::color_and_depth(bg_r, bg_g, bg_b, bg_a, 1.0));
-		let models = self.models.values().map(|m| &m.model).collect::<Vec<_>>();
-		self.directional_light.generate_shadow_map(2048, &models);
+		self.update_auto_lods();
+		let shown_models = self.models.values()
+			.filter(|m| m.is_shown(&self.lod_groups))
+			.collect::<Vec<_>>();
+		let shadow_casters = shown_models.iter().map(|m| &m.model).collect::<Vec<_>>();
+		self.directional_light.generate_shadow_map(2048, &shadow_casters);
+		let models = shown_models.iter()
+			.filter(|m| m.bounding_box.is_empty() || self.camera.in_frustum(&m.bounding_box))
+			.map(|m| &m.model)
+			.collect::<Vec<_>>();
 		render_target.render(&self.camera, &models, &self.lights());
+		if self.show_col_tree {
+			render_target.render(&self.camera, &self.col_tree_boxes, &[]);
+		}
 		render_target.read_color::<[u8; 4]>().into_flattened()
 		// render_target.read_depth().into_iter().map(|f| [(f * 100.0) as u8, (f * 100.0) as u8, (f * 100.0) as u8, 255]).flatten().collect()
 	}
@@ -224,12 +324,87 @@ impl RenderState {
 
 	pub fn set_model_visibility(&mut self, id: u32, visible: bool) {
 		if let Some(model) = self.models.get_mut(&id) {
-			if visible {
-				model.model.set_transformation(model.transform);
+			model.visible = visible;
+			model.update_transformation(&self.lod_groups);
+		}
+		self.update_model_states();
+	}
+
+	pub fn set_col_tree_visibility(&mut self, visible: bool) {
+		self.show_col_tree = visible;
+	}
+
+	pub fn lod_selection(&self, group_index: usize) -> Option<LodSelection> {
+		self.lod_groups.get(group_index).map(|g| g.selection)
+	}
+
+	pub fn active_lod(&self, group_index: usize) -> Option<usize> {
+		self.lod_groups.get(group_index).map(|g| g.active_lod)
+	}
+
+	pub fn set_lod_selection(&mut self, group_index: usize, selection: LodSelection) -> Result<(), String> {
+		let group = self.lod_groups.get_mut(group_index)
+			.ok_or(format!("LOD group index out of bounds: {}", group_index))?;
+		match selection {
+			LodSelection::Fixed(lod_index) if lod_index >= group.lod_count => {
+				return Err(format!("LOD index out of bounds: {}", lod_index));
+			},
+			LodSelection::Fixed(lod_index) => group.active_lod = lod_index,
+			LodSelection::Auto => {},
+		}
+		group.selection = selection;
+		self.update_auto_lods();
+		self.update_lod_visibility(group_index);
+		Ok(())
+	}
+
+	fn update_auto_lods(&mut self) {
+		let camera_position = self.camera.position();
+		// tan(fov / 2) of the perspective projection
+		let fov_factor = 1.0 / self.camera.projection()[1][1];
+		let mut changed_groups = Vec::new();
+		for (group_i, group) in self.lod_groups.iter_mut().enumerate() {
+			if group.selection != LodSelection::Auto || group.bounding_box.is_empty() {
+				continue;
+			}
+			let center = group.bounding_box.center();
+			let radius = group.bounding_box.distance_max(center);
+			let distance = (center - camera_position).magnitude().max(0.001);
+			// fraction of the screen height covered by the model, each halving switches to the next LOD
+			let screen_size = radius / (distance * fov_factor);
+			let lod_index = if screen_size >= 1.0 {
+				0
 			} else {
-				model.model.set_transformation(Matrix4::from_scale(0.0));
+				(-screen_size.log2()).floor() as usize
+			};
+			let lod_index = lod_index.min(group.lod_count.saturating_sub(1));
+			if lod_index != group.active_lod {
+				group.active_lod = lod_index;
+				changed_groups.push(group_i);
 			}
 		}
+		for group_i in changed_groups {
+			self.update_lod_visibility(group_i);
+		}
+	}
+
+	fn update_lod_visibility(&mut self, group_index: usize) {
+		for model in self.models.values_mut() {
+			if model.lod.is_some_and(|lod| lod.group_index == group_index) {
+				model.update_transformation(&self.lod_groups);
+			}
+		}
+		self.update_model_states();
+	}
+
+	fn update_model_states(&mut self) {
+		let mut model_states = String::new();
+		for (i, name) in self.model_names.iter().enumerate() {
+			let shown = self.models.get(&(i as u32)).is_some_and(|m| m.is_shown(&self.lod_groups));
+			model_states.push_str(&format!("{},{},{}\n", i, name, shown));
+		}
+		model_states.push('\0');
+		self.model_states = model_states;
 	}
 
 	fn lights(&self) -> [&dyn Light; 2] {
@@ -237,6 +412,34 @@ impl RenderState {
 	}
 }
 
+impl ModelInfo {
+	fn is_shown(&self, lod_groups: &[LodGroupState]) -> bool {
+		let lod_active = match self.lod {
+			Some(lod) => lod_groups.get(lod.group_index).is_none_or(|g| g.active_lod == lod.lod_index),
+			None => true,
+		};
+		self.visible && lod_active
+	}
+
+	fn update_transformation(&mut self, lod_groups: &[LodGroupState]) {
+		if self.is_shown(lod_groups) {
+			self.model.set_transformation(self.transform);
+		} else {
+			self.model.set_transformation(Matrix4::from_scale(0.0));
+		}
+	}
+}
+
+impl Drop for RenderState {
+	fn drop(&mut self) {
+		self.models.clear();
+		let mut texture_cache = self.context.texture_cache.borrow_mut();
+		for key in self.texture_keys.iter() {
+			texture_cache.release(key);
+		}
+	}
+}
+
 // fn _random_color() -> Srgba {
 //     let mut rng = rand::rng();
 //     Srgba::new(
@@ -247,51 +450,76 @@ impl RenderState {
 //     )
 // }
 
-fn tex_data_to_tex2d(context: &Context, tex_data: crate::mesh_data::TextureData) -> Result<Texture2D, String> {
-	let pixel_buffer = tex_data.bytes
-		.chunks(4)
-		.map(|chunk| [chunk[0], chunk[1], chunk[2], chunk[3]])
-		.collect::<Vec<_>>();
-	let mut cpu_tex = CpuTexture {
-		name: "dds".to_string(),
-		data: TextureData::RgbaU8(pixel_buffer),
-		width: tex_data.width,
-		height: tex_data.height,
-		min_filter: Interpolation::Linear,
-		mag_filter: Interpolation::Linear,
-		mipmap: Some(Mipmap{
-			filter: Interpolation::Linear,
-			max_levels: 4,
-			max_ratio: 4,
-		}),
-		wrap_s: Wrapping::Repeat,
-		wrap_t: Wrapping::Repeat,
-	};
-	cpu_tex.data.to_linear_srgb();
-	Ok(Texture2D::new(context, &cpu_tex))
+fn texture_key(texture_source: &str, texture_id: u32, encoded: &EncodedTextureData) -> TextureKey {
+	TextureKey {
+		source: texture_source.to_string(),
+		id: texture_id,
+		hash: encoded.hash,
+	}
 }
 
+// a failed decode is kept in the cache, see `TextureCache::error`
 fn lookup_texture(
-	context: &Context,
+	context: &RenderContext,
 	texture_id: Option<u32>,
-	texture_handles: &mut HashMap<u32, Arc<Texture2D>>,
-	textures: &HashMap<u32, crate::mesh_data::TextureData>,
+	texture_keys: &mut Vec<TextureKey>,
+	texture_source: &str,
+	textures: &HashMap<u32, EncodedTextureData>,
 ) -> Option<Texture2DRef> {
-	let tex = if let Some(id) = texture_id {
-		texture_handles.get(&id).cloned()
-	} else {
-		None
-	};
-	let tex = tex.or_else(|| {
-		texture_id.and_then(|id| {
-			let tex_data = textures.get(&id)?;
-			let tex_handle = tex_data_to_tex2d(context, tex_data.clone()).ok()?;
-			texture_handles.insert(id, Arc::new(tex_handle));
-			Some(texture_handles[&id].clone())
-		})
-	});
-	tex.map(|t| Texture2DRef{
-		texture: t.clone(),
+	let encoded = textures.get(&texture_id?)?;
+	let key = texture_key(texture_source, texture_id?, encoded);
+	let texture = context.texture_cache.borrow_mut().acquire(context, &key, || encoded.decode()).ok()?;
+	texture_keys.push(key);
+	Some(Texture2DRef{
+		texture,
 		transformation: Matrix3::identity(),
 	})
 }
+
+fn lookup_texture_data(
+	context: &RenderContext,
+	texture_id: u32,
+	texture_keys: &mut Vec<TextureKey>,
+	texture_source: &str,
+	textures: &HashMap<u32, EncodedTextureData>,
+) -> Option<Arc<crate::mesh_data::TextureData>> {
+	let encoded = textures.get(&texture_id)?;
+	let key = texture_key(texture_source, texture_id, encoded);
+	let tex_data = context.texture_cache.borrow_mut().acquire_data(&key, || encoded.decode()).ok()?;
+	texture_keys.push(key);
+	Some(tex_data)
+}
+
+/// Vertex colours multiplied with the lightmap, which is sampled per vertex with `uv2`,
+/// since meshes only have a single UV set on the GPU.
+/// The alpha channel is left out, it's usually a blend weight and not opacity.
+fn vertex_colors(mesh_data: &MeshData, lightmap: Option<&crate::mesh_data::TextureData>) -> Option<Vec<Srgba>> {
+	let num_vertices = mesh_data.vertices.len();
+	let colors = mesh_data.colors.as_ref().filter(|colors| colors.len() == num_vertices);
+	let uv2 = mesh_data.uv2.as_ref().filter(|uv2| uv2.len() == num_vertices);
+	let lightmap = lightmap.zip(uv2);
+	if colors.is_none() && lightmap.is_none() {
+		return None;
+	}
+	Some((0..num_vertices).map(|i| {
+		let mut color = colors.map_or([255; 4], |colors| colors[i]);
+		if let Some((lightmap, uv2)) = lightmap {
+			let light = sample_texture(lightmap, uv2[i]);
+			for c in 0..3 {
+				color[c] = (color[c] as u16 * light[c] as u16 / 255) as u8;
+			}
+		}
+		Srgba::new(color[0], color[1], color[2], 255)
+	}).collect())
+}
+
+/// Nearest texel with repeat wrapping
+fn sample_texture(texture: &crate::mesh_data::TextureData, uv: Vector2<f32>) -> [u8; 4] {
+	if texture.width == 0 || texture.height == 0 {
+		return [255; 4];
+	}
+	let x = ((uv.x.rem_euclid(1.0) * texture.width as f32) as u32).min(texture.width - 1);
+	let y = ((uv.y.rem_euclid(1.0) * texture.height as f32) as u32).min(texture.height - 1);
+	let i = (y * texture.width + x) as usize * 4;
+	texture.bytes.get(i..i + 4).map_or([255; 4], |texel| [texel[0], texel[1], texel[2], texel[3]])
+}
@@ -1,15 +1,16 @@
-use std::{collections::HashMap, io::{Cursor, Read, Seek}};
+use std::io::{Cursor, Read, Seek};
 
 use three_d::Matrix4;
 
-use crate::{byte_stream::ByteReader, mesh_data::{MeshData, TextureData}, wmb::read_wmb, wta_wtp::WtaWtp};
+use crate::{byte_stream::ByteReader, load_options::LoadOptions, mesh_data::SceneData, wmb::read_wmb, wta_wtp::WtaWtp};
 
 
 pub fn read_scr_mgrr<R1: Read + Seek, R2: Read + Seek>(
 	reader: &mut ByteReader<R1>,
 	wta_wtp: &mut Option<WtaWtp<R2>>,
-	textures: &mut HashMap<u32, TextureData>,
-) -> Result<Vec<MeshData>, String> {
+	scene: &mut SceneData,
+	options: &LoadOptions,
+) -> Result<(), String> {
 	reader.seek(6)?;
 	let num_models = reader.read_u16()?;
 	let offsets_offset = reader.read_u32()?;
@@ -18,9 +19,8 @@ pub fn read_scr_mgrr<R1: Read + Seek, R2: Read + Seek>(
 	for _ in 0..num_models {
 		offsets.push(reader.read_u32()? as u64);
 	}
-	let mut meshes = Vec::new();
 	for (i, offset) in offsets.iter().enumerate() {
-		reader.seek(*offset as u64)?;
+		reader.seek(*offset)?;
 		let wmb_offset = reader.read_u32()? as u64;
 		let wmb_name = reader.read_string(64)?;
 		let wmb_name = wmb_name.replace('\0', "");
@@ -45,13 +45,12 @@ pub fn read_scr_mgrr<R1: Read + Seek, R2: Read + Seek>(
 		let cursor = Cursor::new(wmb_bytes);
 		let mut wmb_reader = ByteReader::new(cursor);
 		
-		let mut mesh_datas = read_wmb(&wmb_name, &mut wmb_reader, wta_wtp, textures)?;
-		for mesh_data in mesh_datas.iter_mut() {
+		let mesh_start = scene.meshes.len();
+		read_wmb(&wmb_name, &mut wmb_reader, wta_wtp, scene, options)?;
+		for mesh_data in scene.meshes[mesh_start..].iter_mut() {
 			mesh_data.name = wmb_name.clone();
 			mesh_data.transform = transform;
 		}
-
-		meshes.append(&mut mesh_datas);
 	}
-	Ok(meshes)
+	Ok(())
 }
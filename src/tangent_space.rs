@@ -0,0 +1,229 @@
+use std::collections::HashMap;
+
+use three_d::{InnerSpace, Rad, Vector2, Vector3, Vector4, Zero};
+
+
+/// Faces meeting at a sharper angle than this get a hard edge
+pub const DEFAULT_SMOOTHING_ANGLE: Rad<f32> = Rad(std::f32::consts::FRAC_PI_3);
+
+/// Area weighted smooth normals for every corner of the triangles in `indexes`. Faces sharing the position
+/// of a corner, through the same vertex or e.g. at UV seams, only contribute to it if the angle between them
+/// and the face of the corner is below `smoothing_angle`, so one vertex can get different normals on either
+/// side of a hard edge. Use `split_vertices` to turn them into per vertex normals.
+pub fn generate_corner_normals(positions: &[Vector3<f32>], indexes: &[u32], smoothing_angle: Rad<f32>) -> Vec<Vector3<f32>> {
+	let triangles = triangles(positions, indexes);
+	let face_normals = triangles.iter()
+		.map(|&(_, [a, b, c])| (positions[b] - positions[a]).cross(positions[c] - positions[a]))
+		.collect::<Vec<_>>();
+
+	let mut position_faces: HashMap<[u32; 3], Vec<usize>> = HashMap::new();
+	for (face_i, (_, triangle)) in triangles.iter().enumerate() {
+		for &vertex_i in triangle {
+			let faces = position_faces.entry(position_key(positions[vertex_i])).or_default();
+			if faces.last() != Some(&face_i) {
+				faces.push(face_i);
+			}
+		}
+	}
+
+	let min_cos = smoothing_angle.0.cos();
+	let mut normals = vec![Vector3::unit_z(); indexes.len()];
+	for (face_i, &(first_corner, triangle)) in triangles.iter().enumerate() {
+		let face_direction = normalized(face_normals[face_i]);
+		for (corner, &vertex_i) in triangle.iter().enumerate() {
+			// degenerate faces take the normal of everything around them
+			let normal = position_faces[&position_key(positions[vertex_i])].iter()
+				.filter(|&&f| match (face_direction, normalized(face_normals[f])) {
+					(Some(face_direction), Some(direction)) => f == face_i || direction.dot(face_direction) >= min_cos,
+					(None, _) => true,
+					(_, None) => false,
+				})
+				.fold(Vector3::zero(), |sum, &f| sum + face_normals[f]);
+			normals[first_corner + corner] = normalized(normal).or(face_direction).unwrap_or(Vector3::unit_z());
+		}
+	}
+	normals
+}
+
+/// MikkTSpace tangents for every corner of the triangles in `indexes`, `w` holds the handedness so that
+/// `bitangent = w * cross(normal, tangent)`. Like the normals of `generate_corner_normals` they can differ
+/// between the corners of a vertex, e.g. at mirrored UVs. Corners of degenerate triangles get `fallback_tangent`.
+pub fn generate_corner_tangents(
+	positions: &[Vector3<f32>],
+	normals: &[Vector3<f32>],
+	uvs: &[Vector2<f32>],
+	indexes: &[u32],
+) -> Result<Vec<Vector4<f32>>, String> {
+	struct Geometry<'a> {
+		positions: &'a [Vector3<f32>],
+		normals: &'a [Vector3<f32>],
+		uvs: &'a [Vector2<f32>],
+		triangles: Vec<(usize, [usize; 3])>,
+		tangents: Vec<Vector4<f32>>,
+	}
+
+	impl mikktspace::Geometry for Geometry<'_> {
+		fn num_faces(&self) -> usize {
+			self.triangles.len()
+		}
+
+		fn num_vertices_of_face(&self, _face: usize) -> usize {
+			3
+		}
+
+		fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+			self.positions[self.triangles[face].1[vert]].into()
+		}
+
+		fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+			self.normals[self.triangles[face].1[vert]].into()
+		}
+
+		fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+			self.uvs[self.triangles[face].1[vert]].into()
+		}
+
+		fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+			self.tangents[self.triangles[face].0 + vert] = tangent.into();
+		}
+	}
+
+	let vertex_count = positions.len().min(normals.len()).min(uvs.len());
+	let mut geometry = Geometry {
+		positions,
+		normals,
+		uvs,
+		triangles: triangles(&positions[..vertex_count], indexes),
+		tangents: indexes.iter()
+			.map(|&i| fallback_tangent(normals.get(i as usize).copied().unwrap_or(Vector3::unit_z())))
+			.collect(),
+	};
+	if !geometry.triangles.is_empty() && !mikktspace::generate_tangents(&mut geometry) {
+		return Err("MikkTSpace couldn't generate tangents".to_string());
+	}
+	Ok(geometry.tangents)
+}
+
+/// Gives every corner the value in `corner_values`, duplicating vertices whose corners disagree.
+/// Duplicates are appended to `values` and the indexes of their corners updated.
+/// Returns the vertex each duplicate was copied from, so its other attributes can be copied too.
+pub fn split_vertices<T: Copy + PartialEq>(values: &mut Vec<T>, indexes: &mut [u32], corner_values: &[T]) -> Vec<usize> {
+	let vertex_count = values.len();
+	// vertices holding the values seen so far for each original vertex
+	let mut copies = vec![Vec::new(); vertex_count];
+	let mut sources = Vec::new();
+	for (index, &value) in indexes.iter_mut().zip(corner_values) {
+		let vertex_i = *index as usize;
+		if vertex_i >= vertex_count {
+			continue;
+		}
+		if let Some(&copy) = copies[vertex_i].iter().find(|&&copy| values[copy] == value) {
+			*index = copy as u32;
+		} else if copies[vertex_i].is_empty() {
+			values[vertex_i] = value;
+			copies[vertex_i].push(vertex_i);
+		} else {
+			let copy = values.len();
+			values.push(value);
+			sources.push(vertex_i);
+			copies[vertex_i].push(copy);
+			*index = copy as u32;
+		}
+	}
+	sources
+}
+
+/// Some tangent perpendicular to `normal`, for vertices where none can be generated
+pub fn fallback_tangent(normal: Vector3<f32>) -> Vector4<f32> {
+	any_perpendicular(normal).extend(1.0)
+}
+
+/// Zero length, not finite or (almost) parallel to the normal
+pub fn is_degenerate_tangent(tangent: Vector4<f32>, normal: Vector3<f32>) -> bool {
+	let Some(tangent) = normalized(tangent.truncate()) else {
+		return true;
+	};
+	match normalized(normal) {
+		Some(normal) => tangent.dot(normal).abs() > 0.99,
+		None => false,
+	}
+}
+
+/// Triangles with all vertices in range, along with the position of their first corner in `indexes`
+fn triangles(positions: &[Vector3<f32>], indexes: &[u32]) -> Vec<(usize, [usize; 3])> {
+	indexes.chunks_exact(3)
+		.enumerate()
+		.map(|(i, t)| (i * 3, [t[0] as usize, t[1] as usize, t[2] as usize]))
+		.filter(|(_, t)| t.iter().all(|&i| i < positions.len()))
+		.collect()
+}
+
+fn position_key(position: Vector3<f32>) -> [u32; 3] {
+	// adding 0 turns -0 into +0
+	[(position.x + 0.0).to_bits(), (position.y + 0.0).to_bits(), (position.z + 0.0).to_bits()]
+}
+
+fn normalized(vector: Vector3<f32>) -> Option<Vector3<f32>> {
+	let length = vector.magnitude();
+	if length > 1e-12 && length.is_finite() {
+		Some(vector / length)
+	} else {
+		None
+	}
+}
+
+fn any_perpendicular(normal: Vector3<f32>) -> Vector3<f32> {
+	let axis = if normal.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+	normalized(normal.cross(axis)).unwrap_or(Vector3::unit_x())
+}
+
+#[cfg(test)]
+mod tests {
+	use three_d::{vec2, vec3};
+
+	use super::*;
+
+	fn assert_near(a: Vector3<f32>, b: Vector3<f32>) {
+		assert!((a - b).magnitude() < 1e-5, "{:?} != {:?}", a, b);
+	}
+
+	#[test]
+	fn hard_edge_on_a_shared_vertex() {
+		// two faces at 90 degrees sharing the vertices 0 and 1
+		let positions = vec![vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0), vec3(0.0, 1.0, 0.0), vec3(0.0, 0.0, -1.0)];
+		let mut indexes = vec![0, 1, 2, 1, 0, 3];
+		let corner_normals = generate_corner_normals(&positions, &indexes, DEFAULT_SMOOTHING_ANGLE);
+		assert_near(corner_normals[0], vec3(0.0, 0.0, 1.0));
+		assert_near(corner_normals[4], vec3(0.0, -1.0, 0.0));
+
+		let mut normals = vec![Vector3::unit_z(); positions.len()];
+		let sources = split_vertices(&mut normals, &mut indexes, &corner_normals);
+		assert_eq!(sources, vec![1, 0]);
+		assert_eq!(indexes, vec![0, 1, 2, 4, 5, 3]);
+		assert_near(normals[5], vec3(0.0, -1.0, 0.0));
+
+		let smooth = generate_corner_normals(&positions, &[0, 1, 2, 1, 0, 3], Rad(2.0));
+		assert_near(smooth[0], smooth[4]);
+	}
+
+	#[test]
+	fn mirrored_uvs_get_their_own_tangents() {
+		// a flat quad whose right half mirrors the UVs of the left half, the middle vertices are shared
+		let positions = vec![
+			vec3(0.0, 0.0, 0.0), vec3(1.0, 0.0, 0.0), vec3(1.0, 1.0, 0.0), vec3(0.0, 1.0, 0.0),
+			vec3(2.0, 0.0, 0.0), vec3(2.0, 1.0, 0.0),
+		];
+		let uvs = vec![vec2(0.0, 0.0), vec2(1.0, 0.0), vec2(1.0, 1.0), vec2(0.0, 1.0), vec2(0.0, 0.0), vec2(0.0, 1.0)];
+		let normals = vec![Vector3::unit_z(); positions.len()];
+		let mut indexes = vec![0, 1, 2, 0, 2, 3, 1, 4, 5, 1, 5, 2];
+		let corner_tangents = generate_corner_tangents(&positions, &normals, &uvs, &indexes).unwrap();
+		assert_near(corner_tangents[0].truncate(), vec3(1.0, 0.0, 0.0));
+		assert_near(corner_tangents[6].truncate(), vec3(-1.0, 0.0, 0.0));
+		assert_eq!(corner_tangents[0].w, -corner_tangents[6].w);
+
+		let mut tangents = vec![fallback_tangent(Vector3::unit_z()); positions.len()];
+		let sources = split_vertices(&mut tangents, &mut indexes, &corner_tangents);
+		assert_eq!(sources, vec![1, 2]);
+		assert_eq!(&indexes[6..], &[6, 4, 5, 6, 5, 7]);
+	}
+}
@@ -0,0 +1,303 @@
+use std::{collections::HashMap, sync::Arc};
+
+use three_d::{Context, CpuTexture, Interpolation, Mipmap, Texture2D, TextureData, Wrapping};
+
+
+pub const DEFAULT_TEXTURE_BUDGET: usize = 512 * 1024 * 1024;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct TextureKey {
+	pub source: String,
+	pub id: u32,
+	/// `EncodedTextureData::hash`
+	pub hash: u64,
+}
+
+struct CacheEntry {
+	texture: Option<Arc<Texture2D>>,
+	/// Decoded pixels, only kept for textures that are sampled on the CPU
+	data: Option<Arc<crate::mesh_data::TextureData>>,
+	size: usize,
+	ref_count: u32,
+	last_used: u64,
+}
+
+/// GPU textures, and the decoded pixels of textures sampled on the CPU, shared between all renderers of one context.
+///
+/// Textures that are still referenced by a renderer are never evicted. Unreferenced
+/// textures stay cached until the budget is exceeded, then the least recently used ones are dropped.
+/// Textures that failed to decode are remembered with their error, so they aren't decoded again.
+pub struct TextureCache {
+	entries: HashMap<TextureKey, CacheEntry>,
+	failed: HashMap<TextureKey, String>,
+	budget: usize,
+	used: usize,
+	tick: u64,
+}
+
+impl TextureCache {
+	pub fn new(budget: usize) -> Self {
+		TextureCache {
+			entries: HashMap::new(),
+			failed: HashMap::new(),
+			budget,
+			used: 0,
+			tick: 0,
+		}
+	}
+
+	pub fn contains(&self, key: &TextureKey) -> bool {
+		self.entries.contains_key(key)
+	}
+
+	pub fn get(&mut self, key: &TextureKey) -> Option<Arc<Texture2D>> {
+		self.tick += 1;
+		let entry = self.entries.get_mut(key)?;
+		entry.last_used = self.tick;
+		entry.texture.clone()
+	}
+
+	/// The decode error of a texture that couldn't be acquired
+	pub fn error(&self, key: &TextureKey) -> Option<&str> {
+		self.failed.get(key).map(|error| error.as_str())
+	}
+
+	pub fn errors(&self) -> impl Iterator<Item = (&TextureKey, &str)> {
+		self.failed.iter().map(|(key, error)| (key, error.as_str()))
+	}
+
+	/// Returns the cached texture and increments its reference count.
+	/// On a cache miss the texture is decoded and uploaded. If decoding fails the error is
+	/// returned and kept, later calls for the same key return it without decoding again.
+	pub fn acquire(
+		&mut self,
+		context: &Context,
+		key: &TextureKey,
+		decode: impl FnOnce() -> Result<crate::mesh_data::TextureData, String>,
+	) -> Result<Arc<Texture2D>, String> {
+		self.tick += 1;
+		let texture = match self.entries.get(key).and_then(|entry| entry.texture.clone()) {
+			Some(texture) => texture,
+			None => {
+				let tex_data = self.decoded(key, decode)?;
+				let texture = Arc::new(tex_data_to_tex2d(context, &tex_data));
+				let size = texture_size(tex_data.width, tex_data.height);
+				let entry = self.entry(key);
+				entry.texture = Some(texture.clone());
+				entry.size += size;
+				self.used += size;
+				texture
+			}
+		};
+		self.add_reference(key);
+		Ok(texture)
+	}
+
+	/// Like `acquire`, but returns the decoded RGBA8 pixels instead of a GPU texture
+	pub fn acquire_data(
+		&mut self,
+		key: &TextureKey,
+		decode: impl FnOnce() -> Result<crate::mesh_data::TextureData, String>,
+	) -> Result<Arc<crate::mesh_data::TextureData>, String> {
+		self.tick += 1;
+		let tex_data = match self.entries.get(key).and_then(|entry| entry.data.clone()) {
+			Some(tex_data) => tex_data,
+			None => {
+				let tex_data = self.decoded(key, decode)?;
+				let size = tex_data.bytes.len();
+				let entry = self.entry(key);
+				entry.data = Some(tex_data.clone());
+				entry.size += size;
+				self.used += size;
+				tex_data
+			}
+		};
+		self.add_reference(key);
+		Ok(tex_data)
+	}
+
+	/// Decoded pixels of a cached entry, or of a new decode
+	fn decoded(
+		&mut self,
+		key: &TextureKey,
+		decode: impl FnOnce() -> Result<crate::mesh_data::TextureData, String>,
+	) -> Result<Arc<crate::mesh_data::TextureData>, String> {
+		if let Some(tex_data) = self.entries.get(key).and_then(|entry| entry.data.clone()) {
+			return Ok(tex_data);
+		}
+		if let Some(error) = self.failed.get(key) {
+			return Err(error.clone());
+		}
+		decode().map(Arc::new).inspect_err(|error| {
+			self.failed.insert(key.clone(), error.clone());
+		})
+	}
+
+	fn entry(&mut self, key: &TextureKey) -> &mut CacheEntry {
+		self.entries.entry(key.clone()).or_insert_with(|| CacheEntry {
+			texture: None,
+			data: None,
+			size: 0,
+			ref_count: 0,
+			last_used: 0,
+		})
+	}
+
+	fn add_reference(&mut self, key: &TextureKey) {
+		let tick = self.tick;
+		let entry = self.entry(key);
+		entry.ref_count += 1;
+		entry.last_used = tick;
+		self.evict();
+	}
+
+	pub fn release(&mut self, key: &TextureKey) {
+		if let Some(entry) = self.entries.get_mut(key) {
+			entry.ref_count = entry.ref_count.saturating_sub(1);
+		}
+		self.evict();
+	}
+
+	pub fn set_budget(&mut self, budget: usize) {
+		self.budget = budget;
+		self.evict();
+	}
+
+	pub fn budget(&self) -> usize {
+		self.budget
+	}
+
+	pub fn used_bytes(&self) -> usize {
+		self.used
+	}
+
+	pub fn clear_unused(&mut self) {
+		let unused = self.entries.iter()
+			.filter(|(_, entry)| entry.ref_count == 0)
+			.map(|(key, _)| key.clone())
+			.collect::<Vec<_>>();
+		for key in unused {
+			self.remove(&key);
+		}
+		self.failed.clear();
+	}
+
+	fn evict(&mut self) {
+		while self.used > self.budget {
+			let lru = self.entries.iter()
+				.filter(|(_, entry)| entry.ref_count == 0)
+				.min_by_key(|(_, entry)| entry.last_used)
+				.map(|(key, _)| key.clone());
+			match lru {
+				Some(key) => self.remove(&key),
+				None => break,
+			}
+		}
+	}
+
+	fn remove(&mut self, key: &TextureKey) {
+		if let Some(entry) = self.entries.remove(key) {
+			self.used -= entry.size;
+		}
+	}
+}
+
+impl Default for TextureCache {
+	fn default() -> Self {
+		TextureCache::new(DEFAULT_TEXTURE_BUDGET)
+	}
+}
+
+/// RGBA8 size including the mip chain
+fn texture_size(width: u32, height: u32) -> usize {
+	let base = width as usize * height as usize * 4;
+	base + base / 3
+}
+
+fn tex_data_to_tex2d(context: &Context, tex_data: &crate::mesh_data::TextureData) -> Texture2D {
+	let pixel_buffer = tex_data.bytes
+		.chunks(4)
+		.map(|chunk| [chunk[0], chunk[1], chunk[2], chunk[3]])
+		.collect::<Vec<_>>();
+	let mut cpu_tex = CpuTexture {
+		name: "dds".to_string(),
+		data: TextureData::RgbaU8(pixel_buffer),
+		width: tex_data.width,
+		height: tex_data.height,
+		min_filter: Interpolation::Linear,
+		mag_filter: Interpolation::Linear,
+		mipmap: Some(Mipmap{
+			filter: Interpolation::Linear,
+			max_levels: 4,
+			max_ratio: 4,
+		}),
+		wrap_s: Wrapping::Repeat,
+		wrap_t: Wrapping::Repeat,
+	};
+	cpu_tex.data.to_linear_srgb();
+	Texture2D::new(context, &cpu_tex)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::cell::Cell;
+
+	use super::*;
+	use crate::mesh_data::TextureData as RgbaData;
+
+	fn key(id: u32) -> TextureKey {
+		TextureKey { source: "test".to_string(), id, hash: id as u64 }
+	}
+
+	fn pixels(width: u32, height: u32) -> Result<RgbaData, String> {
+		Ok(RgbaData { bytes: vec![255; (width * height * 4) as usize], width, height })
+	}
+
+	#[test]
+	fn decoded_data_is_shared() {
+		let mut cache = TextureCache::new(1024);
+		let decodes = Cell::new(0);
+		let decode = || {
+			decodes.set(decodes.get() + 1);
+			pixels(2, 2)
+		};
+		let first = cache.acquire_data(&key(1), decode).unwrap();
+		let second = cache.acquire_data(&key(1), decode).unwrap();
+		assert!(Arc::ptr_eq(&first, &second));
+		assert_eq!(decodes.get(), 1);
+		assert_eq!(cache.used_bytes(), 16);
+	}
+
+	#[test]
+	fn failed_decodes_are_kept() {
+		let mut cache = TextureCache::default();
+		let decodes = Cell::new(0);
+		let decode = || {
+			decodes.set(decodes.get() + 1);
+			Err("Unknown texture format".to_string())
+		};
+		assert_eq!(cache.acquire_data(&key(1), decode).err().as_deref(), Some("Unknown texture format"));
+		assert!(cache.acquire_data(&key(1), decode).is_err());
+		assert_eq!(decodes.get(), 1);
+		assert_eq!(cache.error(&key(1)), Some("Unknown texture format"));
+		assert!(!cache.contains(&key(1)));
+		assert_eq!(cache.errors().count(), 1);
+
+		cache.clear_unused();
+		assert_eq!(cache.error(&key(1)), None);
+	}
+
+	#[test]
+	fn only_unreferenced_data_is_evicted() {
+		let mut cache = TextureCache::new(40);
+		cache.acquire_data(&key(1), || pixels(2, 2)).unwrap();
+		cache.acquire_data(&key(2), || pixels(2, 2)).unwrap();
+		cache.acquire_data(&key(3), || pixels(2, 2)).unwrap();
+		assert_eq!(cache.used_bytes(), 48);
+
+		cache.release(&key(2));
+		assert!(!cache.contains(&key(2)));
+		assert!(cache.contains(&key(1)) && cache.contains(&key(3)));
+		assert_eq!(cache.used_bytes(), 32);
+	}
+}
@@ -0,0 +1,444 @@
+use std::{cell::RefCell, collections::{BTreeSet, HashMap}, fs::File, io::{BufReader, Cursor, Read, Seek}, path::PathBuf, rc::Rc, sync::Arc};
+
+use crate::{cpk::Cpk, dat::Dat};
+
+
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+pub enum VfsBackend {
+	Local(PathBuf),
+	Memory(HashMap<String, Arc<[u8]>>),
+}
+
+/// Resolves paths through loose files and nested DAT, DTT and CPK archives.
+///
+/// `data/pl/pl0000.dat/pl0000.wmb` opens `pl0000.wmb` from inside `pl0000.dat`,
+/// regardless of whether `pl0000.dat` is an extracted folder or an archive.
+pub struct Vfs {
+	backend: VfsBackend,
+	/// Parsed archives by path, so that nested archives are only read and decompressed once
+	archives: RefCell<HashMap<String, Rc<RefCell<Archive>>>>,
+}
+
+enum Archive {
+	Dat(Dat<Box<dyn ReadSeek>>),
+	Cpk(Cpk<Box<dyn ReadSeek>>),
+}
+
+enum Node {
+	Backend(String),
+	/// An entry or CPK directory of an archive and the full path of the entry
+	Archive(Rc<RefCell<Archive>>, String, String),
+}
+
+impl Vfs {
+	pub fn new(backend: VfsBackend) -> Self {
+		Vfs { backend, archives: RefCell::new(HashMap::new()) }
+	}
+
+	pub fn local() -> Self {
+		Vfs::new(VfsBackend::Local(PathBuf::new()))
+	}
+
+	pub fn local_at(root: PathBuf) -> Self {
+		Vfs::new(VfsBackend::Local(root))
+	}
+
+	pub fn memory() -> Self {
+		Vfs::new(VfsBackend::Memory(HashMap::new()))
+	}
+
+	pub fn add_file(&mut self, path: &str, bytes: Vec<u8>) -> Result<(), String> {
+		match &mut self.backend {
+			VfsBackend::Memory(files) => {
+				let path = normalize_path(path);
+				let nested_prefix = format!("{}/", path);
+				self.archives.get_mut().retain(|p, _| *p != path && !p.starts_with(&nested_prefix));
+				files.insert(path, bytes.into());
+				Ok(())
+			}
+			VfsBackend::Local(_) => Err("Can't add files to a local VFS".to_string()),
+		}
+	}
+
+	/// Drops all opened archives, e.g. after files on disk have changed
+	pub fn clear_cache(&self) {
+		self.archives.borrow_mut().clear();
+	}
+
+	pub fn open(&self, path: &str) -> Result<Box<dyn ReadSeek>, String> {
+		match self.resolve(path)? {
+			Node::Backend(path) => self.backend_open(&path),
+			Node::Archive(archive, name, _) => {
+				let bytes = archive.borrow_mut().read_entry(&name)?;
+				Ok(Box::new(Cursor::new(bytes)))
+			}
+		}
+	}
+
+	pub fn read(&self, path: &str) -> Result<Vec<u8>, String> {
+		read_all(self.open(path)?)
+	}
+
+	pub fn exists(&self, path: &str) -> bool {
+		match self.resolve(path) {
+			Ok(Node::Backend(path)) => self.backend_is_file(&path) || self.backend_is_dir(&path),
+			Ok(Node::Archive(archive, name, _)) => {
+				let archive = archive.borrow();
+				archive.find(&name).is_some() || archive.is_dir(&name)
+			}
+			Err(_) => false,
+		}
+	}
+
+	/// Directories, archives and directories inside of CPKs
+	pub fn is_dir(&self, path: &str) -> bool {
+		match self.resolve(path) {
+			Ok(Node::Backend(path)) => {
+				self.backend_is_dir(&path) ||
+				self.archives.borrow().contains_key(&path) ||
+				self.backend_is_file(&path) && self.backend_open(&path).is_ok_and(is_archive)
+			}
+			Ok(Node::Archive(archive, name, path)) => {
+				archive.borrow().is_dir(&name) ||
+				has_archive_extension(&name) && self.archive(&path, Some((&archive, &name))).is_ok()
+			}
+			Err(_) => false,
+		}
+	}
+
+	pub fn list(&self, path: &str) -> Result<Vec<String>, String> {
+		match self.resolve(path)? {
+			Node::Backend(path) => {
+				if self.backend_is_dir(&path) {
+					self.backend_list(&path)
+				} else {
+					self.archive(&path, None)
+						.map_err(|_| format!("Not a directory: {}", path))?
+						.borrow()
+						.list("")
+				}
+			}
+			Node::Archive(archive, name, path) => {
+				if archive.borrow().is_dir(&name) {
+					archive.borrow().list(&name)
+				} else {
+					self.archive(&path, Some((&archive, &name)))
+						.map_err(|_| format!("Not a directory: {}", name))?
+						.borrow()
+						.list("")
+				}
+			}
+		}
+	}
+
+	/// Calls `visit` with every file below `path` whose name passes `filter`, descending into archives.
+	/// Files that can't be read are reported to `visit` as errors.
+	pub fn walk<F, V>(&self, path: &str, filter: &F, visit: &mut V)
+	where
+		F: Fn(&str) -> bool,
+		V: FnMut(&str, Result<Vec<u8>, String>),
+	{
+		let path = normalize_path(path);
+		if self.backend_is_dir(&path) {
+			let children = match self.backend_list(&path) {
+				Ok(children) => children,
+				Err(e) => return visit(&path, Err(e)),
+			};
+			for child in children {
+				let child_path = if path.is_empty() { child } else { format!("{}/{}", path, child) };
+				self.walk(&child_path, filter, visit);
+			}
+		} else if has_archive_extension(&path) {
+			match self.backend_open(&path).and_then(Archive::read) {
+				Ok(Some(mut archive)) => walk_archive(&mut archive, &path, filter, visit),
+				Ok(None) => {},
+				Err(e) => visit(&path, Err(e)),
+			}
+		} else if filter(&path) {
+			visit(&path, self.backend_open(&path).and_then(read_all));
+		}
+	}
+
+	fn resolve(&self, path: &str) -> Result<Node, String> {
+		let path = normalize_path(path);
+		let components = path.split('/').collect::<Vec<_>>();
+		// innermost archive so far and the index of its first component
+		let mut current: Option<(Rc<RefCell<Archive>>, usize)> = None;
+		for i in 1..components.len() {
+			let prefix = components[..i].join("/");
+			let cached = self.archives.borrow().get(&prefix).cloned();
+			let archive = match (cached, &current) {
+				(Some(archive), _) => archive,
+				(None, None) if self.backend_is_file(&prefix) => self.archive(&prefix, None)?,
+				(None, Some((parent, start))) => {
+					let name = components[*start..i].join("/");
+					if parent.borrow().find(&name).is_none() {
+						continue;
+					}
+					self.archive(&prefix, Some((parent, &name)))?
+				}
+				(None, None) => continue,
+			};
+			current = Some((archive, i));
+		}
+		match current {
+			Some((archive, start)) => Ok(Node::Archive(archive, components[start..].join("/"), path)),
+			None => Ok(Node::Backend(path)),
+		}
+	}
+
+	/// Returns the cached archive at `path` or opens it from the backend or its `parent` archive
+	fn archive(&self, path: &str, parent: Option<(&Rc<RefCell<Archive>>, &str)>) -> Result<Rc<RefCell<Archive>>, String> {
+		if let Some(archive) = self.archives.borrow().get(path) {
+			return Ok(archive.clone());
+		}
+		let reader: Box<dyn ReadSeek> = match parent {
+			Some((parent, name)) => Box::new(Cursor::new(parent.borrow_mut().read_entry(name)?)),
+			None => self.backend_open(path)?,
+		};
+		let archive = Archive::read(reader)?
+			.ok_or(format!("Not an archive: {}", path))?;
+		let archive = Rc::new(RefCell::new(archive));
+		self.archives.borrow_mut().insert(path.to_string(), archive.clone());
+		Ok(archive)
+	}
+
+	fn backend_is_file(&self, path: &str) -> bool {
+		match &self.backend {
+			VfsBackend::Local(root) => root.join(path).is_file(),
+			VfsBackend::Memory(files) => files.contains_key(path),
+		}
+	}
+
+	fn backend_is_dir(&self, path: &str) -> bool {
+		match &self.backend {
+			VfsBackend::Local(root) => root.join(path).is_dir(),
+			VfsBackend::Memory(files) => path.is_empty() || files.keys().any(|k| is_in_dir(k, path)),
+		}
+	}
+
+	fn backend_open(&self, path: &str) -> Result<Box<dyn ReadSeek>, String> {
+		match &self.backend {
+			VfsBackend::Local(root) => {
+				let file = File::open(root.join(path)).map_err(|e| format!("{}: {}", path, e))?;
+				Ok(Box::new(BufReader::new(file)))
+			}
+			VfsBackend::Memory(files) => {
+				let bytes = files.get(path).ok_or(format!("File not found: {}", path))?;
+				Ok(Box::new(Cursor::new(bytes.clone())))
+			}
+		}
+	}
+
+	fn backend_list(&self, path: &str) -> Result<Vec<String>, String> {
+		match &self.backend {
+			VfsBackend::Local(root) => {
+				let mut names = Vec::new();
+				for entry in root.join(path).read_dir().map_err(|e| e.to_string())? {
+					let entry = entry.map_err(|e| e.to_string())?;
+					names.push(entry.file_name().to_string_lossy().to_string());
+				}
+				names.sort();
+				Ok(names)
+			}
+			VfsBackend::Memory(files) => Ok(list_children(files.keys().map(|k| k.as_str()), path)),
+		}
+	}
+}
+
+impl Archive {
+	fn read(mut reader: Box<dyn ReadSeek>) -> Result<Option<Self>, String> {
+		let mut magic = [0; 4];
+		reader.read_exact(&mut magic).map_err(|e| e.to_string())?;
+		reader.rewind().map_err(|e| e.to_string())?;
+		match &magic {
+			b"DAT\0" => Ok(Some(Archive::Dat(Dat::read(reader)?))),
+			b"CPK " => Ok(Some(Archive::Cpk(Cpk::read(reader)?))),
+			_ => Ok(None),
+		}
+	}
+
+	fn find(&self, name: &str) -> Option<usize> {
+		match self {
+			Archive::Dat(dat) => dat.find(name),
+			Archive::Cpk(cpk) => cpk.find(name),
+		}
+	}
+
+	fn read_entry(&mut self, name: &str) -> Result<Vec<u8>, String> {
+		match self {
+			Archive::Dat(dat) => dat.read_entry(name),
+			Archive::Cpk(cpk) => cpk.read_entry(name),
+		}
+	}
+
+	fn read_entry_at(&mut self, index: usize) -> Result<Vec<u8>, String> {
+		match self {
+			Archive::Dat(dat) => dat.read_entry_at(index),
+			Archive::Cpk(cpk) => cpk.read_entry_at(index),
+		}
+	}
+
+	fn is_dir(&self, name: &str) -> bool {
+		match self {
+			Archive::Dat(_) => name.is_empty(),
+			Archive::Cpk(cpk) => name.is_empty() || cpk.entries().iter().any(|e| is_in_dir(&e.path(), name)),
+		}
+	}
+
+	fn list(&self, name: &str) -> Result<Vec<String>, String> {
+		match self {
+			Archive::Dat(dat) => Ok(dat.entries().iter().map(|e| e.name.clone()).collect()),
+			Archive::Cpk(cpk) => {
+				let paths = cpk.entries().iter().map(|e| e.path()).collect::<Vec<_>>();
+				Ok(list_children(paths.iter().map(|p| p.as_str()), name))
+			}
+		}
+	}
+}
+
+fn walk_archive<F, V>(archive: &mut Archive, archive_path: &str, filter: &F, visit: &mut V)
+where
+	F: Fn(&str) -> bool,
+	V: FnMut(&str, Result<Vec<u8>, String>),
+{
+	let names = match archive {
+		Archive::Dat(dat) => dat.entries().iter().map(|e| e.name.clone()).collect::<Vec<_>>(),
+		Archive::Cpk(cpk) => cpk.entries().iter().map(|e| e.path()).collect::<Vec<_>>(),
+	};
+	for (i, name) in names.iter().enumerate() {
+		let path = format!("{}/{}", archive_path, name);
+		if has_archive_extension(name) {
+			let nested = archive.read_entry_at(i)
+				.and_then(|bytes| Archive::read(Box::new(Cursor::new(bytes))));
+			match nested {
+				Ok(Some(mut nested)) => walk_archive(&mut nested, &path, filter, visit),
+				Ok(None) => {},
+				Err(e) => visit(&path, Err(e)),
+			}
+		} else if filter(&path) {
+			visit(&path, archive.read_entry_at(i));
+		}
+	}
+}
+
+const ARCHIVE_EXTENSIONS: [&str; 5] = ["dat", "dtt", "cpk", "eff", "evn"];
+
+fn has_archive_extension(path: &str) -> bool {
+	path.rsplit_once('.')
+		.is_some_and(|(_, ext)| ARCHIVE_EXTENSIONS.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+}
+
+fn read_all(mut reader: Box<dyn ReadSeek>) -> Result<Vec<u8>, String> {
+	let mut bytes = Vec::new();
+	reader.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+	Ok(bytes)
+}
+
+fn is_archive<R: Read + Seek>(mut reader: R) -> bool {
+	let mut magic = [0; 4];
+	reader.read_exact(&mut magic).is_ok() && (&magic == b"DAT\0" || &magic == b"CPK ")
+}
+
+fn is_in_dir(path: &str, dir: &str) -> bool {
+	dir.is_empty() || path.len() > dir.len() && path.starts_with(dir) && path.as_bytes()[dir.len()] == b'/'
+}
+
+fn list_children<'a>(paths: impl Iterator<Item = &'a str>, dir: &str) -> Vec<String> {
+	let prefix_len = if dir.is_empty() { 0 } else { dir.len() + 1 };
+	paths
+		.filter(|p| is_in_dir(p, dir))
+		.map(|p| p[prefix_len..].split('/').next().unwrap_or("").to_string())
+		.collect::<BTreeSet<_>>()
+		.into_iter()
+		.collect()
+}
+
+pub fn normalize_path(path: &str) -> String {
+	let path = path.replace('\\', "/");
+	let mut components = Vec::new();
+	for (i, component) in path.split('/').enumerate() {
+		match component {
+			"" if i == 0 => components.push(""),
+			"" | "." => {},
+			_ => components.push(component),
+		}
+	}
+	components.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{cpk::tests::test_cpk, dat::{write_dat, DatWriteEntry}};
+
+	fn test_dat(files: &[(&str, Vec<u8>)]) -> Vec<u8> {
+		let entries = files.iter()
+			.map(|(name, data)| DatWriteEntry { name: name.to_string(), data: data.clone() })
+			.collect::<Vec<_>>();
+		let mut bytes = Cursor::new(Vec::new());
+		write_dat(&mut bytes, &entries).unwrap();
+		bytes.into_inner()
+	}
+
+	#[test]
+	fn memory_backend() {
+		let mut vfs = Vfs::memory();
+		vfs.add_file("data\\pl/./pl0000.wmb", vec![1, 2]).unwrap();
+		vfs.add_file("data/em/em0000.wmb", vec![3]).unwrap();
+		assert_eq!(vfs.read("data/pl/pl0000.wmb").unwrap(), vec![1, 2]);
+		assert!(vfs.exists("data/em/em0000.wmb"));
+		assert!(!vfs.exists("data/em/em0001.wmb"));
+		assert!(vfs.read("data/em/em0001.wmb").is_err());
+		assert!(vfs.is_dir("data"));
+		assert!(vfs.is_dir(""));
+		assert!(!vfs.is_dir("data/pl/pl0000.wmb"));
+		assert_eq!(vfs.list("data").unwrap(), vec!["em", "pl"]);
+		assert!(Vfs::local().add_file("a.wmb", Vec::new()).is_err());
+	}
+
+	#[test]
+	fn nested_archives() {
+		let inner_dat = test_dat(&[("pl0000.wmb", vec![1, 2, 3]), ("pl0000.wta", vec![4])]);
+		let outer_dat = test_dat(&[("inner.dat", inner_dat.clone())]);
+		let cpk = test_cpk(&[
+			("pl/pl0000.dat", inner_dat),
+			("pl/pl0000.dtt", test_dat(&[("pl0000.wtp", vec![5])])),
+			("outer.dat", outer_dat),
+		]);
+		let mut vfs = Vfs::memory();
+		vfs.add_file("data/data000.cpk", cpk).unwrap();
+
+		assert_eq!(vfs.read("data/data000.cpk/pl/pl0000.dat/pl0000.wmb").unwrap(), vec![1, 2, 3]);
+		assert_eq!(vfs.read("data/data000.cpk/outer.dat/inner.dat/pl0000.wta").unwrap(), vec![4]);
+		assert!(vfs.exists("data/data000.cpk/pl/pl0000.dtt/pl0000.wtp"));
+		assert!(!vfs.exists("data/data000.cpk/pl/pl0000.dtt/pl0001.wtp"));
+		assert!(vfs.is_dir("data/data000.cpk"));
+		assert!(vfs.is_dir("data/data000.cpk/pl"));
+		assert!(vfs.is_dir("data/data000.cpk/outer.dat/inner.dat"));
+		assert!(!vfs.is_dir("data/data000.cpk/pl/pl0000.dat/pl0000.wmb"));
+		assert_eq!(vfs.list("data/data000.cpk").unwrap(), vec!["outer.dat", "pl"]);
+		assert_eq!(vfs.list("data/data000.cpk/pl/pl0000.dat").unwrap(), vec!["pl0000.wmb", "pl0000.wta"]);
+
+		let mut found = Vec::new();
+		vfs.walk("data", &|path: &str| path.ends_with(".wmb"), &mut |path: &str, bytes: Result<Vec<u8>, String>| {
+			found.push((path.to_string(), bytes.unwrap()));
+		});
+		assert_eq!(found, vec![
+			("data/data000.cpk/pl/pl0000.dat/pl0000.wmb".to_string(), vec![1, 2, 3]),
+			("data/data000.cpk/outer.dat/inner.dat/pl0000.wmb".to_string(), vec![1, 2, 3]),
+		]);
+	}
+
+	#[test]
+	fn replacing_a_file_drops_its_archives() {
+		let mut vfs = Vfs::memory();
+		vfs.add_file("a.dat", test_dat(&[("x.wmb", vec![1])])).unwrap();
+		assert_eq!(vfs.read("a.dat/x.wmb").unwrap(), vec![1]);
+		vfs.add_file("a.dat", test_dat(&[("x.wmb", vec![2])])).unwrap();
+		assert_eq!(vfs.read("a.dat/x.wmb").unwrap(), vec![2]);
+	}
+}
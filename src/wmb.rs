@@ -1,19 +1,176 @@
-use std::{collections::HashMap, io::{Read, Seek}};
+use std::{collections::BTreeSet, io::{Read, Seek, Write}};
 
-use crate::{byte_stream::ByteReader, mesh_data::{MeshData, TextureData}, wmb_mgrr::read_wmb_mgrr, wmb_na::read_wmb_na, wta_wtp::WtaWtp};
+use half::f16;
 
+use crate::{byte_stream::{ByteReader, ByteWriter}, load_options::LoadOptions, mesh_data::SceneData, wmb_mgrr::{read_wmb_mgrr, read_wmb_mgrr_info}, wmb_na::{read_wmb_na, read_wmb_na_info, Wmb3Version}, wta_wtp::WtaWtp};
+
+/// Layout of a WMB, decided by its magic and for WMB3 its header version
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WmbFormat {
+	Wmb3(Wmb3Version),
+	Wmb4,
+}
+
+impl WmbFormat {
+	/// Leaves the reader at the start of the file
+	pub fn detect<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
+		reader.seek(0)?;
+		let magic = reader.read_string(4)?;
+		reader.seek(0)?;
+		match magic.as_str() {
+			"WMB3" => Ok(WmbFormat::Wmb3(Wmb3Version::peek(reader)?)),
+			"WMB4" => Ok(WmbFormat::Wmb4),
+			_ => Err(format!("Unknown WMB version: {}", magic)),
+		}
+	}
+}
 
 pub fn read_wmb<R1: Read + Seek, R2: Read + Seek>(
 	name: &str,
 	reader: &mut ByteReader<R1>,
 	wta_wtp: &mut Option<WtaWtp<R2>>,
-	textures: &mut HashMap<u32, TextureData>,
-) -> Result<Vec<MeshData>, String> {
-	let magic = reader.read_string(4)?;
-	reader.seek(0)?;
-	match magic.as_str() {
-		"WMB3" => read_wmb_na(name, reader, wta_wtp, textures),
-		"WMB4" => read_wmb_mgrr(reader, wta_wtp, textures),
-		_ => Err(format!("Unknown WMB version: {}", magic)),
+	scene: &mut SceneData,
+	options: &LoadOptions,
+) -> Result<(), String> {
+	match WmbFormat::detect(reader)? {
+		WmbFormat::Wmb3(Wmb3Version::Na) => read_wmb_na(name, reader, wta_wtp, scene, options),
+		WmbFormat::Wmb4 => read_wmb_mgrr(name, reader, wta_wtp, scene, options),
+	}
+}
+
+/// Names and references of a WMB, without any geometry
+pub struct WmbInfo {
+	pub meshes: Vec<String>,
+	pub materials: Vec<WmbMaterialInfo>,
+}
+
+pub struct WmbMaterialInfo {
+	pub name: String,
+	pub shader_name: String,
+	pub texture_ids: Vec<u32>,
+}
+
+pub fn read_wmb_info<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<WmbInfo, String> {
+	match WmbFormat::detect(reader)? {
+		WmbFormat::Wmb3(Wmb3Version::Na) => read_wmb_na_info(reader),
+		WmbFormat::Wmb4 => read_wmb_mgrr_info(reader),
+	}
+}
+
+/// Values of one vertex attribute for a range of vertices, taken from the vertex buffer
+/// if the vertex format has it there, otherwise from the ex-data buffer
+pub(crate) fn vertex_channel<V, E, T: Copy + Default>(
+	vertexes: &[V],
+	vertex_ex_data: &[E],
+	vertex_start: usize,
+	num_vertexes: usize,
+	from_vertex: impl Fn(&V) -> Option<T>,
+	from_ex: impl Fn(&E) -> Option<T>,
+) -> Option<Vec<T>> {
+	if vertexes.get(vertex_start).and_then(&from_vertex).is_some() {
+		Some(vertexes.iter()
+			.skip(vertex_start)
+			.take(num_vertexes)
+			.map(|v| from_vertex(v).unwrap_or_default())
+			.collect())
+	} else if vertex_ex_data.get(vertex_start).and_then(&from_ex).is_some() {
+		Some(vertex_ex_data.iter()
+			.skip(vertex_start)
+			.take(num_vertexes)
+			.map(|v| from_ex(v).unwrap_or_default())
+			.collect())
+	} else {
+		None
+	}
+}
+
+/// Maps the batch local bone indices of skinned vertices to skeleton bone indices.
+/// Weights of bones that can't be resolved are dropped and the remaining ones renormalized,
+/// vertices without any weight left are bound to bone 0. Also returns the local indices that couldn't be resolved.
+pub(crate) fn resolve_skin_weights(
+	vertexes: impl Iterator<Item = (Option<[u8; 4]>, Option<[f32; 4]>)>,
+	resolve: impl Fn(u8) -> Option<u16>,
+) -> (Vec<[u16; 4]>, Vec<[f32; 4]>, BTreeSet<u8>) {
+	let mut bone_indices = Vec::new();
+	let mut bone_weights = Vec::new();
+	let mut unresolved = BTreeSet::new();
+	for (local_indices, weights) in vertexes {
+		let local_indices = local_indices.unwrap_or_default();
+		let mut weights = weights.unwrap_or_default();
+		let mut indices = [0; 4];
+		let mut dropped = false;
+		for j in 0..4 {
+			if weights[j] == 0.0 {
+				continue;
+			}
+			match resolve(local_indices[j]) {
+				Some(index) => indices[j] = index,
+				None => {
+					unresolved.insert(local_indices[j]);
+					weights[j] = 0.0;
+					dropped = true;
+				}
+			}
+		}
+		if dropped {
+			let total = weights.iter().sum::<f32>();
+			if total > 0.0 {
+				weights.iter_mut().for_each(|w| *w /= total);
+			} else {
+				weights = [1.0, 0.0, 0.0, 0.0];
+			}
+		}
+		bone_indices.push(indices);
+		bone_weights.push(weights);
+	}
+	(bone_indices, bone_weights, unresolved)
+}
+
+/// Sections of both WMB versions start at multiples of this
+pub(crate) const SECTION_ALIGNMENT: u64 = 16;
+
+pub(crate) fn write_uv<W: Write + Seek>(writer: &mut ByteWriter<W>, uv: [f32; 2]) -> Result<(), String> {
+	writer.write_f16(f16::from_f32(uv[0]))?;
+	writer.write_f16(f16::from_f32(uv[1]))
+}
+
+pub(crate) fn offset_u32(offset: u64) -> Result<u32, String> {
+	u32::try_from(offset).map_err(|_| format!("Offset 0x{:X} doesn't fit in a WMB", offset))
+}
+
+pub(crate) fn count_u32(count: usize) -> Result<u32, String> {
+	u32::try_from(count).map_err(|_| format!("Too many entries for a WMB: {}", count))
+}
+
+/// Writes a section at the next aligned position and returns its offset, or 0 if it is empty
+pub(crate) fn write_section<W: Write + Seek>(
+	writer: &mut ByteWriter<W>,
+	is_empty: bool,
+	write: impl FnOnce(&mut ByteWriter<W>) -> Result<(), String>,
+) -> Result<u32, String> {
+	if is_empty {
+		return Ok(0);
+	}
+	writer.align(SECTION_ALIGNMENT)?;
+	let offset = offset_u32(writer.position()?)?;
+	write(writer)?;
+	Ok(offset)
+}
+
+/// Writes fixed size records, each followed by the data it points to, and returns the offset of the first one.
+/// `write` gets the offset at which the data of the record goes and returns where it ends.
+pub(crate) fn write_records<W: Write + Seek, T>(
+	writer: &mut ByteWriter<W>,
+	records: &[T],
+	record_size: u64,
+	write: impl Fn(&T, &mut ByteWriter<W>, u64) -> Result<u64, String>,
+) -> Result<u32, String> {
+	let start = writer.position()?;
+	let mut data_offset = start + record_size * records.len() as u64;
+	for (i, record) in records.iter().enumerate() {
+		writer.seek(start + record_size * i as u64)?;
+		data_offset = write(record, writer, data_offset)?;
 	}
-}
\ No newline at end of file
+	writer.seek(data_offset)?;
+	offset_u32(start)
+}
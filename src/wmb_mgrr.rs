@@ -1,24 +1,34 @@
-use std::{collections::{HashMap, HashSet}, io::{stdout, Cursor, Read, Seek}, ops};
+use std::{collections::HashMap, io::{stdout, Cursor, Read, Seek}, ops, sync::Arc};
 use std::io::Write;
 
-use image::{codecs::dds::DdsDecoder, EncodableLayout, ImageDecoder, ImageReader};
 use three_d::{Matrix4, SquareMatrix, Vector2, Vector3, Vector4};
 
-use crate::{byte_stream::ByteReader, mesh_data::TextureData, wta_wtp::WtaWtp};
-use crate::mesh_data::MeshData;
+use crate::{byte_stream::{ByteReader, ByteWriter}, load_options::{Game, LoadOptions}, mesh_data::EncodedTextureData, wta_wtp::WtaWtp};
+use crate::mesh_data::{BoneData, BoundsData, MaterialData, MaterialParameterGroupData, MaterialTextureData, MeshData, SceneData, ShaderNameData, SkeletonData};
+use crate::wmb::{count_u32, offset_u32, resolve_skin_weights, vertex_channel, write_records, write_section, write_uv, WmbInfo, WmbMaterialInfo};
 
 pub fn read_wmb_mgrr<R1: Read + Seek, R2: Read + Seek>(
+	name: &str,
 	reader: &mut ByteReader<R1>,
 	wta_wtp: &mut Option<WtaWtp<R2>>,
-	textures: &mut HashMap<u32, TextureData>,
-) -> Result<Vec<MeshData>, String> {
-	let wmb = Wmb::read(reader)?;
+	scene: &mut SceneData,
+	options: &LoadOptions,
+) -> Result<(), String> {
+	let SceneData { meshes, textures, skeletons, materials, warnings, .. } = scene;
+	let wmb = Wmb::read_sections(reader, !options.bounds_only)?;
+	let skeleton_index = if !wmb.bones.is_empty() {
+		skeletons.push(wmb.skeleton());
+		Some(skeletons.len() - 1)
+	} else {
+		None
+	};
+	let material_start_index = materials.len();
+	materials.extend(wmb.materials.iter().map(|m| m.to_material_data(&wmb.textures)));
 
-	let mut meshes: Vec<MeshData> = Vec::new();
 	let mut i = 0;
 
-	let mut batch_infos: Vec<Option<BatchData>> = Vec::with_capacity(wmb.batches.len());
-	for batch_data in wmb.batch_data_group.into_iter() {
+	let mut batch_infos: Vec<Option<&BatchData>> = Vec::with_capacity(wmb.batches.len());
+	for batch_data in wmb.batch_data_group.batch_data.iter().flatten() {
 		let i = batch_data.batch_index as usize;
 		for _ in batch_infos.len()..=i {
 			batch_infos.push(None);
@@ -30,12 +40,19 @@ pub fn read_wmb_mgrr<R1: Read + Seek, R2: Read + Seek>(
 	}
 
 	for mesh in wmb.meshes.iter() {
-		let batch_indices = mesh.batches
-			.iter()
-			.flatten()
-			.map(|i| *i as usize)
-			.collect::<HashSet<_>>();
-		for batch_i in batch_indices.into_iter() {
+		// a batch can be part of several groups, it's loaded once and keeps all of them
+		let mut batch_groups: Vec<(usize, Vec<usize>)> = Vec::new();
+		for (group_i, batches) in mesh.batches.iter().enumerate() {
+			for &batch_i in batches {
+				match batch_groups.iter_mut().find(|(i, _)| *i == batch_i as usize) {
+					Some((_, groups)) if groups.contains(&group_i) => {},
+					Some((_, groups)) => groups.push(group_i),
+					None => batch_groups.push((batch_i as usize, vec![group_i])),
+				}
+			}
+		}
+		batch_groups.retain(|(_, groups)| groups.iter().any(|&group_i| options.wmb4_batch_groups[group_i]));
+		for (batch_i, groups) in batch_groups.into_iter() {
 			i += 1;
 			print!("\r{} (textures: {}, current: {})                 ", i, textures.len(), mesh.name);
 			stdout().flush();
@@ -50,14 +67,27 @@ pub fn read_wmb_mgrr<R1: Read + Seek, R2: Read + Seek>(
 				.materials
 				.get(batch_data.material_index as usize)
 				.ok_or(format!("Material index out of bounds: {}", batch_data.material_index))?;
+			let material_index = Some(material_start_index + batch_data.material_index as usize);
+			let should_be_visible = options.visibility_rules.is_visible(Game::Mgrr, name, &mesh.name, None, material.shader_name.trim_end_matches('\0'));
+			let bounds = Some(mesh.bounding_box.to_bounds_data());
+			if options.bounds_only {
+				meshes.push(MeshData {
+					should_be_visible,
+					skeleton_index,
+					material_index,
+					bounds,
+					batch_groups: groups,
+					..MeshData::new(format!("{}/{}", mesh.name, batch_i))
+				});
+				continue;
+			}
 			let vertex_group = wmb
 				.vertex_groups
 				.get(batch.vertex_group_index as usize)
 				.ok_or(format!("Vertex group index out of bounds: {}", batch.vertex_group_index))?;
 			let indexes = vertex_group.indexes
 				.chunks(3)
-				.map(|i| [i[2], i[1], i[0]])
-				.flatten()
+				.flat_map(|i| [i[2], i[1], i[0]])
 				// .iter()
 				.skip(batch.index_start as usize)
 				.take(batch.num_indexes as usize)
@@ -75,15 +105,33 @@ pub fn read_wmb_mgrr<R1: Read + Seek, R2: Read + Seek>(
 					})
 					.collect()
 			);
+			let uv2 = vertex_channel(&vertex_group.vertexes, &vertex_group.vertex_ex_data, vertex_start, num_vertexes, |v| v.uv2, |v| v.uv2)
+				.map(|uvs| uvs.into_iter().map(|uv| Vector2::new(uv[0], uv[1])).collect());
+			let colors = vertex_channel(&vertex_group.vertexes, &vertex_group.vertex_ex_data, vertex_start, num_vertexes, |v| v.color, |v| v.color);
+
+			let first_vertex = vertex_group.vertexes.get(vertex_start);
+			let (bone_indices, bone_weights) = if skeleton_index.is_some() && first_vertex.is_some_and(|v| v.bone_indices.is_some()) {
+				let vertexes = vertex_group.vertexes.iter()
+					.skip(vertex_start)
+					.take(num_vertexes)
+					.map(|v| (v.bone_indices, v.bone_weights));
+				let (bone_indices, bone_weights, unresolved) = resolve_skin_weights(vertexes, |i| wmb.resolve_bone_index(batch_data.bone_sets_index, i));
+				if !unresolved.is_empty() {
+					warnings.push(format!("{}/{}: dropped weights of unresolvable bones {:?}", mesh.name, batch_i, unresolved));
+				}
+				(Some(bone_indices), Some(bone_weights))
+			} else {
+				(None, None)
+			};
 
 			let albedo_texture_id = material.get_albedo_texture_id(&wmb.textures);
 			let normal_texture_id = material.get_normal_texture_id(&wmb.textures);
 			if let Some(wta_wtp) = wta_wtp.as_mut() {
-				try_add_texture(textures, wta_wtp, albedo_texture_id, None);
-				try_add_texture(textures, wta_wtp, normal_texture_id, None);
+				try_add_texture(textures, wta_wtp, albedo_texture_id);
+				try_add_texture(textures, wta_wtp, normal_texture_id);
 			}
 			
-			let mesh_data = MeshData {
+			let mut mesh_data = MeshData {
 				name: format!("{}/{}", mesh.name, batch_i),
 				vertices: vertex_group.vertexes.iter()
 					.skip(vertex_start)
@@ -102,25 +150,52 @@ pub fn read_wmb_mgrr<R1: Read + Seek, R2: Read + Seek>(
 					.take(num_vertexes)
 					.map(|v| Vector2::new(v.uv[0], v.uv[1]))
 					.collect(),	
+				uv2,
+				uv3: None,
+				uv4: None,
+				uv5: None,
+				colors,
 				albedo_texture_id,
 				normal_texture_id,
 				mask_texture_id: None,
+				lightmap_texture_id: None,
 				transform: Matrix4::identity(),
 				uses_transparency: material.alpha_is_transparency(wta_wtp, &wmb.textures),
-				should_be_visible: !mesh.name.ends_with("_DEC"),
+				should_be_visible,
+				skeleton_index,
+				bone_indices,
+				bone_weights,
+				lod: None,
+				material_index,
+				bounds,
+				batch_groups: groups,
 			};
+			mesh_data.generate_missing_normals_and_tangents();
 			meshes.push(mesh_data);
 		}
 	}
 	println!();
 	
-	Ok(meshes)
+	Ok(())
+}
+
+pub fn read_wmb_mgrr_info<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<WmbInfo, String> {
+	let wmb = Wmb::read_sections(reader, false)?;
+	Ok(WmbInfo {
+		meshes: wmb.meshes.iter().map(|m| m.name.clone()).collect(),
+		materials: wmb.materials.iter().map(|m| WmbMaterialInfo {
+			name: String::new(),
+			shader_name: m.shader_name.trim_end_matches('\0').to_string(),
+			texture_ids: m.get_texture_ids(&wmb.textures),
+		}).collect(),
+	})
 }
 
-struct Point {
-	x: f32,
-	y: f32,
-	z: f32,
+#[derive(Clone, PartialEq)]
+pub struct Point {
+	pub x: f32,
+	pub y: f32,
+	pub z: f32,
 }
 
 impl Point {
@@ -131,6 +206,12 @@ impl Point {
 			z: reader.read_f32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		writer.write_f32(self.x)?;
+		writer.write_f32(self.y)?;
+		writer.write_f32(self.z)
+	}
 }
 
 impl ops::Add<f32> for Point {
@@ -157,26 +238,56 @@ impl ops::Div<f32> for Point {
 	}
 }
 
-struct Wmb {
-	vertex_groups: Vec<VertexGroup>,
-	batches: Vec<Batch>,
-	batch_data_group: Vec<BatchData>,
-	bones: Vec<Bone>,
-	bone_index_translate_table: BoneIndexTranslateTable,
-	bone_sets: Vec<BoneSet>,
-	materials: Vec<Material>,
-	textures: Vec<Texture>,
-	meshes: Vec<Mesh>,
+/// Sections are public and can be edited. While nothing changed `write` gives back the bytes that were read,
+/// after edits it lays out the sections anew and recomputes the offsets and counts of the header.
+#[derive(PartialEq)]
+pub struct Wmb {
+	header: Header,
+	pub vertex_groups: Vec<VertexGroup>,
+	pub batches: Vec<Batch>,
+	pub batch_data_group: BatchDataGroup,
+	pub bones: Vec<Bone>,
+	pub bone_index_translate_table: Option<BoneIndexTranslateTable>,
+	pub bone_sets: Vec<BoneSet>,
+	pub materials: Vec<Material>,
+	pub textures: Vec<Texture>,
+	pub meshes: Vec<Mesh>,
+	/// The bytes `read` parsed this from
+	source: Option<Arc<[u8]>>,
 }
 
+const HEADER_SIZE: u64 = 108;
+const BUFFER_ALIGNMENT: u64 = 16;
+
 impl Wmb {
-	fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
+	pub fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
+		let mut wmb = Self::read_sections(reader, true)?;
+		let size = reader.size()?;
+		reader.seek(0)?;
+		wmb.source = Some(reader.read(size as usize)?.into());
+		Ok(wmb)
+	}
+
+	/// Skipping the vertex groups is much faster when they aren't needed, but writing the result would drop them
+	fn read_sections<R: Read + Seek>(reader: &mut ByteReader<R>, read_vertex_groups: bool) -> Result<Self, String> {
 		let header = Header::read(reader)?;
+		let size = reader.size()?;
 		
 		let mut vertex_groups = Vec::with_capacity(header.num_vertex_groups as usize);
-		reader.seek(header.offset_vertex_groups as u64)?;
-		for _ in 0..header.num_vertex_groups {
-			vertex_groups.push(VertexGroup::read(reader, header.vertex_format)?);
+		if read_vertex_groups {
+			let vertex_format = VertexFormat::from_flags(header.vertex_format)?;
+			reader.seek(header.offset_vertex_groups as u64)?;
+			let mut group_headers = Vec::with_capacity(header.num_vertex_groups as usize);
+			for _ in 0..header.num_vertex_groups {
+				group_headers.push(VertexGroupHeader::read(reader)?);
+			}
+			// buffers of vertex groups end where the next buffer or section starts
+			let mut boundaries = header.section_offsets().to_vec();
+			boundaries.extend(group_headers.iter().flat_map(|h| h.buffer_offsets()));
+			boundaries.push(size as u32);
+			for group_header in group_headers {
+				vertex_groups.push(VertexGroup::read(reader, group_header, &vertex_format, &boundaries)?);
+			}
 		}
 
 		let mut batches = Vec::with_capacity(header.num_batches as usize);
@@ -187,11 +298,12 @@ impl Wmb {
 			}
 		}
 
-		let mut batch_data_group = None;
-		if header.offset_batch_description != 0 {
+		let batch_data_group = if header.offset_batch_description != 0 {
 			reader.seek(header.offset_batch_description as u64)?;
-			batch_data_group = Some(BatchDataGroup::read(reader)?);
-		}
+			BatchDataGroup::read(reader)?
+		} else {
+			BatchDataGroup::default()
+		};
 
 		let mut bones = Vec::with_capacity(header.num_bones as usize);
 		if header.offset_bones != 0 {
@@ -203,13 +315,9 @@ impl Wmb {
 
 		let bone_index_translate_table = if header.offset_bone_index_translate_table != 0 {
 			reader.seek(header.offset_bone_index_translate_table as u64)?;
-			BoneIndexTranslateTable::read(reader)?
+			Some(BoneIndexTranslateTable::read(reader)?)
 		} else {
-			BoneIndexTranslateTable {
-				first_level: [0; 16],
-				second_level: Vec::new(),
-				third_level: Vec::new(),
-			}
+			None
 		};
 
 		let mut bone_sets = Vec::with_capacity(header.num_bone_sets as usize);
@@ -245,20 +353,162 @@ impl Wmb {
 		}
 
 		Ok(Wmb {
+			header,
 			vertex_groups,
 			batches,
-			batch_data_group: batch_data_group.map(|b| b.batch_data).unwrap_or(Vec::new()),
+			batch_data_group,
 			bones,
 			bone_index_translate_table,
 			bone_sets,
 			materials,
 			textures,
 			meshes,
+			source: None,
 		})
 	}
+
+	pub fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		match &self.source {
+			Some(source) if self.is_unmodified(source) => {
+				writer.seek(0)?;
+				writer.write(source)
+			}
+			_ => self.write_layout(writer),
+		}
+	}
+
+	fn is_unmodified(&self, source: &Arc<[u8]>) -> bool {
+		Self::read_sections(&mut ByteReader::new(Cursor::new(source.as_ref())), true)
+			.is_ok_and(|original| Wmb { source: Some(source.clone()), ..original } == *self)
+	}
+
+	/// Lays the sections out one after another in header order
+	fn write_layout<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		let vertex_format = VertexFormat::from_flags(self.header.vertex_format)?;
+		writer.seek(HEADER_SIZE)?;
+
+		let offset_vertex_groups = write_section(writer, self.vertex_groups.is_empty(), |writer| {
+			write_records(writer, &self.vertex_groups, VertexGroup::SIZE, |vertex_group, writer, data_offset| {
+				vertex_group.write(writer, data_offset, &vertex_format)
+			}).map(|_| ())
+		})?;
+		let offset_batches = write_section(writer, self.batches.is_empty(), |writer| {
+			self.batches.iter().try_for_each(|batch| batch.write(writer))
+		})?;
+		let offset_batch_description = write_section(writer, self.batch_data_group.is_empty(), |writer| {
+			self.batch_data_group.write(writer)
+		})?;
+		let offset_bones = write_section(writer, self.bones.is_empty(), |writer| {
+			self.bones.iter().try_for_each(|bone| bone.write(writer))
+		})?;
+		let offset_bone_index_translate_table = match &self.bone_index_translate_table {
+			Some(table) => write_section(writer, false, |writer| table.write(writer))?,
+			None => 0,
+		};
+		let offset_bone_sets = write_section(writer, self.bone_sets.is_empty(), |writer| {
+			write_records(writer, &self.bone_sets, BoneSet::SIZE, BoneSet::write).map(|_| ())
+		})?;
+		let offset_materials = write_section(writer, self.materials.is_empty(), |writer| {
+			write_records(writer, &self.materials, Material::SIZE, Material::write).map(|_| ())
+		})?;
+		let offset_textures = write_section(writer, self.textures.is_empty(), |writer| {
+			self.textures.iter().try_for_each(|texture| texture.write(writer))
+		})?;
+		let offset_meshes = write_section(writer, self.meshes.is_empty(), |writer| {
+			write_records(writer, &self.meshes, Mesh::SIZE, Mesh::write).map(|_| ())
+		})?;
+
+		let header = Header {
+			id: self.header.id.clone(),
+			u_a: self.header.u_a,
+			vertex_format: self.header.vertex_format,
+			u_b: self.header.u_b,
+			u_c: self.header.u_c,
+			pos1: self.header.pos1.clone(),
+			pos2: self.header.pos2.clone(),
+			offset_vertex_groups,
+			num_vertex_groups: count_u32(self.vertex_groups.len())?,
+			offset_batches,
+			num_batches: count_u32(self.batches.len())?,
+			offset_batch_description,
+			offset_bones,
+			num_bones: count_u32(self.bones.len())?,
+			offset_bone_index_translate_table,
+			size_bone_index_translate_table: self.bone_index_translate_table.as_ref().map_or(Ok(0), |table| table.size())?,
+			offset_bone_sets,
+			num_bone_sets: count_u32(self.bone_sets.len())?,
+			offset_materials,
+			num_materials: count_u32(self.materials.len())?,
+			offset_textures,
+			num_textures: count_u32(self.textures.len())?,
+			offset_meshes,
+			num_meshes: count_u32(self.meshes.len())?,
+		};
+		writer.seek(0)?;
+		header.write(writer)
+	}
+
+	/// WMB4 bones only store positions, so all transforms are translations
+	pub fn skeleton(&self) -> SkeletonData {
+		let bones = self.bones.iter()
+			.map(|bone| {
+				let position = Vector3::new(bone.position.x, bone.position.y, bone.position.z);
+				let relative_position = Vector3::new(bone.relative_position.x, bone.relative_position.y, bone.relative_position.z);
+				BoneData {
+					id: bone.id,
+					parent_index: usize::try_from(bone.parent_index).ok().filter(|&i| i < self.bones.len()),
+					local_transform: Matrix4::from_translation(relative_position),
+					world_transform: Matrix4::from_translation(position),
+					inverse_bind_matrix: Matrix4::from_translation(-position),
+				}
+			})
+			.collect();
+		SkeletonData { bones }
+	}
+
+	/// Maps a vertex bone index of a batch to an index into the bones
+	pub fn resolve_bone_index(&self, bone_set_index: u16, local_index: u8) -> Option<u16> {
+		let bone_set = self.bone_sets.get(bone_set_index as usize)?;
+		let index = *bone_set.bone_indexes.get(local_index as usize)? as u16;
+		Some(index).filter(|&i| (i as usize) < self.bones.len())
+	}
+
+	/// Maps a bone id used by the game, e.g. in motion files, to an index into the bones
+	pub fn bone_index(&self, bone_id: i16) -> Option<usize> {
+		self.bone_index_translate_table.as_ref()?
+			.lookup(bone_id)
+			.filter(|&i| i < self.bones.len())
+	}
+
+	/// Model with no sections
+	pub fn new(header: Header) -> Self {
+		Wmb {
+			header,
+			vertex_groups: Vec::new(),
+			batches: Vec::new(),
+			batch_data_group: BatchDataGroup::default(),
+			bones: Vec::new(),
+			bone_index_translate_table: None,
+			bone_sets: Vec::new(),
+			materials: Vec::new(),
+			textures: Vec::new(),
+			meshes: Vec::new(),
+			source: None,
+		}
+	}
+
+	/// Offsets and counts are the ones read from the file, not updated by edits
+	pub fn header(&self) -> &Header {
+		&self.header
+	}
+
+	pub fn header_mut(&mut self) -> &mut Header {
+		&mut self.header
+	}
 }
 
-struct Header {
+#[derive(PartialEq)]
+pub struct Header {
 	id: String,
 	u_a: u32,
 	vertex_format: u32,
@@ -286,6 +536,36 @@ struct Header {
 }
 
 impl Header {
+	/// Header of an empty model, the offsets and counts are filled in by `Wmb::write`
+	pub fn new(vertex_format: u32, pos1: Point, pos2: Point) -> Self {
+		Header {
+			id: "WMB4".to_string(),
+			u_a: 0,
+			vertex_format,
+			u_b: 0,
+			u_c: 0,
+			pos1,
+			pos2,
+			offset_vertex_groups: 0,
+			num_vertex_groups: 0,
+			offset_batches: 0,
+			num_batches: 0,
+			offset_batch_description: 0,
+			offset_bones: 0,
+			num_bones: 0,
+			offset_bone_index_translate_table: 0,
+			size_bone_index_translate_table: 0,
+			offset_bone_sets: 0,
+			num_bone_sets: 0,
+			offset_materials: 0,
+			num_materials: 0,
+			offset_textures: 0,
+			num_textures: 0,
+			offset_meshes: 0,
+			num_meshes: 0,
+		}
+	}
+
 	fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
 		Ok(Header {
 			id: reader.read_string(4)?,
@@ -314,18 +594,95 @@ impl Header {
 			num_meshes: reader.read_u32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		writer.write_string(&self.id, 4)?;
+		writer.write_u32(self.u_a)?;
+		writer.write_u32(self.vertex_format)?;
+		writer.write_u16(self.u_b)?;
+		writer.write_i16(self.u_c)?;
+		self.pos1.write(writer)?;
+		self.pos2.write(writer)?;
+		for value in [
+			self.offset_vertex_groups,
+			self.num_vertex_groups,
+			self.offset_batches,
+			self.num_batches,
+			self.offset_batch_description,
+			self.offset_bones,
+			self.num_bones,
+			self.offset_bone_index_translate_table,
+			self.size_bone_index_translate_table,
+			self.offset_bone_sets,
+			self.num_bone_sets,
+			self.offset_materials,
+			self.num_materials,
+			self.offset_textures,
+			self.num_textures,
+			self.offset_meshes,
+			self.num_meshes,
+		] {
+			writer.write_u32(value)?;
+		}
+		Ok(())
+	}
+
+	/// Layout of every vertex in the vertex groups, see `VertexFormat`
+	pub fn vertex_format(&self) -> u32 {
+		self.vertex_format
+	}
+
+	pub fn set_vertex_format(&mut self, vertex_format: u32) {
+		self.vertex_format = vertex_format;
+	}
+
+	/// Bounding points of the whole model
+	pub fn bounding_points(&self) -> (&Point, &Point) {
+		(&self.pos1, &self.pos2)
+	}
+
+	pub fn set_bounding_points(&mut self, pos1: Point, pos2: Point) {
+		self.pos1 = pos1;
+		self.pos2 = pos2;
+	}
+
+	pub fn section_offsets(&self) -> [u32; 9] {
+		[
+			self.offset_vertex_groups,
+			self.offset_batches,
+			self.offset_batch_description,
+			self.offset_bones,
+			self.offset_bone_index_translate_table,
+			self.offset_bone_sets,
+			self.offset_materials,
+			self.offset_textures,
+			self.offset_meshes,
+		]
+	}
 }
 
-struct BoundingBox {
-	x: f32,
-	y: f32,
-	z: f32,
-	u: f32,
-	v: f32,
-	w: f32,
+#[derive(Clone, PartialEq)]
+pub struct BoundingBox {
+	pub x: f32,
+	pub y: f32,
+	pub z: f32,
+	pub u: f32,
+	pub v: f32,
+	pub w: f32,
 }
 
 impl BoundingBox {
+	pub fn new(center: [f32; 3], extents: [f32; 3]) -> Self {
+		BoundingBox {
+			x: center[0],
+			y: center[1],
+			z: center[2],
+			u: extents[0],
+			v: extents[1],
+			w: extents[2],
+		}
+	}
+
 	fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
 		Ok(BoundingBox {
 			x: reader.read_f32()?,
@@ -336,21 +693,38 @@ impl BoundingBox {
 			w: reader.read_f32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		for value in [self.x, self.y, self.z, self.u, self.v, self.w] {
+			writer.write_f32(value)?;
+		}
+		Ok(())
+	}
+
+	/// `x`, `y`, `z` are the center and `u`, `v`, `w` the half extents
+	fn to_bounds_data(&self) -> BoundsData {
+		BoundsData::from_center_extents(
+			Vector3::new(self.x, self.y, self.z),
+			Vector3::new(self.u, self.v, self.w),
+		)
+	}
 }
 
-struct Bone {
-	unknown_number: i16,
-	unknown_number2: i16,
-	parent_index: i16,
-	u_b: i16,
-	relative_position: Point,
-	position: Point,
+#[derive(PartialEq)]
+pub struct Bone {
+	/// Bone id used by the game, see `BoneIndexTranslateTable`
+	pub id: i16,
+	pub unknown_number2: i16,
+	pub parent_index: i16,
+	pub u_b: i16,
+	pub relative_position: Point,
+	pub position: Point,
 }
 
 impl Bone {
 	fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
 		Ok(Bone {
-			unknown_number: reader.read_i16()?,
+			id: reader.read_i16()?,
 			unknown_number2: reader.read_i16()?,
 			parent_index: reader.read_i16()?,
 			u_b: reader.read_i16()?,
@@ -358,39 +732,39 @@ impl Bone {
 			position: Point::read(reader)?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		writer.write_i16(self.id)?;
+		writer.write_i16(self.unknown_number2)?;
+		writer.write_i16(self.parent_index)?;
+		writer.write_i16(self.u_b)?;
+		self.relative_position.write(writer)?;
+		self.position.write(writer)
+	}
 }
 
-struct BoneIndexTranslateTable {
-	first_level: [i16; 16],
-	second_level: Vec<i16>,
-	third_level: Vec<i16>,
+#[derive(PartialEq)]
+pub struct BoneIndexTranslateTable {
+	pub first_level: [i16; 16],
+	pub second_level: Vec<i16>,
+	pub third_level: Vec<i16>,
 }
 
 impl BoneIndexTranslateTable {
 	fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
 		let mut first_level = [0; 16];
-		for i in 0..16 {
-			first_level[i] = reader.read_i16()?;
+		for value in first_level.iter_mut() {
+			*value = reader.read_i16()?;
 		}
 
-		let mut j = 0;
-		for i in 0..16 {
-			if first_level[i] != -1 {
-				j += 1;
-			}
-		}
+		let j = first_level.iter().filter(|&&value| value != -1).count();
 
 		let mut second_level = Vec::new();
 		for _ in 0..j*16 {
 			second_level.push(reader.read_i16()?);
 		}
 
-		let mut k = 0;
-		for i in 0..j*16 {
-			if second_level[i] != -1 {
-				k += 1;
-			}
-		}
+		let k = second_level.iter().filter(|&&value| value != -1).count();
 
 		let mut third_level = Vec::new();
 		for _ in 0..k*16 {
@@ -403,21 +777,92 @@ impl BoneIndexTranslateTable {
 			third_level,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		for value in self.first_level.iter().chain(&self.second_level).chain(&self.third_level) {
+			writer.write_i16(*value)?;
+		}
+		Ok(())
+	}
+
+	/// In bytes
+	fn size(&self) -> Result<u32, String> {
+		count_u32((16 + self.second_level.len() + self.third_level.len()) * 2)
+	}
+
+	/// Each level is indexed by 4 bits of the id. Entries are offsets into the whole table, which starts with the 16 first level entries.
+	fn lookup(&self, bone_id: i16) -> Option<usize> {
+		let bone_id = usize::try_from(bone_id).ok().filter(|&id| id < 0x1000)?;
+		let entry = |offset: usize| -> Option<i16> {
+			if offset < 16 {
+				Some(self.first_level[offset])
+			} else if offset - 16 < self.second_level.len() {
+				Some(self.second_level[offset - 16])
+			} else {
+				self.third_level.get(offset - 16 - self.second_level.len()).copied()
+			}
+		};
+		let second_level_offset = usize::try_from(entry(bone_id >> 8)?).ok()?;
+		let third_level_offset = usize::try_from(entry(second_level_offset + ((bone_id >> 4) & 0xF))?).ok()?;
+		usize::try_from(entry(third_level_offset + (bone_id & 0xF))?).ok()
+	}
+}
+
+/// Which optional attributes follow the position, uv, normal and tangent of each vertex
+struct VertexFormat {
+	flags: u32,
+	bones: bool,
+	color: bool,
+	uv2: bool,
+	ex_color: bool,
+	ex_uv2: bool,
+}
+
+impl VertexFormat {
+	fn from_flags(flags: u32) -> Result<Self, String> {
+		let (bones, color, uv2, ex_color, ex_uv2) = match flags {
+			0x10107 => (false, true, false, false, false),
+			0x10307 => (false, true, true, false, false),
+			0x00137 => (true, false, false, false, false),
+			0x10137 => (true, false, false, true, false),
+			0x00337 | 0x10337 => (true, false, false, true, true),
+			_ => return Err(format!("Unknown WMB4 vertex format 0x{:X}", flags)),
+		};
+		Ok(VertexFormat {
+			flags,
+			bones,
+			color,
+			uv2,
+			ex_color,
+			ex_uv2,
+		})
+	}
+
+	/// Position, uv, normal and tangent take 24 bytes, bone indices and weights 8, colors and uv2 4 each
+	fn vertex_size(&self) -> u32 {
+		24 + 8 * self.bones as u32 + 4 * self.color as u32 + 4 * self.uv2 as u32
+	}
+
+	fn vertex_ex_data_size(&self) -> u32 {
+		4 * self.ex_color as u32 + 4 * self.ex_uv2 as u32
+	}
 }
 
-struct Vertex {
-	position: Point,
-	uv: [f32; 2],
-	normal: Point,
-	tangent: [f32; 4],
-	color: Option<[u8; 4]>,
-	uv2: Option<[f32; 2]>,
-	bone_indices: Option<[u8; 4]>,
-	bone_weights: Option<[f32; 4]>,
+#[derive(PartialEq)]
+pub struct Vertex {
+	pub position: Point,
+	pub uv: [f32; 2],
+	/// Stored as 11/11/10 bit signed fixed point, so it is quantized when written
+	pub normal: Point,
+	pub tangent: [f32; 4],
+	pub color: Option<[u8; 4]>,
+	pub uv2: Option<[f32; 2]>,
+	pub bone_indices: Option<[u8; 4]>,
+	pub bone_weights: Option<[f32; 4]>,
 }
 
 impl Vertex {
-	fn read<R: Read + Seek>(reader: &mut ByteReader<R>, vertex_flags: u32) -> Result<Self, String> {
+	fn read<R: Read + Seek>(reader: &mut ByteReader<R>, format: &VertexFormat) -> Result<Self, String> {
 		let position = Point::read(reader)?;
 		let uv = [
 			reader.read_f16()?.to_f32(),
@@ -459,7 +904,7 @@ impl Vertex {
 		let mut bone_indices = None;
 		let mut bone_weights = None;
 
-		if (vertex_flags & 0x137) == 0x137 {
+		if format.bones {
 			bone_indices = Some([
 				reader.read_u8()?,
 				reader.read_u8()?,
@@ -472,24 +917,20 @@ impl Vertex {
 				reader.read_u8()? as f32 / 255.0,
 				reader.read_u8()? as f32 / 255.0,
 			]);
-		} else if vertex_flags == 0x10307 {
+		}
+		if format.color {
 			color = Some([
 				reader.read_u8()?,
 				reader.read_u8()?,
 				reader.read_u8()?,
 				reader.read_u8()?,
 			]);
+		}
+		if format.uv2 {
 			uv2 = Some([
 				reader.read_f16()?.to_f32(),
 				reader.read_f16()?.to_f32(),
 			]);
-		} else if vertex_flags == 0x10107 {
-			color = Some([
-				reader.read_u8()?,
-				reader.read_u8()?,
-				reader.read_u8()?,
-				reader.read_u8()?,
-			]);
 		}
 
 		Ok(Vertex {
@@ -503,6 +944,32 @@ impl Vertex {
 			bone_weights,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, format: &VertexFormat) -> Result<(), String> {
+		self.position.write(writer)?;
+		write_uv(writer, self.uv)?;
+		let normal_x = (self.normal.x.clamp(-1.0, 1.0) * ((1 << 10) - 1) as f32).round() as i64 & ((1 << 11) - 1);
+		let normal_y = (self.normal.y.clamp(-1.0, 1.0) * ((1 << 10) - 1) as f32).round() as i64 & ((1 << 11) - 1);
+		let normal_z = (self.normal.z.clamp(-1.0, 1.0) * ((1 << 9) - 1) as f32).round() as i64 & ((1 << 10) - 1);
+		writer.write_u32((normal_x | normal_y << 11 | normal_z << 22) as u32)?;
+		for value in self.tangent {
+			writer.write_u8((value * 127.0 + 127.0).round().clamp(0.0, 255.0) as u8)?;
+		}
+
+		if format.bones {
+			writer.write(&self.bone_indices.unwrap_or_default())?;
+			for weight in self.bone_weights.unwrap_or_default() {
+				writer.write_u8((weight * 255.0).round().clamp(0.0, 255.0) as u8)?;
+			}
+		}
+		if format.color {
+			writer.write(&self.color.unwrap_or_default())?;
+		}
+		if format.uv2 {
+			write_uv(writer, self.uv2.unwrap_or_default())?;
+		}
+		Ok(())
+	}
 }
 
 struct VertexGroupHeader {
@@ -527,36 +994,69 @@ impl VertexGroupHeader {
 			num_indexes: reader.read_u32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		for value in [
+			self.vertex_offset,
+			self.vertex_ex_data_offset,
+			self.unknown1_offset,
+			self.unknown2_offset,
+			self.num_vertexes,
+			self.index_buffer_offset,
+			self.num_indexes,
+		] {
+			writer.write_u32(value)?;
+		}
+		Ok(())
+	}
+
+	fn buffer_offsets(&self) -> [u32; 5] {
+		[self.vertex_offset, self.vertex_ex_data_offset, self.unknown1_offset, self.unknown2_offset, self.index_buffer_offset]
+	}
+
+	/// Buffers end at the next buffer or section, with a wrong stride the vertices don't fit
+	fn validate_buffer_size(&self, offset: u32, stride: u32, boundaries: &[u32], vertex_flags: u32) -> Result<(), String> {
+		let end = boundaries.iter()
+			.map(|&boundary| boundary as u64)
+			.filter(|&boundary| boundary > offset as u64)
+			.min()
+			.unwrap_or(offset as u64);
+		let available = end - offset as u64;
+		let needed = self.num_vertexes as u64 * stride as u64;
+		if needed > available {
+			return Err(format!(
+				"Vertex format 0x{:X} with a stride of {} doesn't match the buffer at 0x{:X}: {} vertices need {} bytes, but there are {}",
+				vertex_flags, stride, offset, self.num_vertexes, needed, available,
+			));
+		}
+		Ok(())
+	}
 }
 
-struct VertexExData {
-	color: Option<[u8; 4]>,
-	uv2: Option<[f32; 2]>,
+#[derive(PartialEq)]
+pub struct VertexExData {
+	pub color: Option<[u8; 4]>,
+	pub uv2: Option<[f32; 2]>,
 }
 
 impl VertexExData {
-	fn read<R: Read + Seek>(reader: &mut ByteReader<R>, vertex_flags: u32) -> Result<Self, String> {
+	fn read<R: Read + Seek>(reader: &mut ByteReader<R>, format: &VertexFormat) -> Result<Self, String> {
 		let mut color = None;
 		let mut uv2 = None;
 
-		if (vertex_flags & 0x337) == 0x337 {
+		if format.ex_color {
 			color = Some([
 				reader.read_u8()?,
 				reader.read_u8()?,
 				reader.read_u8()?,
 				reader.read_u8()?,
 			]);
+		}
+		if format.ex_uv2 {
 			uv2 = Some([
 				reader.read_f16()?.to_f32(),
 				reader.read_f16()?.to_f32(),
 			]);
-		} else if vertex_flags == 0x10137 {
-			color = Some([
-				reader.read_u8()?,
-				reader.read_u8()?,
-				reader.read_u8()?,
-				reader.read_u8()?,
-			]);
 		}
 
 		Ok(VertexExData {
@@ -564,31 +1064,48 @@ impl VertexExData {
 			uv2,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, format: &VertexFormat) -> Result<(), String> {
+		if format.ex_color {
+			writer.write(&self.color.unwrap_or_default())?;
+		}
+		if format.ex_uv2 {
+			write_uv(writer, self.uv2.unwrap_or_default())?;
+		}
+		Ok(())
+	}
 }
 
-struct VertexGroup {
-	vertexes: Vec<Vertex>,
-	vertex_ex_data: Vec<VertexExData>,
-	indexes: Vec<u32>,
+#[derive(PartialEq)]
+pub struct VertexGroup {
+	pub vertexes: Vec<Vertex>,
+	/// Either empty or one entry per vertex
+	pub vertex_ex_data: Vec<VertexExData>,
+	/// Raw bytes of the unknown buffers
+	pub unknown1: Vec<u8>,
+	pub unknown2: Vec<u8>,
+	pub indexes: Vec<u32>,
 }
 
 impl VertexGroup {
-	fn read<R: Read + Seek>(reader: &mut ByteReader<R>, vertex_flags: u32) -> Result<Self, String> {
-		let header = VertexGroupHeader::read(reader)?;
+	fn read<R: Read + Seek>(reader: &mut ByteReader<R>, header: VertexGroupHeader, format: &VertexFormat, boundaries: &[u32]) -> Result<Self, String> {
+		header.validate_buffer_size(header.vertex_offset, format.vertex_size(), boundaries, format.flags)?;
+		if header.vertex_ex_data_offset != 0 && format.vertex_ex_data_size() != 0 {
+			header.validate_buffer_size(header.vertex_ex_data_offset, format.vertex_ex_data_size(), boundaries, format.flags)?;
+		}
 		let mut vertexes = Vec::with_capacity(header.num_vertexes as usize);
 		let mut vertex_ex_data = Vec::with_capacity(header.num_vertexes as usize);
 		let mut indexes = Vec::with_capacity(header.num_indexes as usize);
-		let pos = reader.position()?;
 
 		reader.seek(header.vertex_offset as u64)?;
 		for _ in 0..header.num_vertexes {
-			vertexes.push(Vertex::read(reader, vertex_flags)?);
+			vertexes.push(Vertex::read(reader, format)?);
 		}
 
 		if header.vertex_ex_data_offset != 0 {
 			reader.seek(header.vertex_ex_data_offset as u64)?;
 			for _ in 0..header.num_vertexes {
-				vertex_ex_data.push(VertexExData::read(reader, vertex_flags)?);
+				vertex_ex_data.push(VertexExData::read(reader, format)?);
 			}
 		}
 
@@ -597,22 +1114,98 @@ impl VertexGroup {
 			indexes.push(reader.read_u16()? as u32);
 		}
 
-		reader.seek(pos)?;
+		let unknown1 = VertexGroup::read_unknown_buffer(reader, header.unknown1_offset, boundaries)?;
+		let unknown2 = VertexGroup::read_unknown_buffer(reader, header.unknown2_offset, boundaries)?;
 
 		Ok(VertexGroup {
 			vertexes,
 			vertex_ex_data,
+			unknown1,
+			unknown2,
 			indexes,
 		})
 	}
+
+	fn read_unknown_buffer<R: Read + Seek>(reader: &mut ByteReader<R>, offset: u32, boundaries: &[u32]) -> Result<Vec<u8>, String> {
+		if offset == 0 {
+			return Ok(Vec::new());
+		}
+		let end = boundaries.iter()
+			.copied()
+			.filter(|&boundary| boundary > offset)
+			.min()
+			.unwrap_or(offset);
+		reader.seek(offset as u64)?;
+		reader.read((end - offset) as usize)
+	}
+
+	const SIZE: u64 = 28;
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, data_offset: u64, format: &VertexFormat) -> Result<u64, String> {
+		let pos = writer.position()?;
+		writer.seek(data_offset)?;
+
+		writer.align(BUFFER_ALIGNMENT)?;
+		let vertex_offset = offset_u32(writer.position()?)?;
+		for vertex in &self.vertexes {
+			vertex.write(writer, format)?;
+		}
+
+		let vertex_ex_data_offset = if self.vertex_ex_data.is_empty() || format.vertex_ex_data_size() == 0 {
+			0
+		} else if self.vertex_ex_data.len() != self.vertexes.len() {
+			return Err(format!("{} vertices but {} vertex ex data entries", self.vertexes.len(), self.vertex_ex_data.len()));
+		} else {
+			writer.align(BUFFER_ALIGNMENT)?;
+			let offset = offset_u32(writer.position()?)?;
+			for vertex_ex_data in &self.vertex_ex_data {
+				vertex_ex_data.write(writer, format)?;
+			}
+			offset
+		};
+
+		let unknown1_offset = VertexGroup::write_unknown_buffer(writer, &self.unknown1)?;
+		let unknown2_offset = VertexGroup::write_unknown_buffer(writer, &self.unknown2)?;
+
+		writer.align(BUFFER_ALIGNMENT)?;
+		let index_buffer_offset = offset_u32(writer.position()?)?;
+		for index in &self.indexes {
+			let index = u16::try_from(*index).map_err(|_| format!("Index {} doesn't fit in the 16 bit index buffer", index))?;
+			writer.write_u16(index)?;
+		}
+		let end = writer.position()?;
+
+		writer.seek(pos)?;
+		VertexGroupHeader {
+			vertex_offset,
+			vertex_ex_data_offset,
+			unknown1_offset,
+			unknown2_offset,
+			num_vertexes: count_u32(self.vertexes.len())?,
+			index_buffer_offset,
+			num_indexes: count_u32(self.indexes.len())?,
+		}.write(writer)?;
+		Ok(end)
+	}
+
+	fn write_unknown_buffer<W: Write + Seek>(writer: &mut ByteWriter<W>, buffer: &[u8]) -> Result<u32, String> {
+		if buffer.is_empty() {
+			return Ok(0);
+		}
+		writer.align(BUFFER_ALIGNMENT)?;
+		let offset = offset_u32(writer.position()?)?;
+		writer.write(buffer)?;
+		Ok(offset)
+	}
 }
 
-struct Batch {
-	vertex_group_index: u32,
-	vertex_start: i32,
-	index_start: i32,
-	num_vertices: u32,
-	num_indexes: u32,
+#[derive(PartialEq)]
+pub struct Batch {
+	pub vertex_group_index: u32,
+	pub vertex_start: i32,
+	pub index_start: i32,
+	pub num_vertices: u32,
+	pub num_indexes: u32,
 }
 
 impl Batch {
@@ -625,14 +1218,23 @@ impl Batch {
 			num_indexes: reader.read_u32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		writer.write_u32(self.vertex_group_index)?;
+		writer.write_i32(self.vertex_start)?;
+		writer.write_i32(self.index_start)?;
+		writer.write_u32(self.num_vertices)?;
+		writer.write_u32(self.num_indexes)
+	}
 }
 
-struct BatchData {
-	batch_index: u32,
-	mesh_index: u32,
-	material_index: u16,
-	bone_sets_index: u16,
-	u_a: u32,
+#[derive(PartialEq)]
+pub struct BatchData {
+	pub batch_index: u32,
+	pub mesh_index: u32,
+	pub material_index: u16,
+	pub bone_sets_index: u16,
+	pub u_a: u32,
 }
 
 impl BatchData {
@@ -645,16 +1247,26 @@ impl BatchData {
 			u_a: reader.read_u32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		writer.write_u32(self.batch_index)?;
+		writer.write_u32(self.mesh_index)?;
+		writer.write_u16(self.material_index)?;
+		writer.write_u16(self.bone_sets_index)?;
+		writer.write_u32(self.u_a)
+	}
 }
 
-struct BatchDataGroup {
-	batch_data: Vec<BatchData>,
+#[derive(Default, PartialEq)]
+pub struct BatchDataGroup {
+	/// Batch data of each of the four batch groups
+	pub batch_data: [Vec<BatchData>; 4],
 }
 
 impl BatchDataGroup {
 	fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
-		let mut batch_data = Vec::with_capacity(4);
-		for _ in 0..4 {
+		let mut batch_data: [Vec<BatchData>; 4] = Default::default();
+		for batch_data in batch_data.iter_mut() {
 			let offset = reader.read_u32()?;
 			let num = reader.read_u32()?;
 			if offset != 0 {
@@ -671,11 +1283,35 @@ impl BatchDataGroup {
 			batch_data,
 		})
 	}
+
+	fn is_empty(&self) -> bool {
+		self.batch_data.iter().all(|batch_data| batch_data.is_empty())
+	}
+
+	/// The offsets and counts of the four groups, followed by their batch data
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		let start = writer.position()?;
+		let mut data_offset = start + 8 * self.batch_data.len() as u64;
+		for (i, batch_data) in self.batch_data.iter().enumerate() {
+			let offset = if batch_data.is_empty() { 0 } else { offset_u32(data_offset)? };
+			writer.seek(start + 8 * i as u64)?;
+			writer.write_u32(offset)?;
+			writer.write_u32(count_u32(batch_data.len())?)?;
+			writer.seek(data_offset)?;
+			for data in batch_data {
+				data.write(writer)?;
+			}
+			data_offset = writer.position()?;
+		}
+		writer.seek(data_offset)?;
+		Ok(())
+	}
 }
 
 
-struct BoneSet {
-	bone_indexes: Vec<u8>,
+#[derive(PartialEq)]
+pub struct BoneSet {
+	pub bone_indexes: Vec<u8>,
 }
 
 impl BoneSet {
@@ -696,10 +1332,23 @@ impl BoneSet {
 			bone_indexes,
 		})
 	}
+
+	const SIZE: u64 = 8;
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, data_offset: u64) -> Result<u64, String> {
+		writer.write_u32(offset_u32(data_offset)?)?;
+		writer.write_u32(count_u32(self.bone_indexes.len())?)?;
+
+		writer.seek(data_offset)?;
+		writer.write(&self.bone_indexes)?;
+		writer.position()
+	}
 }
 
-struct TextureIndices {
-	flags_indices: [(u32, u32); 4],
+/// Flags and indices into `Wmb::textures` of the four texture slots of a material
+#[derive(PartialEq)]
+pub struct TextureIndices {
+	pub flags_indices: [(u32, u32); 4],
 }
 
 impl TextureIndices {
@@ -712,46 +1361,63 @@ impl TextureIndices {
 		]})
 	}
 
-	fn get_albedo_index(&self) -> Option<u32> {
-		for (flag, index) in self.flags_indices {
-			if flag == 0 || flag == 1 {
-				return Some(index);
-			}
-		}
-		None
+	/// Usage of the texture flags the viewer relies on, flags 0 and 1 are both albedo layers
+	const SLOTS: [(u32, &'static str); 3] = [
+		(0, "albedo"),
+		(1, "albedo"),
+		(2, "normal"),
+	];
+
+	/// Only the first `num_textures` slots are used
+	fn get_indices<'a>(&'a self, num_textures: u16, slot: &'a str) -> impl Iterator<Item = u32> + 'a {
+		self.flags_indices.iter()
+			.take(num_textures as usize)
+			.filter(move |(flag, _)| Self::SLOTS.contains(&(*flag, slot)))
+			.map(|(_, index)| *index)
 	}
 
-	fn get_normal_index(&self) -> Option<u32> {
+	fn get_index(&self, num_textures: u16, slot: &str) -> Option<u32> {
+		self.get_indices(num_textures, slot).next()
+	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
 		for (flag, index) in self.flags_indices {
-			if flag == 2 {
-				return Some(index);
-			}
+			writer.write_u32(flag)?;
+			writer.write_u32(index)?;
 		}
-		None
+		Ok(())
 	}
 
-	fn get_albedo_indices(&self) -> impl Iterator<Item = u32> + use<'_> {
-		self.flags_indices.iter()
-			.filter(|(flag, _)| *flag == 0 || *flag == 1)
-			.map(|(_, index)| *index)
+	/// Usage of a texture with these flags, `flag_N` for flags of unknown usage
+	fn slot_name(flag: u32) -> String {
+		Self::SLOTS.iter()
+			.find(|(slot_flag, _)| *slot_flag == flag)
+			.map_or_else(|| format!("flag_{}", flag), |(_, name)| name.to_string())
 	}
 }
 
-struct Material {
-	shader_name: String,
-	texture_indices: TextureIndices,
-	parameters: Vec<f32>,
+#[derive(PartialEq)]
+pub struct Material {
+	/// At most 16 bytes
+	pub shader_name: String,
+	pub texture_indices: TextureIndices,
+	/// How many of the texture slots are used
+	pub num_textures: u16,
+	pub parameters: Vec<f32>,
+	pub u_a: u32,
+	pub u_c: u16,
+	pub u_d: u16,
 }
 
 impl Material {
 	fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
 		let offset_shader_name = reader.read_u32()?;
 		let offset_textures = reader.read_u32()?;
-		let _u_a = reader.read_u32()?;
+		let u_a = reader.read_u32()?;
 		let offset_parameters = reader.read_u32()?;
-		let _num_textures = reader.read_u16()?;
-		let _u_c = reader.read_u16()?;
-		let _u_d = reader.read_u16()?;
+		let num_textures = reader.read_u16()?;
+		let u_c = reader.read_u16()?;
+		let u_d = reader.read_u16()?;
 		let num_parameters = reader.read_u16()?;
 		let pos = reader.position()?;
 
@@ -772,38 +1438,102 @@ impl Material {
 		Ok(Material {
 			shader_name,
 			texture_indices,
+			num_textures,
 			parameters,
+			u_a,
+			u_c,
+			u_d,
 		})
 	}
 
-	fn get_albedo_texture_id(&self, textures: &Vec<Texture>) -> Option<u32> {
-		self.texture_indices.get_albedo_index()
+	const SIZE: u64 = 24;
+
+	/// The shader name, texture slots and parameters follow the record
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, data_offset: u64) -> Result<u64, String> {
+		if self.shader_name.len() > 16 {
+			return Err(format!("Shader name {} is longer than 16 bytes", self.shader_name));
+		}
+		let offset_shader_name = data_offset;
+		let offset_textures = offset_shader_name + 16;
+		let offset_parameters = offset_textures + 32;
+		writer.write_u32(offset_u32(offset_shader_name)?)?;
+		writer.write_u32(offset_u32(offset_textures)?)?;
+		writer.write_u32(self.u_a)?;
+		writer.write_u32(offset_u32(offset_parameters)?)?;
+		writer.write_u16(self.num_textures)?;
+		writer.write_u16(self.u_c)?;
+		writer.write_u16(self.u_d)?;
+		writer.write_u16(u16::try_from(self.parameters.len()).map_err(|_| format!("Too many material parameters: {}", self.parameters.len()))?)?;
+
+		writer.seek(offset_shader_name)?;
+		writer.write_string(&self.shader_name, 16)?;
+		self.texture_indices.write(writer)?;
+		for parameter in &self.parameters {
+			writer.write_f32(*parameter)?;
+		}
+		writer.position()
+	}
+
+	fn to_material_data(&self, textures: &[Texture]) -> MaterialData {
+		let shader_name = self.shader_name.trim_end_matches('\0').to_string();
+		MaterialData {
+			name: String::new(),
+			shader_name_parts: ShaderNameData::parse(&shader_name),
+			shader_name,
+			technique_name: String::new(),
+			textures: self.texture_indices.flags_indices.iter()
+				.take(self.num_textures as usize)
+				.filter_map(|&(flag, index)| textures.get(index as usize).map(|texture| MaterialTextureData {
+					slot: TextureIndices::slot_name(flag),
+					id: texture.id,
+					flags: Some(flag),
+				}))
+				.collect(),
+			parameter_groups: self.parameters
+				.chunks(4)
+				.enumerate()
+				.map(|(i, parameters)| MaterialParameterGroupData {
+					index: i as i32,
+					parameters: parameters.to_vec(),
+				})
+				.collect(),
+			variables: Vec::new(),
+		}
+	}
+
+	fn get_albedo_texture_id(&self, textures: &[Texture]) -> Option<u32> {
+		self.texture_indices.get_index(self.num_textures, "albedo")
 			.and_then(|index| textures.get(index as usize))
 			.map(|texture| texture.id)	
 	}
 
-	fn get_normal_texture_id(&self, textures: &Vec<Texture>) -> Option<u32> {
-		self.texture_indices.get_normal_index()
+	fn get_normal_texture_id(&self, textures: &[Texture]) -> Option<u32> {
+		self.texture_indices.get_index(self.num_textures, "normal")
 			.and_then(|index| textures.get(index as usize))
 			.map(|texture| texture.id)
 	}
 
-	fn alpha_is_transparency<F: Read + Seek>(&self, wta_wtp: &mut Option<WtaWtp<F>>, textures: &Vec<Texture>) -> bool {
+	fn get_texture_ids(&self, textures: &[Texture]) -> Vec<u32> {
+		self.texture_indices.flags_indices.iter()
+			.take(self.num_textures as usize)
+			.filter_map(|(_, index)| textures.get(*index as usize))
+			.map(|texture| texture.id)
+			.collect()
+	}
+
+	fn alpha_is_transparency<F: Read + Seek>(&self, wta_wtp: &mut Option<WtaWtp<F>>, textures: &[Texture]) -> bool {
 		if let Some(wta_wtp) = wta_wtp {
 			if self.shader_name.len() >= 5 {
 				const ORGANIC_PREFIXES: [&str; 3] = ["eye", "har", "skn"];
 				let is_organic = ORGANIC_PREFIXES.iter().any(|prefix| self.shader_name.starts_with(prefix));
-				let albedo_tex_count = self.texture_indices.get_albedo_indices()
-					.map(|index| textures.get(index as usize))
-					.flatten()
+				let albedo_tex_count = self.texture_indices.get_indices(self.num_textures, "albedo")
+					.filter_map(|index| textures.get(index as usize))
 					.filter(|texture| wta_wtp.has_id(texture.id))
 					.count();
-				if albedo_tex_count == 1 || (is_organic && albedo_tex_count >= 1) {
-					if &self.shader_name[3..5] != "00" || is_organic {
-						if &self.shader_name[4..5] != "0" || &self.shader_name[0..3] == "har" {
-							return  true;
-						}
-					}
+				if (albedo_tex_count == 1 || (is_organic && albedo_tex_count >= 1))
+					&& (&self.shader_name[3..5] != "00" || is_organic)
+					&& (&self.shader_name[4..5] != "0" || &self.shader_name[0..3] == "har") {
+					return  true;
 				}
 			}
 		}
@@ -812,9 +1542,10 @@ impl Material {
 }
 
 
-struct Texture {
-	flags: u32,
-	id: u32,
+#[derive(PartialEq)]
+pub struct Texture {
+	pub flags: u32,
+	pub id: u32,
 }
 
 impl Texture {
@@ -824,12 +1555,20 @@ impl Texture {
 			id: reader.read_u32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		writer.write_u32(self.flags)?;
+		writer.write_u32(self.id)
+	}
 }
 
-struct Mesh {
-	name: String,
-	bounding_box: BoundingBox,
-	batches: Vec<Vec<u16>>,
+#[derive(PartialEq)]
+pub struct Mesh {
+	pub name: String,
+	pub bounding_box: BoundingBox,
+	/// Batch indices of each of the four batch groups
+	pub batches: [Vec<u16>; 4],
+	pub materials: Vec<u16>,
 }
 
 impl Mesh {
@@ -849,15 +1588,13 @@ impl Mesh {
 		reader.seek(offset_name as u64)?;
 		let name = reader.read_string_zero_term()?;
 
-		let mut batches = Vec::new();
-		for i in 0..4 {
-			if batch_info[i].0 != 0 {
-				reader.seek(batch_info[i].0 as u64)?;
-				let mut batch = Vec::with_capacity(batch_info[i].1 as usize);
-				for _ in 0..batch_info[i].1 {
+		let mut batches: [Vec<u16>; 4] = Default::default();
+		for (batch, (offset, num)) in batches.iter_mut().zip(batch_info) {
+			if offset != 0 {
+				reader.seek(offset as u64)?;
+				for _ in 0..num {
 					batch.push(reader.read_u16()?);
 				}
-				batches.push(batch);
 			}
 		}
 
@@ -873,48 +1610,458 @@ impl Mesh {
 			name,
 			bounding_box,
 			batches,
+			materials,
 		})
 	}
-}
 
-
-fn decompress_dds(bytes: Vec<u8>, swizzle: Option<&dyn Fn(&mut[u8]) -> ()>) -> Result<TextureData, String> {
-	const DDS_MAGIC: [u8; 4] = [0x44, 0x44, 0x53, 0x20];
-	const PNG_MAGIC: [u8; 4] = [0x89, 0x50, 0x4E, 0x47];
-	if bytes.len() > 4 && bytes[0..4] == DDS_MAGIC {
-		let dds_decoder = DdsDecoder::new(bytes.as_slice()).map_err(|e| e.to_string())?;
-		let (width, height) = dds_decoder.dimensions();
-		let buffer_size = dds_decoder.total_bytes() as usize;
-		let mut buffer = vec![0; buffer_size];
-		dds_decoder.read_image(&mut buffer).map_err(|e| e.to_string())?;
-		if let Some(swizzle) = swizzle {
-			for i in (0..buffer.len()).step_by(4) {
-				swizzle(&mut buffer[i..i + 4]);
+	const SIZE: u64 = 68;
+
+	/// The name, batch indices and materials follow the record
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, data_offset: u64) -> Result<u64, String> {
+		let pos = writer.position()?;
+		writer.seek(data_offset)?;
+		let offset_name = offset_u32(writer.position()?)?;
+		writer.write_string_zero_term(&self.name)?;
+
+		let mut offset_batches = [0; 4];
+		for (offset, batch) in offset_batches.iter_mut().zip(&self.batches) {
+			if !batch.is_empty() {
+				writer.align(2)?;
+				*offset = offset_u32(writer.position()?)?;
+				for index in batch {
+					writer.write_u16(*index)?;
+				}
 			}
 		}
-		Ok(TextureData { bytes: buffer, width, height })
-	} else if bytes.len() > 4 && bytes[0..4] == PNG_MAGIC {
-		let reader = ImageReader::new(Cursor::new(bytes)).with_guessed_format().unwrap();
-		let image = reader.decode().map_err(|e| e.to_string())?;
-		let image = image.to_rgba8();
-		let width = image.width();
-		let height = image.height();
-		let buffer = image.as_bytes();
-		let buffer = buffer.to_vec();
-		Ok(TextureData { bytes: buffer, width, height })
-	} else {
-		Err("Unknown texture format".to_string())
+
+		writer.align(2)?;
+		let offset_materials = offset_u32(writer.position()?)?;
+		for material in &self.materials {
+			writer.write_u16(*material)?;
+		}
+		let end = writer.position()?;
+
+		writer.seek(pos)?;
+		writer.write_u32(offset_name)?;
+		self.bounding_box.write(writer)?;
+		for (offset, batch) in offset_batches.iter().zip(&self.batches) {
+			writer.write_u32(*offset)?;
+			writer.write_u32(count_u32(batch.len())?)?;
+		}
+		writer.write_u32(offset_materials)?;
+		writer.write_u32(count_u32(self.materials.len())?)?;
+		Ok(end)
 	}
 }
 
+
+/// Only reads the texture, decoding happens once the renderer doesn't find it in its texture cache
 fn try_add_texture<F: Read + Seek>(
-	textures: &mut HashMap<u32, TextureData>,
+	textures: &mut HashMap<u32, EncodedTextureData>,
 	wta_wtp: &mut WtaWtp<F>,
 	texture_id: Option<u32>,
-	swizzle: Option<&dyn Fn(&mut[u8]) -> ()>,
-) -> () {
+) {
 	texture_id
-		.and_then(|texture_id| wta_wtp.get_texture(texture_id))
-		.and_then(|texture| decompress_dds(texture, swizzle).ok())
-		.map(|texture_data| textures.insert(texture_id.unwrap(), texture_data));
+		.filter(|texture_id| !textures.contains_key(texture_id))
+		.and_then(|texture_id| wta_wtp.get_texture(texture_id).map(|texture| (texture_id, texture)))
+		.map(|(texture_id, texture)| textures.insert(texture_id, EncodedTextureData::new(texture)));
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use super::*;
+	use crate::wmb::SECTION_ALIGNMENT;
+
+	fn point(x: f32, y: f32, z: f32) -> Point {
+		Point { x, y, z }
+	}
+
+	const NORMALS: [[f32; 3]; 3] = [[0.6, 0.0, 0.8], [-0.267, 0.535, 0.802], [0.0, -1.0, 0.0]];
+
+	fn test_wmb() -> Wmb {
+		let mut wmb = Wmb::new(Header::new(0x10337, point(-1.0, 0.0, -1.0), point(1.0, 2.0, 1.0)));
+		wmb.vertex_groups.push(VertexGroup {
+			vertexes: NORMALS.iter().enumerate().map(|(i, &[x, y, z])| Vertex {
+				position: point(i as f32, 0.3, -0.7),
+				uv: [0.1 * i as f32, 0.33],
+				normal: point(x, y, z),
+				tangent: [0.2, 0.9, 0.1, -1.0],
+				color: None,
+				uv2: None,
+				bone_indices: Some([0, 1, 0, 0]),
+				bone_weights: Some([0.6, 0.4, 0.0, 0.0]),
+			}).collect(),
+			vertex_ex_data: (0..3).map(|_| VertexExData {
+				color: Some([255, 128, 0, 255]),
+				uv2: Some([0.9, 0.1]),
+			}).collect(),
+			unknown1: vec![7; 16],
+			unknown2: Vec::new(),
+			indexes: vec![0, 1, 2],
+		});
+		wmb.batches.push(Batch {
+			vertex_group_index: 0,
+			vertex_start: 0,
+			index_start: 0,
+			num_vertices: 3,
+			num_indexes: 3,
+		});
+		wmb.batch_data_group.batch_data[0].push(BatchData {
+			batch_index: 0,
+			mesh_index: 0,
+			material_index: 0,
+			bone_sets_index: 0,
+			u_a: 0,
+		});
+		wmb.bones = (0..2).map(|i| Bone {
+			id: i,
+			unknown_number2: 0,
+			parent_index: i - 1,
+			u_b: 0,
+			relative_position: point(0.0, 1.0, 0.0),
+			position: point(0.0, i as f32, 0.0),
+		}).collect();
+		let mut first_level = [-1; 16];
+		first_level[0] = 16;
+		wmb.bone_index_translate_table = Some(BoneIndexTranslateTable {
+			first_level,
+			second_level: (0..16).map(|i| if i == 0 { 32 } else { -1 }).collect(),
+			third_level: (0..16).map(|i| if i < 2 { i } else { -1 }).collect(),
+		});
+		wmb.bone_sets.push(BoneSet { bone_indexes: vec![0, 1, 1] });
+		wmb.materials.push(Material {
+			shader_name: "skn03_xxx".to_string(),
+			texture_indices: TextureIndices { flags_indices: [(0, 0), (2, 1), (0, 0), (0, 0)] },
+			num_textures: 2,
+			parameters: vec![1.0, 0.5, 0.25, 1.0, 0.0, 0.0, 0.0, 1.0],
+			u_a: 0,
+			u_c: 0,
+			u_d: 0,
+		});
+		wmb.textures.push(Texture { flags: 0, id: 0x1234 });
+		wmb.textures.push(Texture { flags: 0, id: 0x5678 });
+		wmb.meshes.push(Mesh {
+			name: "body".to_string(),
+			bounding_box: BoundingBox::new([0.0, 1.0, 0.0], [1.0, 1.0, 0.5]),
+			batches: [vec![0], Vec::new(), Vec::new(), Vec::new()],
+			materials: vec![0],
+		});
+		wmb
+	}
+
+	fn write_bytes(wmb: &Wmb) -> Vec<u8> {
+		let mut writer = ByteWriter::new(Cursor::new(Vec::new()));
+		wmb.write(&mut writer).unwrap();
+		writer.into_inner().into_inner()
+	}
+
+	fn read_bytes(bytes: &[u8]) -> Wmb {
+		Wmb::read(&mut ByteReader::new(Cursor::new(bytes))).unwrap()
+	}
+
+	fn load(wmb: &Wmb, options: &LoadOptions) -> SceneData {
+		let mut scene = SceneData::default();
+		let mut reader = ByteReader::new(Cursor::new(write_bytes(wmb)));
+		read_wmb_mgrr("test", &mut reader, &mut None::<WtaWtp<Cursor<Vec<u8>>>>, &mut scene, options).unwrap();
+		scene
+	}
+
+	fn quantized_uv(u: f32, v: f32) -> Vector2<f32> {
+		Vector2::new(half::f16::from_f32(u).to_f32(), half::f16::from_f32(v).to_f32())
+	}
+
+	#[test]
+	fn round_trip_is_byte_exact() {
+		// normals are quantized by the first write, after that they round-trip unchanged
+		let written = write_bytes(&test_wmb());
+		let wmb = read_bytes(&written);
+		assert_eq!(write_bytes(&wmb), written);
+
+		let vertexes = &wmb.vertex_groups[0].vertexes;
+		for (vertex, [x, y, z]) in vertexes.iter().zip(NORMALS) {
+			assert!((vertex.normal.x - x).abs() <= 0.5 / 1023.0, "{}", vertex.normal.x);
+			assert!((vertex.normal.y - y).abs() <= 0.5 / 1023.0, "{}", vertex.normal.y);
+			assert!((vertex.normal.z - z).abs() <= 0.5 / 511.0, "{}", vertex.normal.z);
+		}
+		assert_eq!(vertexes[2].normal.y, -1.0);
+		assert_eq!(vertexes[1].bone_weights, Some([0.6, 0.4, 0.0, 0.0]));
+		assert_eq!(wmb.vertex_groups[0].vertex_ex_data[2].color, Some([255, 128, 0, 255]));
+		assert_eq!(wmb.vertex_groups[0].unknown1, vec![7; 16]);
+		assert_eq!(wmb.vertex_groups[0].indexes, vec![0, 1, 2]);
+		assert_eq!(wmb.batch_data_group.batch_data[0][0].material_index, 0);
+		assert_eq!(wmb.bone_index(1), Some(1));
+		assert_eq!(wmb.resolve_bone_index(0, 2), Some(1));
+		assert_eq!(wmb.materials[0].shader_name.trim_end_matches('\0'), "skn03_xxx");
+		assert_eq!(wmb.materials[0].parameters.len(), 8);
+		assert_eq!(wmb.textures[1].id, 0x5678);
+		assert_eq!(wmb.meshes[0].name, "body");
+		assert_eq!(wmb.meshes[0].batches[0], vec![0]);
+	}
+
+	/// Game files aren't laid out the way `write_layout` does it. Here the bones are moved behind the meshes,
+	/// leaving the old copy behind, and the second material points at the shader name of the first one.
+	fn original_layout_fixture() -> Vec<u8> {
+		let mut wmb = test_wmb();
+		wmb.materials.push(Material {
+			shader_name: "skn03_xxx".to_string(),
+			texture_indices: TextureIndices { flags_indices: [(0, 0), (0, 0), (0, 0), (0, 0)] },
+			num_textures: 1,
+			parameters: Vec::new(),
+			u_a: 0,
+			u_c: 0,
+			u_d: 0,
+		});
+		let mut bytes = write_bytes(&wmb);
+		let header = read_bytes(&bytes).header;
+
+		const BONE_SIZE: usize = 32;
+		let offset_bones = header.offset_bones as usize;
+		let bones = bytes[offset_bones..offset_bones + wmb.bones.len() * BONE_SIZE].to_vec();
+		let moved_offset_bones = bytes.len() as u32;
+		bytes.extend(bones);
+		bytes[60..64].copy_from_slice(&moved_offset_bones.to_le_bytes());
+
+		// offset_shader_name starts the material record
+		let shader_name = header.offset_materials as usize;
+		let shared = bytes[shader_name..shader_name + 4].to_vec();
+		bytes[shader_name + Material::SIZE as usize..][..4].copy_from_slice(&shared);
+		bytes
+	}
+
+	#[test]
+	fn unmodified_models_keep_their_layout() {
+		let fixture = original_layout_fixture();
+		let wmb = read_bytes(&fixture);
+		assert_eq!(wmb.materials[1].shader_name.trim_end_matches('\0'), "skn03_xxx");
+		assert!(wmb.bones[1].position == point(0.0, 1.0, 0.0));
+		assert!(wmb.header.offset_bones > wmb.header.offset_meshes);
+		assert_eq!(write_bytes(&wmb), fixture);
+		let mut writer = ByteWriter::new(Cursor::new(Vec::new()));
+		wmb.write_layout(&mut writer).unwrap();
+		assert_ne!(writer.into_inner().into_inner(), fixture);
+
+		let mut edited = read_bytes(&fixture);
+		edited.meshes[0].name = "torso".to_string();
+		let written = write_bytes(&edited);
+		assert_ne!(written, fixture);
+		let rewritten = read_bytes(&written);
+		assert!(rewritten.header.offset_bones < rewritten.header.offset_materials);
+		assert!(rewritten.vertex_groups == edited.vertex_groups);
+		assert!(rewritten.batch_data_group == edited.batch_data_group);
+		assert!(rewritten.bones == edited.bones);
+		assert!(rewritten.bone_index_translate_table == edited.bone_index_translate_table);
+		assert!(rewritten.materials == edited.materials);
+		assert!(rewritten.meshes == edited.meshes);
+	}
+
+	#[test]
+	fn out_of_range_normals_are_clamped() {
+		let mut wmb = test_wmb();
+		wmb.vertex_groups[0].vertexes[0].normal = point(1.5, -2.0, 1.0);
+		let wmb = read_bytes(&write_bytes(&wmb));
+		let normal = &wmb.vertex_groups[0].vertexes[0].normal;
+		assert_eq!((normal.x, normal.y, normal.z), (1.0, -1.0, 1.0));
+	}
+
+	#[test]
+	fn write_lays_out_edited_sections() {
+		let mut wmb = read_bytes(&write_bytes(&test_wmb()));
+		wmb.meshes[0].name = "a_much_longer_mesh_name".to_string();
+		wmb.meshes[0].batches[2].push(0);
+		wmb.materials.push(Material {
+			shader_name: "bg01_xxx".to_string(),
+			texture_indices: TextureIndices { flags_indices: [(0, 1), (0, 0), (0, 0), (0, 0)] },
+			num_textures: 1,
+			parameters: Vec::new(),
+			u_a: 0,
+			u_c: 0,
+			u_d: 0,
+		});
+		wmb.vertex_groups[0].indexes.extend([2, 1, 0]);
+		wmb.bone_index_translate_table = None;
+
+		let wmb = read_bytes(&write_bytes(&wmb));
+		let header = wmb.header();
+		assert_eq!(header.num_materials, 2);
+		assert_eq!(header.offset_bone_index_translate_table, 0);
+		assert_eq!(header.size_bone_index_translate_table, 0);
+		assert!(header.section_offsets().iter().all(|&offset| (offset as u64).is_multiple_of(SECTION_ALIGNMENT)));
+		assert_eq!(wmb.bone_index(1), None);
+		assert_eq!(wmb.meshes[0].name, "a_much_longer_mesh_name");
+		assert_eq!(wmb.meshes[0].batches[2], vec![0]);
+		assert_eq!(wmb.materials[1].shader_name.trim_end_matches('\0'), "bg01_xxx");
+		assert_eq!(wmb.materials[1].texture_indices.flags_indices[0], (0, 1));
+		assert_eq!(wmb.vertex_groups[0].indexes, vec![0, 1, 2, 2, 1, 0]);
+	}
+
+	#[test]
+	fn invalid_models_are_rejected() {
+		let write = |wmb: &Wmb| wmb.write(&mut ByteWriter::new(Cursor::new(Vec::new()))).err().unwrap();
+
+		let mut wmb = test_wmb();
+		wmb.vertex_groups[0].indexes.push(70000);
+		assert_eq!(write(&wmb), "Index 70000 doesn't fit in the 16 bit index buffer");
+
+		let mut wmb = test_wmb();
+		wmb.vertex_groups[0].vertex_ex_data.pop();
+		assert_eq!(write(&wmb), "3 vertices but 2 vertex ex data entries");
+
+		let mut wmb = test_wmb();
+		wmb.header_mut().set_vertex_format(0x12345);
+		assert_eq!(write(&wmb), "Unknown WMB4 vertex format 0x12345");
+	}
+
+	#[test]
+	fn vertex_formats() {
+		for (flags, stride, bones, color, uv2, ex_data) in [
+			(0x10107, 28, false, true, false, false),
+			(0x10307, 32, false, true, true, false),
+			(0x00137, 32, true, false, false, false),
+			(0x10137, 32, true, false, false, true),
+			(0x00337, 32, true, false, false, true),
+			(0x10337, 32, true, false, false, true),
+		] {
+			let mut wmb = test_wmb();
+			wmb.header_mut().set_vertex_format(flags);
+			let wmb = read_bytes(&write_bytes(&wmb));
+			assert_eq!(VertexFormat::from_flags(flags).unwrap().vertex_size(), stride);
+			let vertex = &wmb.vertex_groups[0].vertexes[1];
+			assert_eq!(vertex.bone_indices.is_some(), bones, "0x{:X}", flags);
+			assert_eq!(vertex.color.is_some(), color, "0x{:X}", flags);
+			assert_eq!(vertex.uv2.is_some(), uv2, "0x{:X}", flags);
+			assert_eq!(wmb.vertex_groups[0].vertex_ex_data.len(), if ex_data { 3 } else { 0 }, "0x{:X}", flags);
+			assert_eq!(vertex.position.x, 1.0);
+			assert_eq!(wmb.vertex_groups[0].indexes, vec![0, 1, 2]);
+		}
+	}
+
+	#[test]
+	fn invalid_vertex_buffers_are_rejected() {
+		let read = |bytes: &[u8]| Wmb::read(&mut ByteReader::new(Cursor::new(bytes))).err().unwrap();
+		let written = write_bytes(&test_wmb());
+
+		let mut bytes = written.clone();
+		bytes[8..12].copy_from_slice(&0x12345u32.to_le_bytes());
+		assert_eq!(read(&bytes), "Unknown WMB4 vertex format 0x12345");
+
+		// num_vertexes of the first vertex group header
+		let offset_vertex_groups = u32::from_le_bytes(written[40..44].try_into().unwrap()) as usize;
+		let mut bytes = written.clone();
+		bytes[offset_vertex_groups + 16..offset_vertex_groups + 20].copy_from_slice(&100u32.to_le_bytes());
+		let error = read(&bytes);
+		assert!(error.starts_with("Vertex format 0x10337 with a stride of 32 doesn't match the buffer"), "{}", error);
+		assert!(error.contains("100 vertices need 3200 bytes"), "{}", error);
+	}
+
+	#[test]
+	fn vertex_colors_and_second_uvs_are_loaded() {
+		// 0x10337 keeps them in the ex-data buffer
+		let scene = load(&test_wmb(), &LoadOptions::default());
+		assert_eq!(scene.meshes[0].colors, Some(vec![[255, 128, 0, 255]; 3]));
+		assert_eq!(scene.meshes[0].uv2, Some(vec![quantized_uv(0.9, 0.1); 3]));
+
+		// 0x10307 keeps them in the vertices
+		let mut wmb = test_wmb();
+		wmb.header_mut().set_vertex_format(0x10307);
+		for (i, vertex) in wmb.vertex_groups[0].vertexes.iter_mut().enumerate() {
+			vertex.color = Some([i as u8, 0, 0, 255]);
+			vertex.uv2 = Some([0.5, 0.25]);
+		}
+		let scene = load(&wmb, &LoadOptions::default());
+		assert_eq!(scene.meshes[0].colors, Some(vec![[0, 0, 0, 255], [1, 0, 0, 255], [2, 0, 0, 255]]));
+		assert_eq!(scene.meshes[0].uv2, Some(vec![quantized_uv(0.5, 0.25); 3]));
+
+		let mut wmb = test_wmb();
+		wmb.header_mut().set_vertex_format(0x00137);
+		let scene = load(&wmb, &LoadOptions::default());
+		assert_eq!(scene.meshes[0].colors, None);
+		assert_eq!(scene.meshes[0].uv2, None);
+	}
+
+	#[test]
+	fn batch_groups_are_kept_and_filtered() {
+		let mut wmb = test_wmb();
+		wmb.batches.push(Batch {
+			vertex_group_index: 0,
+			vertex_start: 0,
+			index_start: 0,
+			num_vertices: 3,
+			num_indexes: 3,
+		});
+		wmb.batch_data_group.batch_data[1].push(BatchData {
+			batch_index: 1,
+			mesh_index: 0,
+			material_index: 0,
+			bone_sets_index: 0,
+			u_a: 0,
+		});
+		// batch 0 is in groups 0 and 2
+		wmb.meshes[0].batches = [vec![0], vec![1], vec![0], Vec::new()];
+
+		let loaded = |wmb4_batch_groups: [bool; 4]| -> Vec<(String, Vec<usize>)> {
+			let scene = load(&wmb, &LoadOptions { wmb4_batch_groups, ..LoadOptions::default() });
+			scene.meshes.into_iter().map(|mesh| (mesh.name, mesh.batch_groups)).collect()
+		};
+		assert_eq!(loaded([true; 4]), vec![("body/0".to_string(), vec![0, 2]), ("body/1".to_string(), vec![1])]);
+		assert_eq!(loaded([false, true, false, false]), vec![("body/1".to_string(), vec![1])]);
+		assert_eq!(loaded([false, false, true, false]), vec![("body/0".to_string(), vec![0, 2])]);
+		assert_eq!(loaded([false; 4]), Vec::new());
+	}
+
+	#[test]
+	fn bone_ids_are_translated() {
+		let mut wmb = test_wmb();
+		// id 3 points past the two bones
+		if let Some(table) = wmb.bone_index_translate_table.as_mut() {
+			table.third_level[3] = 5;
+		}
+		let wmb = read_bytes(&write_bytes(&wmb));
+		assert_eq!(wmb.bone_index(0), Some(0));
+		assert_eq!(wmb.bone_index(1), Some(1));
+		// -1 entries in the third, second and first level
+		assert_eq!(wmb.bone_index(2), None);
+		assert_eq!(wmb.bone_index(0x10), None);
+		assert_eq!(wmb.bone_index(0x100), None);
+		assert_eq!(wmb.bone_index(3), None);
+		assert_eq!(wmb.bone_index(-1), None);
+		assert_eq!(wmb.bone_index(0x1000), None);
+
+		let scene = load(&wmb, &LoadOptions::default());
+		let bones = &scene.skeletons[0].bones;
+		assert_eq!(bones.iter().map(|bone| bone.id).collect::<Vec<_>>(), vec![0, 1]);
+		assert_eq!(bones.iter().map(|bone| bone.parent_index).collect::<Vec<_>>(), vec![None, Some(0)]);
+	}
+
+	#[test]
+	fn skin_weights_are_resolved_through_the_bone_set() {
+		let mut wmb = test_wmb();
+		wmb.bone_sets[0].bone_indexes = vec![1, 0];
+		// local index 3 isn't in the bone set
+		wmb.vertex_groups[0].vertexes[2].bone_indices = Some([3, 0, 0, 0]);
+		let scene = load(&wmb, &LoadOptions::default());
+		let mesh = &scene.meshes[0];
+		assert_eq!(mesh.skeleton_index, Some(0));
+		assert_eq!(mesh.bone_indices, Some(vec![[1, 0, 0, 0], [1, 0, 0, 0], [0, 1, 0, 0]]));
+		assert_eq!(mesh.bone_weights, Some(vec![[0.6, 0.4, 0.0, 0.0], [0.6, 0.4, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0]]));
+		assert_eq!(scene.warnings, vec!["body/0: dropped weights of unresolvable bones {3}"]);
+	}
+
+	#[test]
+	fn material_data_names_only_known_texture_flags() {
+		let mut wmb = test_wmb();
+		// the albedo in the third slot is past num_textures
+		wmb.materials[0].texture_indices.flags_indices = [(5, 0), (2, 1), (0, 1), (0, 0)];
+		let scene = load(&wmb, &LoadOptions::default());
+		let material = &scene.materials[0];
+		let slots = material.textures.iter().map(|t| (t.slot.as_str(), t.id, t.flags)).collect::<Vec<_>>();
+		assert_eq!(slots, vec![("flag_5", 0x1234, Some(5)), ("normal", 0x5678, Some(2))]);
+		assert_eq!(material.parameter_groups.iter().map(|g| g.index).collect::<Vec<_>>(), vec![0, 1]);
+		assert_eq!(material.parameter_groups[1].parameters, vec![0.0, 0.0, 0.0, 1.0]);
+		assert_eq!(material.shader_name_parts.family, "skn");
+		assert_eq!(scene.meshes[0].albedo_texture_id, None);
+		assert_eq!(scene.meshes[0].normal_texture_id, Some(0x5678));
+		assert_eq!(scene.meshes[0].lightmap_texture_id, None);
+	}
 }
@@ -1,22 +1,45 @@
-use std::{collections::HashMap, io::{stdout, Read, Seek}, ops};
-use std::io::Write;
+use std::{collections::HashMap, io::{stdout, Cursor, Read, Seek, Write}, ops, sync::Arc};
 
-use image::{codecs::dds::DdsDecoder, ImageDecoder};
-use three_d::{Matrix4, SquareMatrix, Vector2, Vector3, Vector4};
+use half::f16;
+use three_d::{Matrix4, Rad, SquareMatrix, Vector2, Vector3, Vector4};
 
-use crate::{byte_stream::ByteReader, mesh_data::TextureData, wta_wtp::WtaWtp};
-use crate::mesh_data::MeshData;
+use crate::{byte_stream::{ByteReader, ByteWriter}, load_options::{Game, LoadOptions}, mesh_data::{BoneData, ColTreeData, ColTreeNodeData, LodData, LodGroupData, MaterialData, MaterialParameterGroupData, MaterialTextureData, MaterialVariableData, MeshLod, BoundsData, ShaderNameData, SkeletonData, EncodedTextureData}, wta_wtp::WtaWtp};
+use crate::mesh_data::{MeshData, SceneData};
+use crate::wmb::{count_u32, offset_u32, resolve_skin_weights, vertex_channel, write_records, write_section, write_uv, WmbInfo, WmbMaterialInfo};
 
 pub fn read_wmb_na<R1: Read + Seek, R2: Read + Seek>(
 	name: &str,
 	reader: &mut ByteReader<R1>,
 	wta_wtp: &mut Option<WtaWtp<R2>>,
-	textures: &mut HashMap<u32, TextureData>,
-) -> Result<Vec<MeshData>, String> {
-	let wmb = Wmb::read(reader)?;
-	let is_player_pl = name.contains("pl000") || name.contains("pl010") || name.contains("pl020");
+	scene: &mut SceneData,
+	options: &LoadOptions,
+) -> Result<(), String> {
+	let SceneData { meshes, textures, skeletons, lod_groups, materials, col_trees, warnings, .. } = scene;
+	let wmb = Wmb::read_sections(reader, !options.bounds_only)?;
+	if !wmb.col_tree_nodes.is_empty() {
+		let mut col_tree = wmb.col_tree();
+		for node in col_tree.nodes.iter_mut() {
+			node.mesh_indices.iter_mut().for_each(|i| *i += meshes.len());
+		}
+		col_trees.push(col_tree);
+	}
+	let skeleton_index = if !wmb.bones.is_empty() {
+		skeletons.push(wmb.skeleton());
+		Some(skeletons.len() - 1)
+	} else {
+		None
+	};
+	let lod_group_index = lod_groups.len();
+	lod_groups.push(LodGroupData {
+		name: name.to_string(),
+		lods: wmb.lods.iter().map(|lod| LodData {
+			name: lod.name.clone(),
+			level: lod.lod_level,
+		}).collect(),
+	});
+	let material_start_index = materials.len();
+	materials.extend(wmb.materials.iter().map(Material::to_material_data));
 
-	let mut meshes: Vec<MeshData> = Vec::new();
 	let mut i = 0;
 
 	for (lod_i, lod) in wmb.lods.iter().enumerate() {
@@ -34,6 +57,24 @@ pub fn read_wmb_na<R1: Read + Seek, R2: Read + Seek>(
 				.materials
 				.get(batch_info.material_index as usize)
 				.ok_or(format!("Material index out of bounds: {}", batch_info.material_index))?;
+			let mesh_lod = Some(MeshLod {
+				group_index: lod_group_index,
+				lod_index: lod_i,
+			});
+			let material_index = Some(material_start_index + batch_info.material_index as usize);
+			let should_be_visible = options.visibility_rules.is_visible(Game::Na, name, &mesh.name, Some(lod_i), &material.name);
+			let bounds = Some(mesh.bounding_box.to_bounds_data());
+			if options.bounds_only {
+				meshes.push(MeshData {
+					should_be_visible,
+					skeleton_index,
+					lod: mesh_lod,
+					material_index,
+					bounds,
+					..MeshData::new(format!("{}/{}/{}", lod_name, mesh.name, batch_i))
+				});
+				continue;
+			}
 			let vertex_group = wmb
 				.vertex_groups
 				.get(batch_info.vertex_group_index as usize)
@@ -44,8 +85,7 @@ pub fn read_wmb_na<R1: Read + Seek, R2: Read + Seek>(
 				.ok_or(format!("Batch index out of bounds: {}", batch_start_index + batch_i))?;
 			let indexes = vertex_group.indexes
 				.chunks(3)
-				.map(|i| [i[2], i[1], i[0]])
-				.flatten()
+				.flat_map(|i| [i[2], i[1], i[0]])
 				// .iter()
 				.skip(batch.index_start as usize)
 				.take(batch.num_indexes as usize)
@@ -78,19 +118,43 @@ pub fn read_wmb_na<R1: Read + Seek, R2: Read + Seek>(
 				None
 			};
 
+			let uv_channel = |from_vertex: fn(&Vertex) -> Option<[f32; 2]>, from_ex: fn(&VertexExData) -> Option<[f32; 2]>| {
+				vertex_channel(&vertex_group.vertexes, &vertex_group.vertex_ex_data, vertex_start, num_vertexes, from_vertex, from_ex)
+					.map(|uvs| uvs.into_iter().map(|uv| Vector2::new(uv[0], uv[1])).collect::<Vec<_>>())
+			};
+			let uv2 = uv_channel(|v| v.uv2, |v| v.uv2);
+			let uv3 = uv_channel(|_| None, |v| v.uv3);
+			let uv4 = uv_channel(|_| None, |v| v.uv4);
+			let uv5 = uv_channel(|_| None, |v| v.uv5);
+			let colors = vertex_channel(&vertex_group.vertexes, &vertex_group.vertex_ex_data, vertex_start, num_vertexes, |v| v.color, |v| v.color);
+
 			let albedo_texture_id = material.get_albedo_texture_id();
 			let normal_texture_id = material.get_normal_texture_id();
 			let mask_texture_id = material.get_mask_map_texture_id();
+			let lightmap_texture_id = material.get_lightmap_texture_id().filter(|_| uv2.is_some());
 			if let Some(wta_wtp) = wta_wtp.as_mut() {
-				try_add_texture(textures, wta_wtp, albedo_texture_id, None);
-				try_add_texture(textures, wta_wtp, normal_texture_id, None);
+				try_add_texture(textures, wta_wtp, albedo_texture_id);
+				try_add_texture(textures, wta_wtp, normal_texture_id);
+				try_add_texture(textures, wta_wtp, lightmap_texture_id);
 				// try_add_texture(&mut textures, &mut wta_wtp, mask_texture_id, Some(&mask_map_swizzle));
 			}
 
-			let mut should_be_visible = lod_i == 0;
-			should_be_visible &= !is_player_pl || !mesh.name.contains("Armor") && !mesh.name.contains("serious") && !mesh.name.contains("Broken") && !mesh.name.contains("DLC");
-			
-			let mesh_data = MeshData {
+			let (bone_indices, bone_weights) = if skeleton_index.is_some() && first_vertex.bone_indices.is_some() {
+				let vertexes = vertex_group.vertexes.iter()
+					.skip(vertex_start)
+					.take(num_vertexes)
+					.map(|v| (v.bone_indices, v.bone_weights));
+				let mesh_name = format!("{}/{}/{}", lod_name, mesh.name, batch_i);
+				let (bone_indices, bone_weights, unresolved) = resolve_skin_weights(vertexes, |i| wmb.resolve_bone_index(batch.bone_set_index, i));
+				if !unresolved.is_empty() {
+					warnings.push(format!("{}: dropped weights of unresolvable bones {:?}", mesh_name, unresolved));
+				}
+				(Some(bone_indices), Some(bone_weights))
+			} else {
+				(None, None)
+			};
+
+			let mut mesh_data = MeshData {
 				name: format!("{}/{}/{}", lod_name, mesh.name, batch_i),
 				vertices: vertex_group.vertexes.iter()
 					.skip(vertex_start)
@@ -112,25 +176,52 @@ pub fn read_wmb_na<R1: Read + Seek, R2: Read + Seek>(
 					.take(num_vertexes)
 					.map(|v| Vector2::new(v.uv[0], v.uv[1]))
 					.collect(),	
+				uv2,
+				uv3,
+				uv4,
+				uv5,
+				colors,
 				albedo_texture_id,
 				normal_texture_id,
 				mask_texture_id,
+				lightmap_texture_id,
 				transform: Matrix4::identity(),
 				uses_transparency: true,
 				should_be_visible,
+				skeleton_index,
+				bone_indices,
+				bone_weights,
+				lod: mesh_lod,
+				material_index,
+				bounds,
+				batch_groups: Vec::new(),
 			};
+			mesh_data.generate_missing_normals_and_tangents();
 			meshes.push(mesh_data);
 		}
 	}
 	println!();
 	
-	Ok(meshes)
+	Ok(())
 }
 
-struct Point {
-	x: f32,
-	y: f32,
-	z: f32,
+pub fn read_wmb_na_info<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<WmbInfo, String> {
+	let wmb = Wmb::read_sections(reader, false)?;
+	Ok(WmbInfo {
+		meshes: wmb.meshes.iter().map(|m| m.name.clone()).collect(),
+		materials: wmb.materials.iter().map(|m| WmbMaterialInfo {
+			name: m.name.clone(),
+			shader_name: m.shader_name.clone(),
+			texture_ids: m.textures.iter().map(|t| t.id).collect(),
+		}).collect(),
+	})
+}
+
+#[derive(PartialEq)]
+pub struct Point {
+	pub x: f32,
+	pub y: f32,
+	pub z: f32,
 }
 
 impl Point {
@@ -141,6 +232,12 @@ impl Point {
 			z: reader.read_f32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		writer.write_f32(self.x)?;
+		writer.write_f32(self.y)?;
+		writer.write_f32(self.z)
+	}
 }
 
 impl ops::Add<f32> for Point {
@@ -167,23 +264,43 @@ impl ops::Div<f32> for Point {
 	}
 }
 
-struct Wmb {
+/// The sections can be edited freely. `write` reproduces the file that was read as long as nothing
+/// was changed, otherwise it lays the sections out anew and recomputes the offsets and counts of the header.
+#[derive(PartialEq)]
+pub struct Wmb {
 	header: Header,
-	bones: Vec<Bone>,
-	bone_index_translate_table: BoneIndexTranslateTable,
-	vertex_groups: Vec<VertexGroup>,
-	batches: Vec<Batch>,
-	lods: Vec<Lod>,
-	col_tree_nodes: Vec<ColTreeNode>,
-	bone_map: Vec<i32>,
-	bone_sets: Vec<BoneSet>,
-	materials: Vec<Material>,
-	meshes: Vec<Mesh>,
-	mesh_material: Vec<MeshMaterial>,
+	pub bones: Vec<Bone>,
+	pub bone_index_translate_table: Option<BoneIndexTranslateTable>,
+	pub vertex_groups: Vec<VertexGroup>,
+	pub batches: Vec<Batch>,
+	pub lods: Vec<Lod>,
+	pub col_tree_nodes: Vec<ColTreeNode>,
+	pub bone_map: Vec<i32>,
+	pub bone_sets: Vec<BoneSet>,
+	pub materials: Vec<Material>,
+	pub meshes: Vec<Mesh>,
+	pub mesh_material: Vec<MeshMaterial>,
+	/// Raw bytes and entry count of the unknown0 section
+	pub unknown0: Vec<u8>,
+	pub num_unknown0: u32,
+	/// The bytes `read` parsed this from
+	source: Option<Arc<[u8]>>,
 }
 
+const HEADER_SIZE: u64 = 144;
+const BUFFER_ALIGNMENT: u64 = 16;
+
 impl Wmb {
-	fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
+	pub fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
+		let mut wmb = Self::read_sections(reader, true)?;
+		let size = reader.size()?;
+		reader.seek(0)?;
+		wmb.source = Some(reader.read(size as usize)?.into());
+		Ok(wmb)
+	}
+
+	/// Skipping the vertex groups is much faster when only the bounds are needed, but the result can't be written back
+	fn read_sections<R: Read + Seek>(reader: &mut ByteReader<R>, read_vertex_groups: bool) -> Result<Self, String> {
 		let header = Header::read(reader)?;
 
 		let mut bones = Vec::with_capacity(header.num_bones as usize);
@@ -196,19 +313,17 @@ impl Wmb {
 
 		let bone_index_translate_table = if header.offset_bone_index_translate_table != 0 {
 			reader.seek(header.offset_bone_index_translate_table as u64)?;
-			BoneIndexTranslateTable::read(reader)?
+			Some(BoneIndexTranslateTable::read(reader)?)
 		} else {
-			BoneIndexTranslateTable {
-				first_level: [0; 16],
-				second_level: Vec::new(),
-				third_level: Vec::new(),
-			}
+			None
 		};
 		
 		let mut vertex_groups = Vec::with_capacity(header.num_vertex_groups as usize);
-		reader.seek(header.offset_vertex_groups as u64)?;
-		for _ in 0..header.num_vertex_groups {
-			vertex_groups.push(VertexGroup::read(reader, header.flags as u32)?);
+		if read_vertex_groups {
+			reader.seek(header.offset_vertex_groups as u64)?;
+			for _ in 0..header.num_vertex_groups {
+				vertex_groups.push(VertexGroup::read(reader, header.flags as u32)?);
+			}
 		}
 
 		let mut batches = Vec::with_capacity(header.num_batches as usize);
@@ -275,8 +390,22 @@ impl Wmb {
 			}
 		}
 
+		// the layout of this section is unknown, so keep everything up to the next section
+		let unknown0 = if header.offset_unknown0 != 0 && header.num_unknown0 != 0 {
+			let size = reader.size()?;
+			let end = header.section_offsets().into_iter()
+				.map(|offset| offset as u64)
+				.filter(|&offset| offset > header.offset_unknown0 as u64)
+				.min()
+				.unwrap_or(size);
+			reader.seek(header.offset_unknown0 as u64)?;
+			reader.read((end - header.offset_unknown0 as u64) as usize)?
+		} else {
+			Vec::new()
+		};
 
 		Ok(Wmb {
+			num_unknown0: if unknown0.is_empty() { 0 } else { header.num_unknown0 },
 			header,
 			bones,
 			bone_index_translate_table,
@@ -289,13 +418,223 @@ impl Wmb {
 			materials,
 			meshes,
 			mesh_material,
+			unknown0,
+			source: None,
 		})
 	}
+
+	pub fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		match &self.source {
+			Some(source) if self.is_unmodified(source) => {
+				writer.seek(0)?;
+				writer.write(source)
+			}
+			_ => self.write_layout(writer),
+		}
+	}
+
+	fn is_unmodified(&self, source: &Arc<[u8]>) -> bool {
+		Self::read_sections(&mut ByteReader::new(Cursor::new(source.as_ref())), true)
+			.is_ok_and(|original| Wmb { source: Some(source.clone()), ..original } == *self)
+	}
+
+	/// Lays the sections out one after another in header order
+	fn write_layout<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		let flags = self.header.flags as u32;
+		writer.seek(HEADER_SIZE)?;
+
+		let offset_bones = write_section(writer, self.bones.is_empty(), |writer| {
+			self.bones.iter().try_for_each(|bone| bone.write(writer))
+		})?;
+		let offset_bone_index_translate_table = match &self.bone_index_translate_table {
+			Some(table) => write_section(writer, false, |writer| table.write(writer))?,
+			None => 0,
+		};
+		let offset_vertex_groups = write_section(writer, self.vertex_groups.is_empty(), |writer| {
+			write_records(writer, &self.vertex_groups, VertexGroup::SIZE, |vertex_group, writer, data_offset| {
+				vertex_group.write(writer, data_offset, flags)
+			}).map(|_| ())
+		})?;
+		let offset_batches = write_section(writer, self.batches.is_empty(), |writer| {
+			self.batches.iter().try_for_each(|batch| batch.write(writer))
+		})?;
+		let offset_lods = write_section(writer, self.lods.is_empty(), |writer| {
+			write_records(writer, &self.lods, Lod::SIZE, Lod::write).map(|_| ())
+		})?;
+		let offset_col_tree_nodes = write_section(writer, self.col_tree_nodes.is_empty(), |writer| {
+			self.col_tree_nodes.iter().try_for_each(|node| node.write(writer))
+		})?;
+		let offset_bone_map = write_section(writer, self.bone_map.is_empty(), |writer| {
+			self.bone_map.iter().try_for_each(|&bone| writer.write_i32(bone))
+		})?;
+		let offset_bone_sets = write_section(writer, self.bone_sets.is_empty(), |writer| {
+			write_records(writer, &self.bone_sets, BoneSet::SIZE, BoneSet::write).map(|_| ())
+		})?;
+		let offset_materials = write_section(writer, self.materials.is_empty(), |writer| {
+			write_records(writer, &self.materials, Material::SIZE, Material::write).map(|_| ())
+		})?;
+		let offset_meshes = write_section(writer, self.meshes.is_empty(), |writer| {
+			write_records(writer, &self.meshes, Mesh::SIZE, Mesh::write).map(|_| ())
+		})?;
+		let offset_mesh_material = write_section(writer, self.mesh_material.is_empty(), |writer| {
+			self.mesh_material.iter().try_for_each(|mesh_material| mesh_material.write(writer))
+		})?;
+		// the reader keeps everything up to the end of the file, so this has to come last without padding after it
+		let offset_unknown0 = write_section(writer, self.unknown0.is_empty(), |writer| writer.write(&self.unknown0))?;
+
+		let header = Header {
+			id: self.header.id.clone(),
+			version: self.header.version,
+			wmb3_version: self.header.wmb3_version,
+			unknown_a: self.header.unknown_a,
+			flags: self.header.flags,
+			reference_bone: self.header.reference_bone,
+			bounding_box: self.header.bounding_box.clone(),
+			offset_bones,
+			num_bones: count_u32(self.bones.len())?,
+			offset_bone_index_translate_table,
+			bone_translate_table_size: self.bone_index_translate_table.as_ref().map_or(Ok(0), |table| table.size())?,
+			offset_vertex_groups,
+			num_vertex_groups: count_u32(self.vertex_groups.len())?,
+			offset_batches,
+			num_batches: count_u32(self.batches.len())?,
+			offset_lods,
+			num_lods: count_u32(self.lods.len())?,
+			offset_col_tree_nodes,
+			num_col_tree_nodes: count_u32(self.col_tree_nodes.len())?,
+			offset_bone_map,
+			bone_map_size: count_u32(self.bone_map.len())?,
+			offset_bone_sets,
+			num_bone_sets: count_u32(self.bone_sets.len())?,
+			offset_materials,
+			num_materials: count_u32(self.materials.len())?,
+			offset_meshes,
+			num_meshes: count_u32(self.meshes.len())?,
+			offset_mesh_material,
+			num_mesh_material: count_u32(self.mesh_material.len())?,
+			offset_unknown0,
+			num_unknown0: if self.unknown0.is_empty() { 0 } else { self.num_unknown0 },
+		};
+		writer.seek(0)?;
+		header.write(writer)
+	}
+
+	pub fn skeleton(&self) -> SkeletonData {
+		let bones = self.bones.iter()
+			.map(|bone| {
+				let world_transform = bind_transform(&bone.position, &bone.rotation, &bone.scale);
+				BoneData {
+					id: bone.id,
+					parent_index: usize::try_from(bone.parent_index).ok().filter(|&i| i < self.bones.len()),
+					local_transform: bind_transform(&bone.local_position, &bone.local_rotation, &bone.local_scale),
+					world_transform,
+					inverse_bind_matrix: world_transform.invert().unwrap_or(Matrix4::identity()),
+				}
+			})
+			.collect();
+		SkeletonData { bones }
+	}
+
+	/// Mesh indices follow the order in which `read_wmb_na` creates meshes, i.e. the batch infos of all LODs
+	pub fn col_tree(&self) -> ColTreeData {
+		let child_index = |i: i32| usize::try_from(i).ok().filter(|&i| i < self.col_tree_nodes.len());
+		let mut nodes = self.col_tree_nodes.iter()
+			.map(|node| ColTreeNodeData {
+				min: Vector3::new(node.p1.x, node.p1.y, node.p1.z),
+				max: Vector3::new(node.p2.x, node.p2.y, node.p2.z),
+				left: child_index(node.left),
+				right: child_index(node.right),
+				mesh_indices: Vec::new(),
+			})
+			.collect::<Vec<_>>();
+		let batch_infos = self.lods.iter().flat_map(|lod| lod.batch_infos.iter());
+		for (mesh_i, batch_info) in batch_infos.enumerate() {
+			if let Some(node) = usize::try_from(batch_info.col_tree_node_index).ok().and_then(|i| nodes.get_mut(i)) {
+				node.mesh_indices.push(mesh_i);
+			}
+		}
+		ColTreeData { nodes }
+	}
+
+	/// Maps a vertex bone index of a batch to an index into `bones`
+	pub fn resolve_bone_index(&self, bone_set_index: i32, local_index: u8) -> Option<u16> {
+		let bone_set = self.bone_sets.get(usize::try_from(bone_set_index).ok()?)?;
+		let index = *bone_set.bone_indexes.get(local_index as usize)?;
+		let index = if self.bone_map.is_empty() {
+			index as i32
+		} else {
+			*self.bone_map.get(usize::try_from(index).ok()?)?
+		};
+		u16::try_from(index).ok().filter(|&i| (i as usize) < self.bones.len())
+	}
+
+	/// Model with no sections
+	pub fn new(header: Header) -> Self {
+		Wmb {
+			header,
+			bones: Vec::new(),
+			bone_index_translate_table: None,
+			vertex_groups: Vec::new(),
+			batches: Vec::new(),
+			lods: Vec::new(),
+			col_tree_nodes: Vec::new(),
+			bone_map: Vec::new(),
+			bone_sets: Vec::new(),
+			materials: Vec::new(),
+			meshes: Vec::new(),
+			mesh_material: Vec::new(),
+			unknown0: Vec::new(),
+			num_unknown0: 0,
+			source: None,
+		}
+	}
+
+	/// Offsets and counts are the ones read from the file, not updated by edits
+	pub fn header(&self) -> &Header {
+		&self.header
+	}
+
+	pub fn header_mut(&mut self) -> &mut Header {
+		&mut self.header
+	}
+}
+
+/// Revisions of the WMB3 layout, identified by `Header::version`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Wmb3Version {
+	/// NieR:Automata
+	Na,
+}
+
+impl Wmb3Version {
+	pub fn from_header_version(version: u32) -> Result<Self, String> {
+		match version {
+			0x20160116 => Ok(Wmb3Version::Na),
+			_ => Err(format!("Unsupported WMB3 version 0x{:08X}", version)),
+		}
+	}
+
+	pub fn header_version(self) -> u32 {
+		match self {
+			Wmb3Version::Na => 0x20160116,
+		}
+	}
+
+	/// Reads the version of a WMB3 without consuming anything
+	pub fn peek<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
+		let pos = reader.position()?;
+		reader.seek(pos + 4)?;
+		let version = reader.read_u32()?;
+		reader.seek(pos)?;
+		Wmb3Version::from_header_version(version)
+	}
 }
 
-struct Header {
+#[derive(PartialEq)]
+pub struct Header {
 	id: String,
 	version: u32,
+	wmb3_version: Wmb3Version,
 	unknown_a: i32,
 	flags: i16,
 	reference_bone: i16,
@@ -327,10 +666,51 @@ struct Header {
 }
 
 impl Header {
+	/// Header of an empty model, the offsets and counts are filled in by `Wmb::write`
+	pub fn new(version: Wmb3Version, flags: i16, reference_bone: i16, bounding_box: BoundingBox) -> Self {
+		Header {
+			id: "WMB3".to_string(),
+			version: version.header_version(),
+			wmb3_version: version,
+			unknown_a: 0,
+			flags,
+			reference_bone,
+			bounding_box,
+			offset_bones: 0,
+			num_bones: 0,
+			offset_bone_index_translate_table: 0,
+			bone_translate_table_size: 0,
+			offset_vertex_groups: 0,
+			num_vertex_groups: 0,
+			offset_batches: 0,
+			num_batches: 0,
+			offset_lods: 0,
+			num_lods: 0,
+			offset_col_tree_nodes: 0,
+			num_col_tree_nodes: 0,
+			offset_bone_map: 0,
+			bone_map_size: 0,
+			offset_bone_sets: 0,
+			num_bone_sets: 0,
+			offset_materials: 0,
+			num_materials: 0,
+			offset_meshes: 0,
+			num_meshes: 0,
+			offset_mesh_material: 0,
+			num_mesh_material: 0,
+			offset_unknown0: 0,
+			num_unknown0: 0,
+		}
+	}
+
 	fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
+		let id = reader.read_string(4)?;
+		let version = reader.read_u32()?;
+		let wmb3_version = Wmb3Version::from_header_version(version)?;
 		Ok(Header {
-			id: reader.read_string(4)?,
-			version: reader.read_u32()?,
+			id,
+			version,
+			wmb3_version,
 			unknown_a: reader.read_i32()?,
 			flags: reader.read_i16()?,
 			reference_bone: reader.read_i16()?,
@@ -361,18 +741,209 @@ impl Header {
 			num_unknown0: reader.read_u32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		writer.write_string(&self.id, 4)?;
+		writer.write_u32(self.version)?;
+		writer.write_i32(self.unknown_a)?;
+		writer.write_i16(self.flags)?;
+		writer.write_i16(self.reference_bone)?;
+		self.bounding_box.write(writer)?;
+		for value in [
+			self.offset_bones, self.num_bones,
+			self.offset_bone_index_translate_table, self.bone_translate_table_size,
+			self.offset_vertex_groups, self.num_vertex_groups,
+			self.offset_batches, self.num_batches,
+			self.offset_lods, self.num_lods,
+			self.offset_col_tree_nodes, self.num_col_tree_nodes,
+			self.offset_bone_map, self.bone_map_size,
+			self.offset_bone_sets, self.num_bone_sets,
+			self.offset_materials, self.num_materials,
+			self.offset_meshes, self.num_meshes,
+			self.offset_mesh_material, self.num_mesh_material,
+			self.offset_unknown0, self.num_unknown0,
+		] {
+			writer.write_u32(value)?;
+		}
+		Ok(())
+	}
+
+	fn section_offsets(&self) -> [u32; 12] {
+		[
+			self.offset_bones,
+			self.offset_bone_index_translate_table,
+			self.offset_vertex_groups,
+			self.offset_batches,
+			self.offset_lods,
+			self.offset_col_tree_nodes,
+			self.offset_bone_map,
+			self.offset_bone_sets,
+			self.offset_materials,
+			self.offset_meshes,
+			self.offset_mesh_material,
+			self.offset_unknown0,
+		]
+	}
+
+	pub fn id(&self) -> &str {
+		&self.id
+	}
+
+	pub fn version(&self) -> u32 {
+		self.version
+	}
+
+	pub fn wmb3_version(&self) -> Wmb3Version {
+		self.wmb3_version
+	}
+
+	pub fn unknown_a(&self) -> i32 {
+		self.unknown_a
+	}
+
+	pub fn set_flags(&mut self, flags: i16) {
+		self.flags = flags;
+	}
+
+	pub fn set_reference_bone(&mut self, reference_bone: i16) {
+		self.reference_bone = reference_bone;
+	}
+
+	pub fn set_bounding_box(&mut self, bounding_box: BoundingBox) {
+		self.bounding_box = bounding_box;
+	}
+
+	pub fn flags(&self) -> i16 {
+		self.flags
+	}
+
+	pub fn reference_bone(&self) -> i16 {
+		self.reference_bone
+	}
+
+	pub fn bounding_box(&self) -> &BoundingBox {
+		&self.bounding_box
+	}
+
+	pub fn offset_bones(&self) -> u32 {
+		self.offset_bones
+	}
+
+	pub fn num_bones(&self) -> u32 {
+		self.num_bones
+	}
+
+	pub fn offset_bone_index_translate_table(&self) -> u32 {
+		self.offset_bone_index_translate_table
+	}
+
+	pub fn bone_translate_table_size(&self) -> u32 {
+		self.bone_translate_table_size
+	}
+
+	pub fn offset_vertex_groups(&self) -> u32 {
+		self.offset_vertex_groups
+	}
+
+	pub fn num_vertex_groups(&self) -> u32 {
+		self.num_vertex_groups
+	}
+
+	pub fn offset_batches(&self) -> u32 {
+		self.offset_batches
+	}
+
+	pub fn num_batches(&self) -> u32 {
+		self.num_batches
+	}
+
+	pub fn offset_lods(&self) -> u32 {
+		self.offset_lods
+	}
+
+	pub fn num_lods(&self) -> u32 {
+		self.num_lods
+	}
+
+	pub fn offset_col_tree_nodes(&self) -> u32 {
+		self.offset_col_tree_nodes
+	}
+
+	pub fn num_col_tree_nodes(&self) -> u32 {
+		self.num_col_tree_nodes
+	}
+
+	pub fn offset_bone_map(&self) -> u32 {
+		self.offset_bone_map
+	}
+
+	pub fn bone_map_size(&self) -> u32 {
+		self.bone_map_size
+	}
+
+	pub fn offset_bone_sets(&self) -> u32 {
+		self.offset_bone_sets
+	}
+
+	pub fn num_bone_sets(&self) -> u32 {
+		self.num_bone_sets
+	}
+
+	pub fn offset_materials(&self) -> u32 {
+		self.offset_materials
+	}
+
+	pub fn num_materials(&self) -> u32 {
+		self.num_materials
+	}
+
+	pub fn offset_meshes(&self) -> u32 {
+		self.offset_meshes
+	}
+
+	pub fn num_meshes(&self) -> u32 {
+		self.num_meshes
+	}
+
+	pub fn offset_mesh_material(&self) -> u32 {
+		self.offset_mesh_material
+	}
+
+	pub fn num_mesh_material(&self) -> u32 {
+		self.num_mesh_material
+	}
+
+	pub fn offset_unknown0(&self) -> u32 {
+		self.offset_unknown0
+	}
+
+	pub fn num_unknown0(&self) -> u32 {
+		self.num_unknown0
+	}
 }
 
-struct BoundingBox {
-	x: f32,
-	y: f32,
-	z: f32,
-	u: f32,
-	v: f32,
-	w: f32,
+#[derive(Clone, PartialEq)]
+pub struct BoundingBox {
+	pub x: f32,
+	pub y: f32,
+	pub z: f32,
+	pub u: f32,
+	pub v: f32,
+	pub w: f32,
 }
 
 impl BoundingBox {
+	pub fn new(center: [f32; 3], extents: [f32; 3]) -> Self {
+		BoundingBox {
+			x: center[0],
+			y: center[1],
+			z: center[2],
+			u: extents[0],
+			v: extents[1],
+			w: extents[2],
+		}
+	}
+
 	fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
 		Ok(BoundingBox {
 			x: reader.read_f32()?,
@@ -383,18 +954,34 @@ impl BoundingBox {
 			w: reader.read_f32()?,
 		})
 	}
+
+	/// `x`, `y`, `z` are the center and `u`, `v`, `w` the half extents
+	pub fn to_bounds_data(&self) -> BoundsData {
+		BoundsData::from_center_extents(
+			Vector3::new(self.x, self.y, self.z),
+			Vector3::new(self.u, self.v, self.w),
+		)
+	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		for value in [self.x, self.y, self.z, self.u, self.v, self.w] {
+			writer.write_f32(value)?;
+		}
+		Ok(())
+	}
 }
 
-struct Bone {
-	id: i16,
-	parent_index: i16,
-	local_position: Point,
-	local_rotation: Point,
-	local_scale: Point,
-	position: Point,
-	rotation: Point,
-	scale: Point,
-	t_position: Point,
+#[derive(PartialEq)]
+pub struct Bone {
+	pub id: i16,
+	pub parent_index: i16,
+	pub local_position: Point,
+	pub local_rotation: Point,
+	pub local_scale: Point,
+	pub position: Point,
+	pub rotation: Point,
+	pub scale: Point,
+	pub t_position: Point,
 }
 
 impl Bone {
@@ -411,39 +998,42 @@ impl Bone {
 			t_position: Point::read(reader)?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		writer.write_i16(self.id)?;
+		writer.write_i16(self.parent_index)?;
+		self.local_position.write(writer)?;
+		self.local_rotation.write(writer)?;
+		self.local_scale.write(writer)?;
+		self.position.write(writer)?;
+		self.rotation.write(writer)?;
+		self.scale.write(writer)?;
+		self.t_position.write(writer)
+	}
 }
 
-struct BoneIndexTranslateTable {
-	first_level: [i16; 16],
-	second_level: Vec<i16>,
-	third_level: Vec<i16>,
+#[derive(PartialEq)]
+pub struct BoneIndexTranslateTable {
+	pub first_level: [i16; 16],
+	pub second_level: Vec<i16>,
+	pub third_level: Vec<i16>,
 }
 
 impl BoneIndexTranslateTable {
 	fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
 		let mut first_level = [0; 16];
-		for i in 0..16 {
-			first_level[i] = reader.read_i16()?;
+		for value in first_level.iter_mut() {
+			*value = reader.read_i16()?;
 		}
 
-		let mut j = 0;
-		for i in 0..16 {
-			if first_level[i] != -1 {
-				j += 1;
-			}
-		}
+		let j = first_level.iter().filter(|&&value| value != -1).count();
 
 		let mut second_level = Vec::new();
 		for _ in 0..j*16 {
 			second_level.push(reader.read_i16()?);
 		}
 
-		let mut k = 0;
-		for i in 0..j*16 {
-			if second_level[i] != -1 {
-				k += 1;
-			}
-		}
+		let k = second_level.iter().filter(|&&value| value != -1).count();
 
 		let mut third_level = Vec::new();
 		for _ in 0..k*16 {
@@ -456,19 +1046,33 @@ impl BoneIndexTranslateTable {
 			third_level,
 		})
 	}
+
+	/// Size in bytes
+	fn size(&self) -> Result<u32, String> {
+		count_u32((self.first_level.len() + self.second_level.len() + self.third_level.len()) * 2)
+	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		for value in self.first_level.iter().chain(&self.second_level).chain(&self.third_level) {
+			writer.write_i16(*value)?;
+		}
+		Ok(())
+	}
 }
 
-struct Vertex {
-	position: Point,
-	tangent: Point,
-	tangent_sign: f32,
-	tangent_length: f32,
-	uv: [f32; 2],
-	normal: Option<Point>,
-	uv2: Option<[f32; 2]>,
-	bone_indices: Option<[u8; 4]>,
-	bone_weights: Option<[f32; 4]>,
-	color: Option<[u8; 4]>,
+#[derive(PartialEq)]
+pub struct Vertex {
+	pub position: Point,
+	pub tangent: Point,
+	pub tangent_sign: f32,
+	pub uv: [f32; 2],
+	pub normal: Option<Point>,
+	/// Fourth component of the normal, usually padding
+	pub normal_w: f32,
+	pub uv2: Option<[f32; 2]>,
+	pub bone_indices: Option<[u8; 4]>,
+	pub bone_weights: Option<[f32; 4]>,
+	pub color: Option<[u8; 4]>,
 }
 
 const UV2_FLAGS: &[u32] = &[1, 4, 5, 12, 14];
@@ -480,28 +1084,19 @@ impl Vertex {
 		let position = Point::read(reader)?;
 		let tangent = [reader.read_u8()? as f32, reader.read_u8()? as f32, reader.read_u8()? as f32];
 		let tangent_sign = reader.read_u8()?;
-		let uv = [
-			reader.read_f16()?.to_f32(),
-			reader.read_f16()?.to_f32()
-		];
+		let uv = read_uv(reader)?;
 		let tangent = Point {
 			x: (tangent[0] - 127.0) / 127.0,
 			y: (tangent[1] - 127.0) / 127.0,
 			z: (tangent[2] - 127.0) / 127.0,
 		};
 		let tangent_sign = (tangent_sign as f32 - 127.0) / 127.0;
-		let tangent_length = (tangent.x * tangent.x + tangent.y * tangent.y + tangent.z * tangent.z).sqrt();
 
-		let normal = if vertex_flags == 0 {
-			let v = Point{
-				x: reader.read_f16()?.to_f32(),
-				y: reader.read_f16()?.to_f32(),
-				z: reader.read_f16()?.to_f32(),
-			};
-			reader.read_f16()?;
-			Some(v)
+		let (normal, normal_w) = if vertex_flags == 0 {
+			let (normal, normal_w) = read_normal(reader)?;
+			(Some(normal), normal_w)
 		} else {
-			None
+			(None, 0.0)
 		};
 		let uv2 = if UV2_FLAGS.contains(&vertex_flags) {
 			Some([
@@ -545,15 +1140,62 @@ impl Vertex {
 			position,
 			tangent,
 			tangent_sign,
-			tangent_length,
 			uv,
 			normal,
+			normal_w,
 			uv2,
 			bone_indices,
 			bone_weights,
 			color,
 		})
 	}
+
+	fn size(vertex_flags: u32) -> u32 {
+		let mut size = 20;
+		if vertex_flags == 0 {
+			size += 8;
+		}
+		if UV2_FLAGS.contains(&vertex_flags) {
+			size += 4;
+		}
+		if BONE_FLAGS.contains(&vertex_flags) {
+			size += 8;
+		}
+		if COLOR_FLAGS.contains(&vertex_flags) {
+			size += 4;
+		}
+		size
+	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, vertex_flags: u32) -> Result<(), String> {
+		self.position.write(writer)?;
+		writer.write_u8(encode_unorm8(self.tangent.x))?;
+		writer.write_u8(encode_unorm8(self.tangent.y))?;
+		writer.write_u8(encode_unorm8(self.tangent.z))?;
+		writer.write_u8(encode_unorm8(self.tangent_sign))?;
+		write_uv(writer, self.uv)?;
+		if vertex_flags == 0 {
+			write_normal(writer, self.normal.as_ref(), self.normal_w)?;
+		}
+		if UV2_FLAGS.contains(&vertex_flags) {
+			write_uv(writer, self.uv2.unwrap_or_default())?;
+		}
+		if BONE_FLAGS.contains(&vertex_flags) {
+			writer.write(&self.bone_indices.unwrap_or_default())?;
+			for weight in self.bone_weights.unwrap_or_default() {
+				writer.write_u8((weight * 255.0).round() as u8)?;
+			}
+		}
+		if COLOR_FLAGS.contains(&vertex_flags) {
+			writer.write(&self.color.unwrap_or_default())?;
+		}
+		Ok(())
+	}
+
+	pub fn tangent_length(&self) -> f32 {
+		let tangent = &self.tangent;
+		(tangent.x * tangent.x + tangent.y * tangent.y + tangent.z * tangent.z).sqrt()
+	}
 }
 
 struct VertexGroupHeader {
@@ -588,129 +1230,132 @@ impl VertexGroupHeader {
 			num_indexes: reader.read_u32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		for value in [
+			self.vertex_offset,
+			self.vertex_ex_data_offset,
+			self.unknown1_offset,
+			self.unknown2_offset,
+			self.vertex_size,
+			self.vertex_ex_data_size,
+			self.unknown1_size,
+			self.unknown2_size,
+			self.num_vertexes,
+			self.vertex_flags,
+			self.index_buffer_offset,
+			self.num_indexes,
+		] {
+			writer.write_u32(value)?;
+		}
+		Ok(())
+	}
 }
 
-struct VertexExData {
-	normal: Option<Point>,
-	uv2: Option<[f32; 2]>,
-	uv3: Option<[f32; 2]>,
-	uv4: Option<[f32; 2]>,
-	uv5: Option<[f32; 2]>,
-	color: Option<[u8; 4]>,
+#[derive(PartialEq)]
+pub struct VertexExData {
+	pub normal: Option<Point>,
+	/// Fourth component of the normal, usually padding
+	pub normal_w: f32,
+	pub uv2: Option<[f32; 2]>,
+	pub uv3: Option<[f32; 2]>,
+	pub uv4: Option<[f32; 2]>,
+	pub uv5: Option<[f32; 2]>,
+	pub color: Option<[u8; 4]>,
 }
 
-impl VertexExData {
-	fn read<R: Read + Seek>(reader: &mut ByteReader<R>, vertex_flags: u32) -> Result<Self, String> {
-		let normal: Option<Point>;
-		let uv2: Option<[f32; 2]>;
-		let uv3: Option<[f32; 2]>;
-		let uv4: Option<[f32; 2]>;
-		let uv5: Option<[f32; 2]>;
-		let color: Option<[u8; 4]>;
+#[derive(Clone, Copy)]
+enum ExDataAttribute {
+	Normal,
+	Uv2,
+	Uv3,
+	Uv4,
+	Uv5,
+	Color,
+}
 
+impl VertexExData {
+	fn layout(vertex_flags: u32) -> Result<&'static [ExDataAttribute], String> {
+		use ExDataAttribute::*;
 		match vertex_flags {
-			1 | 4 => {
-				normal = Some(VertexExData::read_normal(reader)?);
-				uv2 = None;
-				uv3 = None;
-				uv4 = None;
-				uv5 = None;
-				color = None;
-			}
-			5 => {
-				normal = Some(VertexExData::read_normal(reader)?);
-				uv3 = Some(VertexExData::read_uv(reader)?);
-				uv2 = None;
-				uv4 = None;
-				uv5 = None;
-				color = None;
-			}
-			7 => {
-				uv2 = Some(VertexExData::read_uv(reader)?);
-				normal = Some(VertexExData::read_normal(reader)?);
-				uv3 = None;
-				uv4 = None;
-				uv5 = None;
-				color = None;
-			}
-			10 => {
-				uv2 = Some(VertexExData::read_uv(reader)?);
-				color = Some(VertexExData::read_color(reader)?);
-				normal = Some(VertexExData::read_normal(reader)?);
-				uv3 = None;
-				uv4 = None;
-				uv5 = None;
-			}
-			11 => {
-				uv2 = Some(VertexExData::read_uv(reader)?);
-				color = Some(VertexExData::read_color(reader)?);
-				normal = Some(VertexExData::read_normal(reader)?);
-				uv3 = Some(VertexExData::read_uv(reader)?);
-				uv4 = None;
-				uv5 = None;
-			}
-			12 => {
-				normal = Some(VertexExData::read_normal(reader)?);
-				uv3 = Some(VertexExData::read_uv(reader)?);
-				uv4 = Some(VertexExData::read_uv(reader)?);
-				uv5 = Some(VertexExData::read_uv(reader)?);
-				uv2 = None;
-				color = None;
-			}
-			14 => {
-				normal = Some(VertexExData::read_normal(reader)?);
-				uv3 = Some(VertexExData::read_uv(reader)?);
-				uv4 = Some(VertexExData::read_uv(reader)?);
-				uv2 = None;
-				uv5 = None;
-				color = None;
-			}
-			_ => {
-				return Err(format!("Unknown vertex flags: {}", vertex_flags));
-			}
-		};
-
-		Ok(VertexExData {
-			normal,
-			uv2,
-			uv3,
-			uv4,
-			uv5,
-			color,
-		})
+			1 | 4 => Ok(&[Normal]),
+			5 => Ok(&[Normal, Uv3]),
+			7 => Ok(&[Uv2, Normal]),
+			10 => Ok(&[Uv2, Color, Normal]),
+			11 => Ok(&[Uv2, Color, Normal, Uv3]),
+			12 => Ok(&[Normal, Uv3, Uv4, Uv5]),
+			14 => Ok(&[Normal, Uv3, Uv4]),
+			_ => Err(format!("Unknown vertex flags: {}", vertex_flags)),
+		}
 	}
 
-	fn read_normal<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Point, String> {
-		let v = Point {
-			x: reader.read_f16()?.to_f32(),
-			y: reader.read_f16()?.to_f32(),
-			z: reader.read_f16()?.to_f32(),
-		};
-		reader.read_f16()?;
-		Ok(v)
+	fn size(vertex_flags: u32) -> Result<u32, String> {
+		Ok(VertexExData::layout(vertex_flags)?.iter()
+			.map(|attribute| match attribute {
+				ExDataAttribute::Normal => 8,
+				_ => 4,
+			})
+			.sum())
 	}
 
-	fn read_uv<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<[f32; 2], String> {
-		Ok([
-			reader.read_f16()?.to_f32(),
-			reader.read_f16()?.to_f32(),
-		])
+	fn read<R: Read + Seek>(reader: &mut ByteReader<R>, vertex_flags: u32) -> Result<Self, String> {
+		let mut ex_data = VertexExData {
+			normal: None,
+			normal_w: 0.0,
+			uv2: None,
+			uv3: None,
+			uv4: None,
+			uv5: None,
+			color: None,
+		};
+		for attribute in VertexExData::layout(vertex_flags)? {
+			match attribute {
+				ExDataAttribute::Normal => {
+					let (normal, normal_w) = read_normal(reader)?;
+					ex_data.normal = Some(normal);
+					ex_data.normal_w = normal_w;
+				},
+				ExDataAttribute::Uv2 => ex_data.uv2 = Some(read_uv(reader)?),
+				ExDataAttribute::Uv3 => ex_data.uv3 = Some(read_uv(reader)?),
+				ExDataAttribute::Uv4 => ex_data.uv4 = Some(read_uv(reader)?),
+				ExDataAttribute::Uv5 => ex_data.uv5 = Some(read_uv(reader)?),
+				ExDataAttribute::Color => ex_data.color = Some([
+					reader.read_u8()?,
+					reader.read_u8()?,
+					reader.read_u8()?,
+					reader.read_u8()?,
+				]),
+			}
+		}
+		Ok(ex_data)
 	}
 
-	fn read_color<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<[u8; 4], String> {
-		Ok([
-			reader.read_u8()?,
-			reader.read_u8()?,
-			reader.read_u8()?,
-			reader.read_u8()?,
-		])
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, vertex_flags: u32) -> Result<(), String> {
+		for attribute in VertexExData::layout(vertex_flags)? {
+			match attribute {
+				ExDataAttribute::Normal => write_normal(writer, self.normal.as_ref(), self.normal_w)?,
+				ExDataAttribute::Uv2 => write_uv(writer, self.uv2.unwrap_or_default())?,
+				ExDataAttribute::Uv3 => write_uv(writer, self.uv3.unwrap_or_default())?,
+				ExDataAttribute::Uv4 => write_uv(writer, self.uv4.unwrap_or_default())?,
+				ExDataAttribute::Uv5 => write_uv(writer, self.uv5.unwrap_or_default())?,
+				ExDataAttribute::Color => writer.write(&self.color.unwrap_or_default())?,
+			}
+		}
+		Ok(())
 	}
 }
 
-struct VertexGroup {
-	vertexes: Vec<Vertex>,
-	vertex_ex_data: Vec<VertexExData>,
-	indexes: Vec<u32>,
+#[derive(PartialEq)]
+pub struct VertexGroup {
+	pub vertex_flags: u32,
+	pub vertexes: Vec<Vertex>,
+	pub vertex_ex_data: Vec<VertexExData>,
+	/// Raw bytes of the unknown per vertex buffers, with their size per vertex
+	pub unknown1: Vec<u8>,
+	pub unknown1_size: u32,
+	pub unknown2: Vec<u8>,
+	pub unknown2_size: u32,
+	pub indexes: Vec<u32>,
 }
 
 impl VertexGroup {
@@ -741,24 +1386,110 @@ impl VertexGroup {
 			indexes.push(read_idx_func(reader)?);
 		}
 
+		let unknown1 = VertexGroup::read_unknown_buffer(reader, header.unknown1_offset, header.unknown1_size, header.num_vertexes)?;
+		let unknown2 = VertexGroup::read_unknown_buffer(reader, header.unknown2_offset, header.unknown2_size, header.num_vertexes)?;
+
 		reader.seek(pos)?;
 
 		Ok(VertexGroup {
+			vertex_flags: header.vertex_flags,
 			vertexes,
 			vertex_ex_data,
+			unknown1,
+			unknown1_size: header.unknown1_size,
+			unknown2,
+			unknown2_size: header.unknown2_size,
 			indexes,
 		})
 	}
+
+	fn read_unknown_buffer<R: Read + Seek>(reader: &mut ByteReader<R>, offset: u32, size: u32, num_vertexes: u32) -> Result<Vec<u8>, String> {
+		if offset == 0 || size == 0 {
+			return Ok(Vec::new());
+		}
+		reader.seek(offset as u64)?;
+		reader.read(size as usize * num_vertexes as usize)
+	}
+
+	const SIZE: u64 = 48;
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, data_offset: u64, header_flags: u32) -> Result<u64, String> {
+		let pos = writer.position()?;
+		writer.seek(data_offset)?;
+
+		writer.align(BUFFER_ALIGNMENT)?;
+		let vertex_offset = offset_u32(writer.position()?)?;
+		for vertex in &self.vertexes {
+			vertex.write(writer, self.vertex_flags)?;
+		}
+
+		writer.align(BUFFER_ALIGNMENT)?;
+		let vertex_ex_data_offset = offset_u32(writer.position()?)?;
+		for vertex_ex_data in &self.vertex_ex_data {
+			vertex_ex_data.write(writer, self.vertex_flags)?;
+		}
+
+		let unknown1_offset = VertexGroup::write_unknown_buffer(writer, &self.unknown1)?;
+		let unknown2_offset = VertexGroup::write_unknown_buffer(writer, &self.unknown2)?;
+
+		writer.align(BUFFER_ALIGNMENT)?;
+		let index_buffer_offset = offset_u32(writer.position()?)?;
+		for index in &self.indexes {
+			if header_flags & 0x8 != 0 {
+				writer.write_u32(*index)?;
+			} else {
+				let index = u16::try_from(*index).map_err(|_| format!("Index {} needs 32 bit indexes (header flag 0x8)", index))?;
+				writer.write_u16(index)?;
+			}
+		}
+		let end = writer.position()?;
+
+		writer.seek(pos)?;
+		VertexGroupHeader {
+			vertex_offset,
+			vertex_ex_data_offset,
+			unknown1_offset,
+			unknown2_offset,
+			vertex_size: Vertex::size(self.vertex_flags),
+			vertex_ex_data_size: VertexExData::size(self.vertex_flags)?,
+			unknown1_size: self.unknown1_size,
+			unknown2_size: self.unknown2_size,
+			num_vertexes: count_u32(self.vertexes.len())?,
+			vertex_flags: self.vertex_flags,
+			index_buffer_offset,
+			num_indexes: count_u32(self.indexes.len())?,
+		}.write(writer)?;
+		Ok(end)
+	}
+
+	fn write_unknown_buffer<W: Write + Seek>(writer: &mut ByteWriter<W>, buffer: &[u8]) -> Result<u32, String> {
+		if buffer.is_empty() {
+			return Ok(0);
+		}
+		writer.align(BUFFER_ALIGNMENT)?;
+		let offset = offset_u32(writer.position()?)?;
+		writer.write(buffer)?;
+		Ok(offset)
+	}
+
+	pub fn vertex_size(&self) -> u32 {
+		Vertex::size(self.vertex_flags)
+	}
+
+	pub fn vertex_ex_data_size(&self) -> Result<u32, String> {
+		VertexExData::size(self.vertex_flags)
+	}
 }
 
-struct Batch {
-	vertex_group_index: u32,
-	bone_set_index: i32,
-	vertex_start: u32,
-	index_start: u32,
-	num_vertexes: u32,
-	num_indexes: u32,
-	num_primitives: u32,
+#[derive(PartialEq)]
+pub struct Batch {
+	pub vertex_group_index: u32,
+	pub bone_set_index: i32,
+	pub vertex_start: u32,
+	pub index_start: u32,
+	pub num_vertexes: u32,
+	pub num_indexes: u32,
+	pub num_primitives: u32,
 }
 
 impl Batch {
@@ -773,13 +1504,24 @@ impl Batch {
 			num_primitives: reader.read_u32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		writer.write_u32(self.vertex_group_index)?;
+		writer.write_i32(self.bone_set_index)?;
+		writer.write_u32(self.vertex_start)?;
+		writer.write_u32(self.index_start)?;
+		writer.write_u32(self.num_vertexes)?;
+		writer.write_u32(self.num_indexes)?;
+		writer.write_u32(self.num_primitives)
+	}
 }
 
-struct Lod {
-	name: String,
-	lod_level: i32,
-	batch_start: u32,
-	batch_infos: Vec<BatchInfo>,
+#[derive(PartialEq)]
+pub struct Lod {
+	pub name: String,
+	pub lod_level: i32,
+	pub batch_start: u32,
+	pub batch_infos: Vec<BatchInfo>,
 }
 
 impl Lod {
@@ -809,15 +1551,40 @@ impl Lod {
 			batch_infos,
 		})
 	}
+
+	const SIZE: u64 = 20;
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, data_offset: u64) -> Result<u64, String> {
+		let pos = writer.position()?;
+		writer.seek(data_offset)?;
+		let offset_name = offset_u32(writer.position()?)?;
+		writer.write_string_zero_term(&self.name)?;
+
+		writer.align(4)?;
+		let offset_batch_infos = offset_u32(writer.position()?)?;
+		for batch_info in &self.batch_infos {
+			batch_info.write(writer)?;
+		}
+		let end = writer.position()?;
+
+		writer.seek(pos)?;
+		writer.write_u32(offset_name)?;
+		writer.write_i32(self.lod_level)?;
+		writer.write_u32(self.batch_start)?;
+		writer.write_u32(offset_batch_infos)?;
+		writer.write_u32(count_u32(self.batch_infos.len())?)?;
+		Ok(end)
+	}
 }
 
-struct BatchInfo {
-	vertex_group_index: u32,
-	mesh_index: u32,
-	material_index: u32,
-	col_tree_node_index: i32,
-	mesh_mat_pair_index: u32,
-	index_to_unknown1: i32,
+#[derive(PartialEq)]
+pub struct BatchInfo {
+	pub vertex_group_index: u32,
+	pub mesh_index: u32,
+	pub material_index: u32,
+	pub col_tree_node_index: i32,
+	pub mesh_mat_pair_index: u32,
+	pub index_to_unknown1: i32,
 }
 
 impl BatchInfo {
@@ -831,13 +1598,23 @@ impl BatchInfo {
 			index_to_unknown1: reader.read_i32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		writer.write_u32(self.vertex_group_index)?;
+		writer.write_u32(self.mesh_index)?;
+		writer.write_u32(self.material_index)?;
+		writer.write_i32(self.col_tree_node_index)?;
+		writer.write_u32(self.mesh_mat_pair_index)?;
+		writer.write_i32(self.index_to_unknown1)
+	}
 }
 
-struct ColTreeNode {
-	p1: Point,
-	p2: Point,
-	left: i32,
-	right: i32,
+#[derive(PartialEq)]
+pub struct ColTreeNode {
+	pub p1: Point,
+	pub p2: Point,
+	pub left: i32,
+	pub right: i32,
 }
 
 impl ColTreeNode {
@@ -849,10 +1626,18 @@ impl ColTreeNode {
 			right: reader.read_i32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		self.p1.write(writer)?;
+		self.p2.write(writer)?;
+		writer.write_i32(self.left)?;
+		writer.write_i32(self.right)
+	}
 }
 
-struct BoneSet {
-	bone_indexes: Vec<i16>,
+#[derive(PartialEq)]
+pub struct BoneSet {
+	pub bone_indexes: Vec<i16>,
 }
 
 impl BoneSet {
@@ -873,20 +1658,40 @@ impl BoneSet {
 			bone_indexes,
 		})
 	}
+
+	const SIZE: u64 = 8;
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, data_offset: u64) -> Result<u64, String> {
+		let pos = writer.position()?;
+		writer.seek(data_offset)?;
+		let offset_bone_set = offset_u32(writer.position()?)?;
+		for bone_index in &self.bone_indexes {
+			writer.write_i16(*bone_index)?;
+		}
+		let end = writer.position()?;
+
+		writer.seek(pos)?;
+		writer.write_u32(offset_bone_set)?;
+		writer.write_u32(count_u32(self.bone_indexes.len())?)?;
+		Ok(end)
+	}
 }
 
-struct Material {
-	name: String,
-	shader_name: String,
-	technique_name: String,
-	textures: Vec<Texture>,
-	parameter_groups: Vec<ParameterGroup>,
-	variables: Vec<Variable>,
+#[derive(PartialEq)]
+pub struct Material {
+	pub name: String,
+	pub shader_name: String,
+	pub technique_name: String,
+	pub textures: Vec<Texture>,
+	pub parameter_groups: Vec<ParameterGroup>,
+	pub variables: Vec<Variable>,
+	pub unknown0: [u16; 4],
+	pub unknown1: u32,
 }
 
 impl Material {
 	fn read<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<Self, String> {
-		let _unknown0 = [
+		let unknown0 = [
 			reader.read_u16()?,
 			reader.read_u16()?,
 			reader.read_u16()?,
@@ -895,7 +1700,7 @@ impl Material {
 		let offset_name = reader.read_u32()?;
 		let offset_shader_name = reader.read_u32()?;
 		let offset_technique_name = reader.read_u32()?;
-		let _unknown1 = reader.read_u32()?;
+		let unknown1 = reader.read_u32()?;
 		let offset_textures = reader.read_u32()?;
 		let num_textures = reader.read_u32()?;
 		let offset_parameter_groups = reader.read_u32()?;
@@ -940,10 +1745,49 @@ impl Material {
 			textures,
 			parameter_groups,
 			variables,
+			unknown0,
+			unknown1,
 		})
 	}
 
-	fn get_albedo_texture_id(&self) -> Option<u32> {
+	const SIZE: u64 = 48;
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, data_offset: u64) -> Result<u64, String> {
+		let pos = writer.position()?;
+		writer.seek(data_offset)?;
+		let offset_name = offset_u32(writer.position()?)?;
+		writer.write_string_zero_term(&self.name)?;
+		let offset_shader_name = offset_u32(writer.position()?)?;
+		writer.write_string_zero_term(&self.shader_name)?;
+		let offset_technique_name = offset_u32(writer.position()?)?;
+		writer.write_string_zero_term(&self.technique_name)?;
+
+		writer.align(4)?;
+		let offset_textures = write_records(writer, &self.textures, Texture::SIZE, Texture::write)?;
+		writer.align(4)?;
+		let offset_parameter_groups = write_records(writer, &self.parameter_groups, ParameterGroup::SIZE, ParameterGroup::write)?;
+		writer.align(4)?;
+		let offset_variables = write_records(writer, &self.variables, Variable::SIZE, Variable::write)?;
+		let end = writer.position()?;
+
+		writer.seek(pos)?;
+		for value in self.unknown0 {
+			writer.write_u16(value)?;
+		}
+		writer.write_u32(offset_name)?;
+		writer.write_u32(offset_shader_name)?;
+		writer.write_u32(offset_technique_name)?;
+		writer.write_u32(self.unknown1)?;
+		writer.write_u32(offset_textures)?;
+		writer.write_u32(count_u32(self.textures.len())?)?;
+		writer.write_u32(offset_parameter_groups)?;
+		writer.write_u32(count_u32(self.parameter_groups.len())?)?;
+		writer.write_u32(offset_variables)?;
+		writer.write_u32(count_u32(self.variables.len())?)?;
+		Ok(end)
+	}
+
+	pub fn get_albedo_texture_id(&self) -> Option<u32> {
 		for texture in &self.textures {
 			if texture.name.contains("g_AlbedoMap") {
 				return Some(texture.id);
@@ -952,7 +1796,7 @@ impl Material {
 		None
 	}
 
-	fn get_normal_texture_id(&self) -> Option<u32> {
+	pub fn get_normal_texture_id(&self) -> Option<u32> {
 		for texture in &self.textures {
 			if texture.name.contains("g_NormalMap") {
 				return Some(texture.id);
@@ -961,7 +1805,7 @@ impl Material {
 		None
 	}
 
-	fn get_mask_map_texture_id(&self) -> Option<u32> {
+	pub fn get_mask_map_texture_id(&self) -> Option<u32> {
 		for texture in &self.textures {
 			if texture.name.contains("g_MaskMap") {
 				return Some(texture.id);
@@ -969,11 +1813,43 @@ impl Material {
 		}
 		None
 	}
+
+	pub fn get_lightmap_texture_id(&self) -> Option<u32> {
+		for texture in &self.textures {
+			if texture.name.contains("g_LightMap") {
+				return Some(texture.id);
+			}
+		}
+		None
+	}
+
+	pub fn to_material_data(&self) -> MaterialData {
+		MaterialData {
+			name: self.name.clone(),
+			shader_name: self.shader_name.clone(),
+			technique_name: self.technique_name.clone(),
+			textures: self.textures.iter().map(|t| MaterialTextureData {
+				slot: t.name.clone(),
+				id: t.id,
+				flags: None,
+			}).collect(),
+			parameter_groups: self.parameter_groups.iter().map(|p| MaterialParameterGroupData {
+				index: p.index,
+				parameters: p.parameters.clone(),
+			}).collect(),
+			variables: self.variables.iter().map(|v| MaterialVariableData {
+				name: v.name.clone(),
+				value: v.value,
+			}).collect(),
+			shader_name_parts: ShaderNameData::parse(&self.shader_name),
+		}
+	}
 }
 
-struct Texture {
-	id: u32,
-	name: String,
+#[derive(PartialEq)]
+pub struct Texture {
+	pub id: u32,
+	pub name: String,
 }
 
 impl Texture {
@@ -992,11 +1868,26 @@ impl Texture {
 			name,
 		})
 	}
+
+	const SIZE: u64 = 8;
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, data_offset: u64) -> Result<u64, String> {
+		let pos = writer.position()?;
+		writer.seek(data_offset)?;
+		writer.write_string_zero_term(&self.name)?;
+		let end = writer.position()?;
+
+		writer.seek(pos)?;
+		writer.write_u32(offset_u32(data_offset)?)?;
+		writer.write_u32(self.id)?;
+		Ok(end)
+	}
 }
 
-struct ParameterGroup {
-	index: i32,
-	parameters: Vec<f32>,
+#[derive(PartialEq)]
+pub struct ParameterGroup {
+	pub index: i32,
+	pub parameters: Vec<f32>,
 }
 
 impl ParameterGroup {
@@ -1019,11 +1910,29 @@ impl ParameterGroup {
 			parameters,
 		})
 	}
+
+	const SIZE: u64 = 12;
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, data_offset: u64) -> Result<u64, String> {
+		let pos = writer.position()?;
+		writer.seek(data_offset)?;
+		for parameter in &self.parameters {
+			writer.write_f32(*parameter)?;
+		}
+		let end = writer.position()?;
+
+		writer.seek(pos)?;
+		writer.write_i32(self.index)?;
+		writer.write_u32(offset_u32(data_offset)?)?;
+		writer.write_u32(count_u32(self.parameters.len())?)?;
+		Ok(end)
+	}
 }
 
-struct Variable {
-	name: String,
-	value: f32,
+#[derive(PartialEq)]
+pub struct Variable {
+	pub name: String,
+	pub value: f32,
 }
 
 impl Variable {
@@ -1042,13 +1951,28 @@ impl Variable {
 			value,
 		})
 	}
+
+	const SIZE: u64 = 8;
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, data_offset: u64) -> Result<u64, String> {
+		let pos = writer.position()?;
+		writer.seek(data_offset)?;
+		writer.write_string_zero_term(&self.name)?;
+		let end = writer.position()?;
+
+		writer.seek(pos)?;
+		writer.write_u32(offset_u32(data_offset)?)?;
+		writer.write_f32(self.value)?;
+		Ok(end)
+	}
 }
 
-struct Mesh {
-	name: String,
-	bounding_box: BoundingBox,
-	materials: Vec<u16>,
-	bones: Vec<u16>,
+#[derive(PartialEq)]
+pub struct Mesh {
+	pub name: String,
+	pub bounding_box: BoundingBox,
+	pub materials: Vec<u16>,
+	pub bones: Vec<u16>,
 }
 
 impl Mesh {
@@ -1085,11 +2009,43 @@ impl Mesh {
 			bones,
 		})
 	}
+
+	const SIZE: u64 = 44;
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>, data_offset: u64) -> Result<u64, String> {
+		let pos = writer.position()?;
+		writer.seek(data_offset)?;
+		let name_offset = offset_u32(writer.position()?)?;
+		writer.write_string_zero_term(&self.name)?;
+
+		writer.align(4)?;
+		let offset_materials = offset_u32(writer.position()?)?;
+		for material in &self.materials {
+			writer.write_u16(*material)?;
+		}
+
+		writer.align(4)?;
+		let offset_bones = offset_u32(writer.position()?)?;
+		for bone in &self.bones {
+			writer.write_u16(*bone)?;
+		}
+		let end = writer.position()?;
+
+		writer.seek(pos)?;
+		writer.write_u32(name_offset)?;
+		self.bounding_box.write(writer)?;
+		writer.write_u32(offset_materials)?;
+		writer.write_u32(count_u32(self.materials.len())?)?;
+		writer.write_u32(offset_bones)?;
+		writer.write_u32(count_u32(self.bones.len())?)?;
+		Ok(end)
+	}
 }
 
-struct MeshMaterial {
-	mesh_id: u32,
-	material_id: u32,
+#[derive(PartialEq)]
+pub struct MeshMaterial {
+	pub mesh_id: u32,
+	pub material_id: u32,
 }
 
 impl MeshMaterial {
@@ -1099,20 +2055,48 @@ impl MeshMaterial {
 			material_id: reader.read_u32()?,
 		})
 	}
+
+	fn write<W: Write + Seek>(&self, writer: &mut ByteWriter<W>) -> Result<(), String> {
+		writer.write_u32(self.mesh_id)?;
+		writer.write_u32(self.material_id)
+	}
+}
+
+fn read_normal<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<(Point, f32), String> {
+	let normal = Point {
+		x: reader.read_f16()?.to_f32(),
+		y: reader.read_f16()?.to_f32(),
+		z: reader.read_f16()?.to_f32(),
+	};
+	let normal_w = reader.read_f16()?.to_f32();
+	Ok((normal, normal_w))
+}
+
+fn write_normal<W: Write + Seek>(writer: &mut ByteWriter<W>, normal: Option<&Point>, normal_w: f32) -> Result<(), String> {
+	let normal = normal.map_or([0.0; 3], |n| [n.x, n.y, n.z]);
+	for value in normal.into_iter().chain([normal_w]) {
+		writer.write_f16(f16::from_f32(value))?;
+	}
+	Ok(())
+}
+
+fn read_uv<R: Read + Seek>(reader: &mut ByteReader<R>) -> Result<[f32; 2], String> {
+	Ok([
+		reader.read_f16()?.to_f32(),
+		reader.read_f16()?.to_f32(),
+	])
+}
+
+/// Inverse of `(byte - 127) / 127`
+fn encode_unorm8(value: f32) -> u8 {
+	(value * 127.0 + 127.0).round().clamp(0.0, 255.0) as u8
 }
 
-fn decompress_dds(bytes: Vec<u8>, swizzle: Option<&dyn Fn(&mut[u8]) -> ()>) -> Result<TextureData, String> {
-    let dds_decoder = DdsDecoder::new(bytes.as_slice()).map_err(|e| e.to_string())?;
-    let (width, height) = dds_decoder.dimensions();
-    let buffer_size = dds_decoder.total_bytes() as usize;
-    let mut buffer = vec![0; buffer_size];
-    dds_decoder.read_image(&mut buffer).map_err(|e| e.to_string())?;
-    if let Some(swizzle) = swizzle {
-        for i in (0..buffer.len()).step_by(4) {
-            swizzle(&mut buffer[i..i + 4]);
-        }
-    }
-    Ok(TextureData { bytes: buffer, width, height })
+fn bind_transform(position: &Point, rotation: &Point, scale: &Point) -> Matrix4<f32> {
+	let translation = Matrix4::from_translation(Vector3::new(position.x, position.y, position.z));
+	let rotation = Matrix4::from_angle_z(Rad(rotation.z)) * Matrix4::from_angle_y(Rad(rotation.y)) * Matrix4::from_angle_x(Rad(rotation.x));
+	let scale = Matrix4::from_nonuniform_scale(scale.x, scale.y, scale.z);
+	translation * rotation * scale
 }
 
 fn mask_map_swizzle(pixel: &mut[u8]) {
@@ -1128,14 +2112,276 @@ fn mask_map_swizzle(pixel: &mut[u8]) {
     pixel[1] = 255 - pixel[1];
 }
 
+/// Only reads the texture, decoding happens once the renderer doesn't find it in its texture cache
 fn try_add_texture<F: Read + Seek>(
-	textures: &mut HashMap<u32, TextureData>,
+	textures: &mut HashMap<u32, EncodedTextureData>,
 	wta_wtp: &mut WtaWtp<F>,
 	texture_id: Option<u32>,
-	swizzle: Option<&dyn Fn(&mut[u8]) -> ()>,
-) -> () {
+) {
 	texture_id
-		.and_then(|texture_id| wta_wtp.get_texture(texture_id))
-		.and_then(|texture| decompress_dds(texture, swizzle).ok())
-		.map(|texture_data| textures.insert(texture_id.unwrap(), texture_data));
+		.filter(|texture_id| !textures.contains_key(texture_id))
+		.and_then(|texture_id| wta_wtp.get_texture(texture_id).map(|texture| (texture_id, texture)))
+		.map(|(texture_id, texture)| textures.insert(texture_id, EncodedTextureData::new(texture)));
+}
+
+#[cfg(test)]
+mod tests {
+	use std::io::Cursor;
+
+	use super::*;
+	use crate::wmb::SECTION_ALIGNMENT;
+
+	fn point(x: f32, y: f32, z: f32) -> Point {
+		Point { x, y, z }
+	}
+
+	fn test_wmb() -> Wmb {
+		let mut wmb = Wmb::new(Header::new(Wmb3Version::Na, 0xA, -1, BoundingBox::new([0.0, 1.0, 0.0], [1.0, 1.0, 0.5])));
+		wmb.bones = (0..2).map(|i| Bone {
+			id: i,
+			parent_index: i - 1,
+			local_position: point(0.0, i as f32, 0.0),
+			local_rotation: point(0.0, 0.0, 0.0),
+			local_scale: point(1.0, 1.0, 1.0),
+			position: point(0.0, i as f32, 0.0),
+			rotation: point(0.0, 0.0, 0.0),
+			scale: point(1.0, 1.0, 1.0),
+			t_position: point(0.0, i as f32, 0.0),
+		}).collect();
+		let mut first_level = [-1; 16];
+		first_level[0] = 16;
+		wmb.bone_index_translate_table = Some(BoneIndexTranslateTable {
+			first_level,
+			second_level: (0..16).map(|i| if i == 0 { 32 } else { -1 }).collect(),
+			third_level: (0..16).map(|i| if i < 2 { i } else { 0xFFF }).collect(),
+		});
+		wmb.vertex_groups.push(VertexGroup {
+			vertex_flags: 10,
+			vertexes: (0..3).map(|i| Vertex {
+				position: point(i as f32, 0.3, -0.7),
+				tangent: point(0.2, 0.9, 0.1),
+				tangent_sign: -1.0,
+				uv: [0.1 * i as f32, 0.33],
+				normal: None,
+				normal_w: 0.0,
+				uv2: Some([0.5, 0.25]),
+				bone_indices: Some([0, 1, 0, 0]),
+				bone_weights: Some([0.7, 0.3, 0.0, 0.0]),
+				color: None,
+			}).collect(),
+			vertex_ex_data: (0..3).map(|_| VertexExData {
+				normal: Some(point(0.0, 0.6, 0.8)),
+				normal_w: 1.0,
+				uv2: Some([0.9, 0.1]),
+				uv3: None,
+				uv4: None,
+				uv5: None,
+				color: Some([255, 128, 0, 255]),
+			}).collect(),
+			unknown1: vec![7; 12],
+			unknown1_size: 4,
+			unknown2: Vec::new(),
+			unknown2_size: 0,
+			indexes: vec![0, 1, 2],
+		});
+		wmb.batches.push(Batch {
+			vertex_group_index: 0,
+			bone_set_index: 0,
+			vertex_start: 0,
+			index_start: 0,
+			num_vertexes: 3,
+			num_indexes: 3,
+			num_primitives: 1,
+		});
+		wmb.lods.push(Lod {
+			name: "LOD0".to_string(),
+			lod_level: 0,
+			batch_start: 0,
+			batch_infos: vec![BatchInfo {
+				vertex_group_index: 0,
+				mesh_index: 0,
+				material_index: 0,
+				col_tree_node_index: 0,
+				mesh_mat_pair_index: 0,
+				index_to_unknown1: -1,
+			}],
+		});
+		wmb.col_tree_nodes.push(ColTreeNode {
+			p1: point(-1.0, -1.0, -1.0),
+			p2: point(1.0, 1.0, 1.0),
+			left: -1,
+			right: -1,
+		});
+		wmb.bone_map = vec![0, 1];
+		wmb.bone_sets.push(BoneSet { bone_indexes: vec![0, 1, 1] });
+		wmb.materials.push(Material {
+			name: "mat0".to_string(),
+			shader_name: "CLT00_XXXXX".to_string(),
+			technique_name: "Default".to_string(),
+			textures: vec![
+				Texture { id: 0x1234, name: "g_AlbedoMap".to_string() },
+				Texture { id: 0x5678, name: "g_NormalMap".to_string() },
+			],
+			parameter_groups: vec![ParameterGroup { index: 0, parameters: vec![1.0, 0.5, 0.25, 1.0] }],
+			variables: vec![Variable { name: "g_Glossiness".to_string(), value: 0.75 }],
+			unknown0: [1, 2, 3, 4],
+			unknown1: 5,
+		});
+		wmb.meshes.push(Mesh {
+			name: "body".to_string(),
+			bounding_box: BoundingBox::new([0.0, 1.0, 0.0], [1.0, 1.0, 0.5]),
+			materials: vec![0],
+			bones: vec![0, 1],
+		});
+		wmb.mesh_material.push(MeshMaterial { mesh_id: 0, material_id: 0 });
+		wmb.unknown0 = (1..=12).collect();
+		wmb.num_unknown0 = 1;
+		wmb
+	}
+
+	fn write_bytes(wmb: &Wmb) -> Vec<u8> {
+		let mut writer = ByteWriter::new(Cursor::new(Vec::new()));
+		wmb.write(&mut writer).unwrap();
+		writer.into_inner().into_inner()
+	}
+
+	fn read_bytes(bytes: &[u8]) -> Wmb {
+		Wmb::read(&mut ByteReader::new(Cursor::new(bytes))).unwrap()
+	}
+
+	#[test]
+	fn round_trip_is_byte_exact() {
+		let written = write_bytes(&test_wmb());
+		let wmb = read_bytes(&written);
+		assert_eq!(write_bytes(&wmb), written);
+
+		assert_eq!(wmb.bones.len(), 2);
+		assert_eq!(wmb.bone_index_translate_table.as_ref().unwrap().third_level[1], 1);
+		let vertex_group = &wmb.vertex_groups[0];
+		assert_eq!(vertex_group.indexes, vec![0, 1, 2]);
+		assert_eq!(vertex_group.unknown1, vec![7; 12]);
+		assert_eq!(vertex_group.vertex_ex_data[2].color, Some([255, 128, 0, 255]));
+		assert_eq!(wmb.lods[0].name, "LOD0");
+		assert_eq!(wmb.materials[0].textures[1].name, "g_NormalMap");
+		assert_eq!(wmb.materials[0].variables[0].value, 0.75);
+		assert_eq!(wmb.meshes[0].bones, vec![0, 1]);
+		assert_eq!(wmb.unknown0, (1..=12).collect::<Vec<u8>>());
+		assert_eq!(wmb.resolve_bone_index(0, 2), Some(1));
+	}
+
+	/// Game files aren't laid out the way `write_layout` does it. Here the bones come after all other
+	/// sections, leaving stale bytes behind, and both materials use the technique name of the first one.
+	fn original_layout_fixture() -> Vec<u8> {
+		let mut wmb = test_wmb();
+		wmb.materials.push(Material {
+			name: "mat1".to_string(),
+			shader_name: "CLT00_XXXXX".to_string(),
+			technique_name: "Default".to_string(),
+			textures: Vec::new(),
+			parameter_groups: Vec::new(),
+			variables: Vec::new(),
+			unknown0: [0; 4],
+			unknown1: 0,
+		});
+		let mut bytes = write_bytes(&wmb);
+		let header = read_bytes(&bytes).header;
+
+		const BONE_SIZE: usize = 88;
+		let offset_bones = header.offset_bones as usize;
+		let bones = bytes[offset_bones..offset_bones + wmb.bones.len() * BONE_SIZE].to_vec();
+		let moved_offset_bones = bytes.len() as u32;
+		bytes.extend(bones);
+		bytes[40..44].copy_from_slice(&moved_offset_bones.to_le_bytes());
+
+		// offset_technique_name is at 16 in the material record
+		let technique_name = header.offset_materials as usize + 16;
+		let shared = bytes[technique_name..technique_name + 4].to_vec();
+		bytes[technique_name + Material::SIZE as usize..][..4].copy_from_slice(&shared);
+		bytes
+	}
+
+	#[test]
+	fn unmodified_models_keep_their_layout() {
+		let fixture = original_layout_fixture();
+		let wmb = read_bytes(&fixture);
+		assert_eq!(wmb.materials[1].technique_name, "Default");
+		assert!(wmb.bones[1].position == point(0.0, 1.0, 0.0));
+		assert_eq!(write_bytes(&wmb), fixture);
+
+		let mut edited = read_bytes(&fixture);
+		edited.materials[1].name = "mat2".to_string();
+		let written = write_bytes(&edited);
+		assert_ne!(written, fixture);
+		let rewritten = read_bytes(&written);
+		assert!(rewritten.header.offset_bones < rewritten.header.offset_materials);
+		assert!(rewritten.bones == edited.bones);
+		assert!(rewritten.vertex_groups == edited.vertex_groups);
+		assert!(rewritten.materials == edited.materials);
+		assert!(rewritten.meshes == edited.meshes);
+		assert_eq!(rewritten.unknown0, edited.unknown0);
+	}
+
+	#[test]
+	fn write_lays_out_edited_sections() {
+		let mut wmb = read_bytes(&write_bytes(&test_wmb()));
+		wmb.materials[0].name = "a_much_longer_material_name".to_string();
+		wmb.materials.push(Material {
+			name: "mat1".to_string(),
+			shader_name: "CLT00_XXXXX".to_string(),
+			technique_name: "Default".to_string(),
+			textures: Vec::new(),
+			parameter_groups: Vec::new(),
+			variables: Vec::new(),
+			unknown0: [0; 4],
+			unknown1: 0,
+		});
+		wmb.vertex_groups[0].indexes.extend([2, 1, 0]);
+		wmb.bone_map.clear();
+
+		let wmb = read_bytes(&write_bytes(&wmb));
+		let header = wmb.header();
+		assert_eq!(header.num_materials(), 2);
+		assert_eq!(header.bone_map_size(), 0);
+		assert_eq!(header.offset_bone_map(), 0);
+		assert!(header.section_offsets().iter().all(|&offset| (offset as u64).is_multiple_of(SECTION_ALIGNMENT)));
+		assert_eq!(wmb.materials[0].name, "a_much_longer_material_name");
+		assert_eq!(wmb.materials[1].name, "mat1");
+		assert_eq!(wmb.vertex_groups[0].indexes, vec![0, 1, 2, 2, 1, 0]);
+		assert_eq!(wmb.meshes[0].name, "body");
+	}
+
+	#[test]
+	fn material_data_keeps_the_full_description() {
+		let material = test_wmb().materials[0].to_material_data();
+		assert_eq!(material.technique_name, "Default");
+		assert_eq!(material.texture("g_NormalMap"), Some(0x5678));
+		assert_eq!(material.texture("g_MaskMap"), None);
+		assert_eq!(material.variable("g_Glossiness"), Some(0.75));
+		assert_eq!(material.variable("g_glossiness"), None);
+		assert_eq!(material.parameter_groups[0].parameters, vec![1.0, 0.5, 0.25, 1.0]);
+		assert_eq!(material.shader_name_parts.family, "CLT");
+		assert_eq!(material.shader_name_parts.variant, "00");
+		assert_eq!(material.shader_name_parts.suffix, "XXXXX");
+	}
+
+	#[test]
+	fn unsupported_version() {
+		let mut bytes = write_bytes(&test_wmb());
+		for (version, error) in [
+			(0x12345678u32, "Unsupported WMB3 version 0x12345678"),
+			(0x20190816, "Unsupported WMB3 version 0x20190816"),
+		] {
+			bytes[4..8].copy_from_slice(&version.to_le_bytes());
+			assert_eq!(Wmb::read(&mut ByteReader::new(Cursor::new(&bytes))).err().as_deref(), Some(error));
+		}
+	}
+
+	#[test]
+	fn large_indexes_need_the_32_bit_flag() {
+		let mut wmb = test_wmb();
+		wmb.header_mut().set_flags(0x2);
+		wmb.vertex_groups[0].indexes.push(70000);
+		let mut writer = ByteWriter::new(Cursor::new(Vec::new()));
+		assert!(wmb.write(&mut writer).is_err());
+	}
 }
@@ -1,47 +1,68 @@
-use std::{collections::HashMap, fs::File, io::{BufReader, Cursor}, time::Instant};
+use std::{io::{Cursor, Read, Seek}, time::Instant};
 
-use crate::{byte_stream::ByteReader, mesh_data::{SceneData, TextureData}, scr_mgrr::read_scr_mgrr, wmb_mgrr::read_wmb_mgrr, wmb_na::read_wmb_na, wta_wtp::{WtaBasenameExt, WtaWtp}};
+use crate::{byte_stream::ByteReader, dat::Dat, load_options::LoadOptions, mesh_data::SceneData, scr_mgrr::read_scr_mgrr, vfs::Vfs, wmb::WmbFormat, wmb_mgrr::read_wmb_mgrr, wmb_na::{read_wmb_na, Wmb3Version}, wta_wtp::{WtaBasenameExt, WtaWtp}};
 
 
-pub fn read_wmb_scr(path: String) -> Result<SceneData, String> {
+pub fn read_wmb_scr(path: String, options: &LoadOptions) -> Result<SceneData, String> {
+	read_wmb_scr_from_vfs(&Vfs::local(), &path, options)
+}
+
+pub fn read_wmb_scr_from_vfs(vfs: &Vfs, path: &str, options: &LoadOptions) -> Result<SceneData, String> {
 	// let t1 = Instant::now();
-	let file = File::open(&path).map_err(|e| e.to_string())?;
-	let mut reader = ByteReader::new(BufReader::new(file));
-	let magic = reader.read_string(4)?;
-	reader.seek(0)?;
-	let mut wta_wtp = WtaWtp::from_wmb(&path, WtaBasenameExt::Maybe("scr".to_string())).ok();
-	let mut textures: HashMap<u32, TextureData> = HashMap::new();
-	let meshes = match magic.as_str() {
-		"WMB3" => read_wmb_na(&path, &mut reader, &mut wta_wtp, &mut textures),
-		"WMB4" => read_wmb_mgrr(&mut reader, &mut wta_wtp, &mut textures),
-		"SCR\0" => read_scr_mgrr(&mut reader, &mut wta_wtp, &mut textures),
-		_ => Err(format!("Unknown WMB version: {}", magic)),
-	}?;
+	let mut reader = ByteReader::new(vfs.open(path)?);
+	let mut wta_wtp = WtaWtp::from_wmb(vfs, path, WtaBasenameExt::Maybe("scr".to_string())).ok();
+	let scene_data = read_scene(path, &mut reader, &mut wta_wtp, options)?;
 
 	// println!("WMB read time: {:?}", t1.elapsed());
-	Ok(SceneData {
-		meshes: meshes,
-		textures,
-	})
+	Ok(scene_data)
 }
 
-pub fn read_wmb_scr_from_bytes(name: &str, wmb: &[u8], wta_wtb: Option<&[u8]>, wtp: Option<&[u8]>) -> Result<SceneData, String> {
+pub fn read_wmb_scr_from_bytes(name: &str, wmb: &[u8], wta_wtb: Option<&[u8]>, wtp: Option<&[u8]>, options: &LoadOptions) -> Result<SceneData, String> {
 	// let t1 = Instant::now();
 	let mut reader = ByteReader::new(Cursor::new(wmb));
+	let mut wta_wtp = WtaWtp::from_bytes(wta_wtb, wtp).ok();
+	let scene_data = read_scene(name, &mut reader, &mut wta_wtp, options)?;
+
+	// println!("WMB read time: {:?}", t1.elapsed());
+	Ok(scene_data)
+}
+
+pub fn read_wmb_scr_from_dat(dat_path: String, dtt_path: Option<String>, entry_name: &str, options: &LoadOptions) -> Result<SceneData, String> {
+	let mut dat = Dat::open(&dat_path)?;
+	let mut dtt = match dtt_path {
+		Some(dtt_path) => Some(Dat::open(&dtt_path)?),
+		None => None,
+	};
+	let wmb = if dat.contains(entry_name) {
+		dat.read_entry(entry_name)?
+	} else if let Some(dtt) = dtt.as_mut().filter(|dtt| dtt.contains(entry_name)) {
+		dtt.read_entry(entry_name)?
+	} else {
+		return Err(format!("{} not found in DAT or DTT", entry_name));
+	};
+	let mut reader = ByteReader::new(Cursor::new(wmb));
+	let mut wta_wtp = WtaWtp::from_dat(&mut dat, dtt.as_mut(), entry_name, WtaBasenameExt::Maybe("scr".to_string())).ok();
+	read_scene(entry_name, &mut reader, &mut wta_wtp, options)
+}
+
+fn read_scene<R1: Read + Seek, R2: Read + Seek>(
+	name: &str,
+	reader: &mut ByteReader<R1>,
+	wta_wtp: &mut Option<WtaWtp<R2>>,
+	options: &LoadOptions,
+) -> Result<SceneData, String> {
 	let magic = reader.read_string(4)?;
 	reader.seek(0)?;
-	let mut wta_wtp = WtaWtp::from_bytes(wta_wtb, wtp).ok();
-	let mut textures: HashMap<u32, TextureData> = HashMap::new();
-	let meshes = match magic.as_str() {
-		"WMB3" => read_wmb_na(name, &mut reader, &mut wta_wtp, &mut textures),
-		"WMB4" => read_wmb_mgrr(&mut reader, &mut wta_wtp, &mut textures),
-		"SCR\0" => read_scr_mgrr(&mut reader, &mut wta_wtp, &mut textures),
-		_ => Err(format!("Unknown WMB version: {}", magic)),
+	let mut scene = SceneData::default();
+	if magic == "SCR\0" {
+		read_scr_mgrr(reader, wta_wtp, &mut scene, options)
+	} else {
+		match WmbFormat::detect(reader)? {
+			WmbFormat::Wmb3(Wmb3Version::Na) => read_wmb_na(name, reader, wta_wtp, &mut scene, options),
+			WmbFormat::Wmb4 => read_wmb_mgrr(name, reader, wta_wtp, &mut scene, options),
+		}
 	}?;
+	scene.texture_source = wta_wtp.as_ref().map(|w| w.source().to_string()).unwrap_or_default();
 
-	// println!("WMB read time: {:?}", t1.elapsed());
-	Ok(SceneData {
-		meshes: meshes,
-		textures,
-	})
+	Ok(scene)
 }
@@ -1,11 +1,12 @@
-use std::{collections::HashMap, fs::File, io::{BufReader, Cursor, Read, Seek, SeekFrom}, path::Path};
+use std::{collections::{hash_map::DefaultHasher, HashMap}, hash::{Hash, Hasher}, io::{BufReader, Cursor, Read, Seek, SeekFrom}, path::Path};
 
-use crate::byte_stream::ByteReader;
+use crate::{byte_stream::ByteReader, dat::Dat, vfs::{normalize_path, ReadSeek, Vfs}};
 
 
 pub struct WtaWtp<F: Read + Seek> {
 	id_offsets: HashMap<u32, TexturePos>,
 	wtp_file: BufReader<F>,
+	source: String,
 }
 
 pub struct TexturePos {
@@ -19,8 +20,8 @@ pub enum WtaBasenameExt {
 	No,
 }
 
-impl WtaWtp<File> {
-	pub fn from_wmb(wmb_path_orig: &String, basename_ext: WtaBasenameExt) -> Result<Self, String> {
+impl WtaWtp<Box<dyn ReadSeek>> {
+	pub fn from_wmb(vfs: &Vfs, wmb_path_orig: &str, basename_ext: WtaBasenameExt) -> Result<Self, String> {
 		let wmb_path = Path::new(wmb_path_orig);
 		
 		let mut base_name = wmb_path.file_stem()
@@ -44,38 +45,32 @@ impl WtaWtp<File> {
 		} else {
 			return Err("WMB not in a DAT or DTT directory".to_string());
 		}
-		if !dat_dir.is_dir() || !dtt_dir.is_dir() {
+		if !vfs.is_dir(&dat_dir.to_string_lossy()) || !vfs.is_dir(&dtt_dir.to_string_lossy()) {
 			return Err("DAT or DTT directory not found".to_string());
 		}
-		let wta_path = dat_dir.join(format!("{base_name}.wta"));
-		let wtp_path = dtt_dir.join(format!("{base_name}.wtp"));
-		let wtb_path = dtt_dir.join(format!("{base_name}.wtb"));
-		let wta_exists = wta_path.exists();
-		let wtp_exists = wtp_path.exists();
-		let wtb_exists = wtb_path.exists();
+		let wta_path = dat_dir.join(format!("{base_name}.wta")).to_string_lossy().to_string();
+		let wtp_path = dtt_dir.join(format!("{base_name}.wtp")).to_string_lossy().to_string();
+		let wtb_path = dtt_dir.join(format!("{base_name}.wtb")).to_string_lossy().to_string();
+		let wta_exists = vfs.exists(&wta_path);
+		let wtp_exists = vfs.exists(&wtp_path);
+		let wtb_exists = vfs.exists(&wtb_path);
 		if (!wta_exists || !wtp_exists) && !wtb_exists {
 			if let WtaBasenameExt::Maybe(ext) = basename_ext {
-				return Self::from_wmb(wmb_path_orig, WtaBasenameExt::Try(ext));
+				return Self::from_wmb(vfs, wmb_path_orig, WtaBasenameExt::Try(ext));
 			}
 			return Err("WTA, WTP or WTB file not found".to_string());
 		}
 
 		let id_offsets = if wta_exists {
-			let wta_file = File::open(wta_path).map_err(|e| e.to_string())?;
-			read_wta(BufReader::new(wta_file))?
+			read_wta(BufReader::new(vfs.open(&wta_path)?))?
 		} else {
-			let wtb_file = File::open(&wtb_path).map_err(|e| e.to_string())?;
-			read_wta(BufReader::new(wtb_file))?
+			read_wta(BufReader::new(vfs.open(&wtb_path)?))?
 		};
 
-		let wtp_file = if wta_exists {
-			File::open(wtp_path).map_err(|e| e.to_string())?
-		} else {
-			File::open(wtb_path).map_err(|e| e.to_string())?
-		};
-		let wtp_reader = BufReader::new(wtp_file);
+		let wtp_path = if wta_exists { wtp_path } else { wtb_path };
+		let wtp_reader = BufReader::new(vfs.open(&wtp_path)?);
 
-		Ok(WtaWtp { id_offsets, wtp_file: wtp_reader })
+		Ok(WtaWtp { id_offsets, wtp_file: wtp_reader, source: normalize_path(&wtp_path) })
 	}
 }
 
@@ -96,8 +91,46 @@ impl<'a> WtaWtp<Cursor<&'a[u8]>> {
 			}
 			_ => return Err("WTA or WTP file not found".to_string()),
 		};
+		let source = bytes_source(wta_wtb.unwrap_or_default());
+
+		Ok(WtaWtp { id_offsets, wtp_file: wtp_reader, source })
+	}
+}
+
+impl WtaWtp<Cursor<Vec<u8>>> {
+	pub fn from_dat<R1: Read + Seek, R2: Read + Seek>(
+		dat: &mut Dat<R1>,
+		mut dtt: Option<&mut Dat<R2>>,
+		wmb_name: &str,
+		basename_ext: WtaBasenameExt,
+	) -> Result<Self, String> {
+		let base_name = Path::new(wmb_name).file_stem()
+			.ok_or("Invalid wmb name")?
+			.to_str()
+			.ok_or("Invalid wmb name")?
+			.to_string();
+		let base_names = match basename_ext {
+			WtaBasenameExt::Maybe(ext) => vec![base_name.clone(), base_name + &ext],
+			WtaBasenameExt::Try(ext) => vec![base_name + &ext],
+			WtaBasenameExt::No => vec![base_name],
+		};
+
+		for base_name in base_names {
+			let wta = read_from_dat_or_dtt(dat, dtt.as_deref_mut(), &format!("{base_name}.wta"))?;
+			let wtp = read_from_dat_or_dtt(dat, dtt.as_deref_mut(), &format!("{base_name}.wtp"))?;
+			let (wta, wtp) = match (wta, wtp) {
+				(Some(wta), Some(wtp)) => (wta, wtp),
+				_ => match read_from_dat_or_dtt(dat, dtt.as_deref_mut(), &format!("{base_name}.wtb"))? {
+					Some(wtb) => (wtb.clone(), wtb),
+					None => continue,
+				},
+			};
+			let id_offsets = read_wta(BufReader::new(Cursor::new(wta.as_slice())))?;
+			let source = bytes_source(&wta);
+			return Ok(WtaWtp { id_offsets, wtp_file: BufReader::new(Cursor::new(wtp)), source });
+		}
 
-		Ok(WtaWtp { id_offsets, wtp_file: wtp_reader })
+		Err("WTA, WTP or WTB file not found".to_string())
 	}
 }
 	
@@ -113,6 +146,41 @@ impl<F: Read + Seek> WtaWtp<F> {
 	pub fn has_id(&self, id: u32) -> bool {
 		self.id_offsets.contains_key(&id)
 	}
+
+	pub fn source(&self) -> &str {
+		&self.source
+	}
+}
+
+pub fn read_wta_ids<R: Read + Seek>(wta_file: R) -> Result<Vec<u32>, String> {
+	let mut ids = read_wta(BufReader::new(wta_file))?
+		.into_keys()
+		.collect::<Vec<_>>();
+	ids.sort();
+	Ok(ids)
+}
+
+fn read_from_dat_or_dtt<R1: Read + Seek, R2: Read + Seek>(
+	dat: &mut Dat<R1>,
+	dtt: Option<&mut Dat<R2>>,
+	name: &str,
+) -> Result<Option<Vec<u8>>, String> {
+	if let Some(dtt) = dtt {
+		if dtt.contains(name) {
+			return dtt.read_entry(name).map(Some);
+		}
+	}
+	if dat.contains(name) {
+		return dat.read_entry(name).map(Some);
+	}
+	Ok(None)
+}
+
+// identical WTA contents mean identical texture ids, offsets and sizes
+fn bytes_source(wta: &[u8]) -> String {
+	let mut hasher = DefaultHasher::new();
+	wta.hash(&mut hasher);
+	format!("bytes:{:016x}", hasher.finish())
 }
 
 fn read_wta<R: Read + Seek>(wta_file: BufReader<R>) -> Result<HashMap<u32, TexturePos>, String> {